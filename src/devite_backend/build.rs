@@ -0,0 +1,29 @@
+// Embeds the personal storage module into the backend when it has been built,
+// so a fresh deploy can register users without uploading the module first.
+// dfx builds devite_personal_storage before the backend (see dfx.json); point
+// DEVITE_PERSONAL_STORAGE_WASM at another module to embed that one instead.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn main() {
+    println!("cargo:rustc-check-cfg=cfg(embedded_storage_wasm)");
+    println!("cargo:rerun-if-env-changed=DEVITE_PERSONAL_STORAGE_WASM");
+
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let wasm = env::var_os("DEVITE_PERSONAL_STORAGE_WASM")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            manifest_dir.join("../../target/wasm32-unknown-unknown/release/devite_personal_storage.wasm")
+        });
+    println!("cargo:rerun-if-changed={}", wasm.display());
+
+    // Host builds (checks, tests) have no storage module and embed nothing
+    if env::var("TARGET").unwrap() != "wasm32-unknown-unknown" || !wasm.exists() {
+        return;
+    }
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("personal_storage.wasm");
+    fs::copy(&wasm, out).expect("Failed to copy the personal storage wasm");
+    println!("cargo:rustc-cfg=embedded_storage_wasm");
+}
@@ -0,0 +1,145 @@
+use candid::{CandidType, Deserialize, Principal, Encode, Decode};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, Storable, BoundedStorable};
+use std::cell::RefCell;
+use std::borrow::Cow;
+
+use crate::error::DeviteError;
+use crate::{Memory, StorablePrincipal, MEMORY_MANAGER};
+
+type RoleStorage = StableBTreeMap<StorablePrincipal, RoleSet, Memory>;
+
+// ACCESS CONTROL TYPES
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq, Eq)]
+pub enum Role {
+    Admin,
+    Moderator,
+    InstitutionVerifier,
+    Reviewer,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct RoleSet(pub Vec<Role>);
+
+impl Storable for RoleSet {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&self.0).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let roles: Vec<Role> = Decode!(bytes.as_ref(), Vec<Role>).unwrap();
+        RoleSet(roles)
+    }
+}
+
+impl BoundedStorable for RoleSet {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Role registry (Memory ID 13)
+    static ROLES: RefCell<RoleStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+        )
+    );
+}
+
+// ACCESS CONTROL FUNCTIONS
+
+pub fn has_role(principal: Principal, role: Role) -> bool {
+    ROLES.with(|roles| {
+        roles.borrow()
+            .get(&StorablePrincipal::from(principal))
+            .map(|set| set.0.contains(&role))
+            .unwrap_or(false)
+    })
+}
+
+// Guard for every state-changing method; returns the authenticated caller
+pub fn require_authenticated() -> Result<Principal, DeviteError> {
+    let caller = ic_cdk::api::caller();
+    if caller == Principal::anonymous() {
+        Err(DeviteError::AnonymousCaller)
+    } else {
+        Ok(caller)
+    }
+}
+
+// Guard for update methods restricted to holders of `role`
+pub fn require_role(role: Role) -> Result<(), DeviteError> {
+    if has_role(require_authenticated()?, role) {
+        Ok(())
+    } else {
+        Err(DeviteError::Unauthorized { required_role: role })
+    }
+}
+
+pub fn assign_role(principal: Principal, role: Role) {
+    let storable_principal = StorablePrincipal::from(principal);
+    ROLES.with(|roles| {
+        let mut set = roles.borrow().get(&storable_principal).unwrap_or_default();
+        if !set.0.contains(&role) {
+            set.0.push(role);
+            roles.borrow_mut().insert(storable_principal, set);
+        }
+    });
+}
+
+pub fn remove_role(principal: Principal, role: Role) -> Result<(), DeviteError> {
+    // Never leave the platform without an administrator
+    if role == Role::Admin && list_role_holders(Role::Admin) == vec![principal] {
+        return Err(DeviteError::LastAdmin);
+    }
+
+    let storable_principal = StorablePrincipal::from(principal);
+    ROLES.with(|roles| {
+        let mut set = roles.borrow().get(&storable_principal).unwrap_or_default();
+        set.0.retain(|held| *held != role);
+        if set.0.is_empty() {
+            roles.borrow_mut().remove(&storable_principal);
+        } else {
+            roles.borrow_mut().insert(storable_principal, set);
+        }
+    });
+
+    Ok(())
+}
+
+#[update]
+fn grant_role(principal: Principal, role: Role) -> Result<(), DeviteError> {
+    require_role(Role::Admin)?;
+    assign_role(principal, role);
+    Ok(())
+}
+
+#[update]
+fn revoke_role(principal: Principal, role: Role) -> Result<(), DeviteError> {
+    require_role(Role::Admin)?;
+    remove_role(principal, role)
+}
+
+#[query]
+fn list_role_holders(role: Role) -> Vec<Principal> {
+    ROLES.with(|roles| {
+        roles.borrow().iter()
+            .filter(|(_, set)| set.0.contains(&role))
+            .map(|(principal, _)| principal.into())
+            .collect()
+    })
+}
+
+#[query]
+fn get_roles(principal: Principal) -> Vec<Role> {
+    ROLES.with(|roles| {
+        roles.borrow()
+            .get(&StorablePrincipal::from(principal))
+            .map(|set| set.0)
+            .unwrap_or_default()
+    })
+}
@@ -0,0 +1,383 @@
+// Authorship attestations for mints, signed with threshold ECDSA so they can
+// be checked without an IC certificate. An attestation is an ECDSA secp256k1
+// signature over SHA-256 of the canonical message
+//   "DEVITE-AUTHORSHIP-V1"
+//   || token_id as u64 BE || created_at as u64 BE
+//   || len(content_hash) as u16 BE || content_hash UTF-8 bytes
+//   || number of authors as u16 BE
+//   || for each author in order: len(author) as u16 BE || author UTF-8 bytes
+// and verifies against the SEC1 key from the `get_attestation_public_key` query
+// with any standard library. The signature is the 64-byte concatenation r || s.
+
+use candid::{CandidType, Deserialize, Principal, Encode, Decode};
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument, SignWithEcdsaArgument, SignWithEcdsaResponse,
+};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable, BoundedStorable};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use crate::access_control::{require_role, Role};
+use crate::error::{truncate_error, DeviteError, ResourceKind};
+use crate::{Memory, ResearchNFT, StorableString, MEMORY_MANAGER, RESEARCH_TOKENS};
+
+type AttestationConfigCell = StableCell<AttestationConfig, Memory>;
+type AttestationStorage = StableBTreeMap<u64, Attestation, Memory>;
+type PublicKeyStorage = StableBTreeMap<StorableString, AttestationPublicKey, Memory>;
+
+const DOMAIN_SEPARATOR: &[u8] = b"DEVITE-AUTHORSHIP-V1";
+// Keeps attestation keys apart from anything else the backend may sign
+const DERIVATION_PATH: &[u8] = b"attestation";
+// Key dfx provides to local replicas; mainnet deployments switch to "key_1"
+const DEFAULT_KEY_NAME: &str = "dfx_test_key";
+const MAX_KEY_NAME_LENGTH: usize = 64;
+// Fee of the production key; whatever the subnet does not charge is refunded
+const SIGN_WITH_ECDSA_CYCLES: u128 = 26_153_846_153;
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Signatures requested per retry run, which bounds the cycles it spends
+const MAX_SIGNATURES_PER_RUN: usize = 20;
+
+// ATTESTATION TYPES
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AttestationConfig {
+    pub key_name: String,
+}
+
+impl Default for AttestationConfig {
+    fn default() -> Self {
+        AttestationConfig {
+            key_name: DEFAULT_KEY_NAME.to_string(),
+        }
+    }
+}
+
+impl Storable for AttestationConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Recorded at mint and signed afterwards; `signature` stays None until the
+// management canister has answered
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Attestation {
+    pub token_id: u64,
+    // Canonical message, as described at the top of this module
+    pub message: Vec<u8>,
+    pub key_name: String,
+    pub signature: Option<Vec<u8>>,
+    pub signed_at: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+impl Storable for Attestation {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Attestation {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AttestationPublicKey {
+    pub key_name: String,
+    pub derivation_path: Vec<Vec<u8>>,
+    // SEC1 compressed secp256k1 point
+    pub public_key: Vec<u8>,
+}
+
+impl Storable for AttestationPublicKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AttestationPublicKey {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct AttestationReport {
+    pub signed: Vec<u64>,
+    pub errors: Vec<String>,
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Signing key and attestations (Memory ID 31, 32)
+    static ATTESTATION_CONFIG: RefCell<AttestationConfigCell> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))),
+            AttestationConfig::default(),
+        ).expect("Failed to initialize attestation config")
+    );
+
+    static ATTESTATIONS: RefCell<AttestationStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))),
+        )
+    );
+
+    // Public keys by key name (Memory ID 33). They never change, so each is
+    // fetched once and served to anyone from a query.
+    static PUBLIC_KEYS: RefCell<PublicKeyStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))),
+        )
+    );
+
+    // Tokens with a signature request in flight
+    static SIGNING: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+// ATTESTATION FUNCTIONS
+
+fn key_name() -> String {
+    ATTESTATION_CONFIG.with(|cell| cell.borrow().get().key_name.clone())
+}
+
+fn key_id(key_name: &str) -> EcdsaKeyId {
+    EcdsaKeyId {
+        curve: EcdsaCurve::Secp256k1,
+        name: key_name.to_string(),
+    }
+}
+
+fn derivation_path() -> Vec<Vec<u8>> {
+    vec![DERIVATION_PATH.to_vec()]
+}
+
+fn push_text(message: &mut Vec<u8>, text: &str) {
+    message.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    message.extend_from_slice(text.as_bytes());
+}
+
+fn canonical_message(token: &ResearchNFT) -> Vec<u8> {
+    let mut message = DOMAIN_SEPARATOR.to_vec();
+    message.extend_from_slice(&token.token_id.to_be_bytes());
+    message.extend_from_slice(&token.created_at.to_be_bytes());
+    push_text(&mut message, &token.content_hash);
+    message.extend_from_slice(&(token.authors.len() as u16).to_be_bytes());
+    for author in &token.authors {
+        push_text(&mut message, author);
+    }
+    message
+}
+
+fn store_attestation(attestation: Attestation) {
+    ATTESTATIONS.with(|attestations| attestations.borrow_mut().insert(attestation.token_id, attestation));
+}
+
+fn record_pending(token: &ResearchNFT) {
+    store_attestation(Attestation {
+        token_id: token.token_id,
+        message: canonical_message(token),
+        key_name: key_name(),
+        signature: None,
+        signed_at: None,
+        last_error: None,
+    });
+}
+
+// Records the attestation of a new mint and requests its signature in the
+// background, so minting neither waits for nor fails on the signature
+pub fn attest_mint(token: &ResearchNFT) {
+    record_pending(token);
+    let token_id = token.token_id;
+    ic_cdk::spawn(async move {
+        if let Err(error) = sign_attestation(token_id).await {
+            ic_cdk::println!("Failed to sign attestation for token {}: {}", token_id, error);
+        }
+    });
+}
+
+// Tokens minted before attestations existed are signed by the retry timer
+pub fn ensure_recorded() {
+    let unattested: Vec<ResearchNFT> = RESEARCH_TOKENS.with(|tokens| {
+        tokens.borrow()
+            .iter()
+            .filter(|(token_id, _)| !ATTESTATIONS.with(|attestations| attestations.borrow().contains_key(token_id)))
+            .map(|(_, token)| token)
+            .collect()
+    });
+    for token in &unattested {
+        record_pending(token);
+    }
+}
+
+// Fetches the public key of `key_name` unless it is already stored
+async fn cache_public_key(key_name: String) -> Result<(), DeviteError> {
+    let storable_name = StorableString::from(key_name.clone());
+    if PUBLIC_KEYS.with(|keys| keys.borrow().contains_key(&storable_name)) {
+        return Ok(());
+    }
+
+    let argument = EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: derivation_path(),
+        key_id: key_id(&key_name),
+    };
+    let (response,) = ecdsa_public_key(argument).await.map_err(|(code, msg)| DeviteError::CanisterCallFailed {
+        reason: format!("Failed to fetch attestation public key: {:?} - {}", code, msg),
+    })?;
+
+    let public_key = AttestationPublicKey {
+        key_name,
+        derivation_path: derivation_path(),
+        public_key: response.public_key,
+    };
+    PUBLIC_KEYS.with(|keys| keys.borrow_mut().insert(storable_name, public_key));
+    Ok(())
+}
+
+fn spawn_key_fetch() {
+    ic_cdk::spawn(async {
+        if let Err(error) = cache_public_key(key_name()).await {
+            ic_cdk::println!("Failed to cache attestation public key: {:?}", error);
+        }
+    });
+}
+
+// Init and upgrades cannot call other canisters, so the configured key is
+// fetched right after them, and by every retry run until it is stored
+pub fn start_retry_timer() {
+    ic_cdk_timers::set_timer(Duration::ZERO, spawn_key_fetch);
+    ic_cdk_timers::set_timer_interval(RETRY_INTERVAL, || {
+        spawn_key_fetch();
+        ic_cdk::spawn(async {
+            let report = sign_pending().await;
+            if !report.errors.is_empty() {
+                ic_cdk::println!("Attestation signing errors: {:?}", report.errors);
+            }
+        });
+    });
+}
+
+// Signs a pending attestation with the key configured at signing time.
+// Returns the error text, which is also kept on the attestation. The ic-cdk
+// helper for sign_with_ecdsa attaches no cycles, so the call is made directly.
+async fn sign_attestation(token_id: u64) -> Result<(), String> {
+    let Some(mut attestation) = ATTESTATIONS.with(|attestations| attestations.borrow().get(&token_id)) else {
+        return Err("Attestation not found".to_string());
+    };
+    if attestation.signature.is_some() || SIGNING.with(|signing| !signing.borrow_mut().insert(token_id)) {
+        return Ok(());
+    }
+
+    attestation.key_name = key_name();
+    let argument = SignWithEcdsaArgument {
+        message_hash: Sha256::digest(&attestation.message).to_vec(),
+        derivation_path: derivation_path(),
+        key_id: key_id(&attestation.key_name),
+    };
+    let result: Result<(SignWithEcdsaResponse,), _> = ic_cdk::api::call::call_with_payment128(
+        Principal::management_canister(),
+        "sign_with_ecdsa",
+        (argument,),
+        SIGN_WITH_ECDSA_CYCLES,
+    ).await;
+    SIGNING.with(|signing| signing.borrow_mut().remove(&token_id));
+
+    let result = match result {
+        Ok((response,)) => {
+            attestation.signature = Some(response.signature);
+            attestation.signed_at = Some(ic_cdk::api::time());
+            attestation.last_error = None;
+            Ok(())
+        }
+        Err((code, msg)) => {
+            let error = truncate_error(format!("{:?} - {}", code, msg));
+            attestation.last_error = Some(error.clone());
+            Err(error)
+        }
+    };
+    store_attestation(attestation);
+    result
+}
+
+async fn sign_pending() -> AttestationReport {
+    let pending: Vec<u64> = ATTESTATIONS.with(|attestations| {
+        attestations.borrow()
+            .iter()
+            .filter(|(_, attestation)| attestation.signature.is_none())
+            .map(|(token_id, _)| token_id)
+            .take(MAX_SIGNATURES_PER_RUN)
+            .collect()
+    });
+
+    let mut report = AttestationReport::default();
+    for token_id in pending {
+        match sign_attestation(token_id).await {
+            Ok(()) => report.signed.push(token_id),
+            Err(error) => report.errors.push(format!("Token {}: {}", token_id, error)),
+        }
+    }
+    report
+}
+
+// The key's public key is fetched first, so a key the subnet does not offer is
+// refused and every configured key can be served to verifiers
+#[update]
+async fn set_attestation_key(key_name: String) -> Result<(), DeviteError> {
+    require_role(Role::Admin)?;
+    if key_name.trim().is_empty() || key_name.len() > MAX_KEY_NAME_LENGTH || key_name.contains(char::is_control) {
+        return Err(DeviteError::ValidationFailed {
+            field: "key_name".to_string(),
+            reason: format!("must be a single line of 1 to {} bytes", MAX_KEY_NAME_LENGTH),
+        });
+    }
+    cache_public_key(key_name.clone()).await?;
+    ATTESTATION_CONFIG.with(|cell| {
+        cell.borrow_mut().set(AttestationConfig { key_name }).expect("Failed to store attestation config")
+    });
+    Ok(())
+}
+
+#[query]
+fn get_attestation_config() -> AttestationConfig {
+    ATTESTATION_CONFIG.with(|cell| cell.borrow().get().clone())
+}
+
+#[query]
+fn get_attestation(token_id: u64) -> Option<Attestation> {
+    ATTESTATIONS.with(|attestations| attestations.borrow().get(&token_id))
+}
+
+// Signs up to a batch of attestations still waiting for a signature
+#[update]
+async fn sign_pending_attestations() -> Result<AttestationReport, DeviteError> {
+    require_role(Role::Admin)?;
+    Ok(sign_pending().await)
+}
+
+// Key of the configured signing key, or of `key_name` for attestations signed
+// before the key was changed. A query, so anonymous verifiers can fetch it.
+#[query]
+fn get_attestation_public_key(key_name: Option<String>) -> Result<AttestationPublicKey, DeviteError> {
+    let key_name = key_name.unwrap_or_else(self::key_name);
+    PUBLIC_KEYS.with(|keys| keys.borrow().get(&StorableString::from(key_name.clone())))
+        .ok_or_else(|| DeviteError::not_found(ResourceKind::AttestationKey, key_name))
+}
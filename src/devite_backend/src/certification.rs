@@ -0,0 +1,345 @@
+// Proof-of-existence certificates for mints. Every mint is a leaf of a Merkle
+// tree whose root is the canister's certified data, so a mint certificate can
+// be checked offline against the IC root key:
+//   1. verify `certificate` as an IC certificate and read
+//      /canister/<backend id>/certified_data from its tree; that is `root`
+//   2. hash the leaf and fold the witness into it; the result must be `root`
+//
+// Hashes are SHA-256:
+//   leaf = H(0x00 || token_id as u64 BE || created_at as u64 BE
+//            || len(owner) as u8 || owner bytes
+//            || len(content_hash) as u16 BE || content_hash UTF-8 bytes)
+//   node = H(0x01 || left || right)
+// Leaves are ordered by token id. A node without a sibling is carried up to
+// the next level unchanged, and contributes no witness step. The root of an
+// empty tree is 32 zero bytes.
+
+use candid::{CandidType, Deserialize, Principal, Encode, Decode};
+use ic_cdk::query;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, Storable, BoundedStorable};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::borrow::Cow;
+
+use crate::error::{DeviteError, ResourceKind};
+use crate::{Memory, ResearchNFT, MEMORY_MANAGER, RESEARCH_TOKENS};
+
+type CertifiedMintStorage = StableBTreeMap<u64, CertifiedMint, Memory>;
+type Hash = [u8; 32];
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+// CERTIFICATION TYPES
+
+// What a mint certificate vouches for. Unlike the token itself it never
+// changes: `owner` is the principal that minted, whoever holds the token now.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CertifiedMint {
+    pub token_id: u64,
+    pub content_hash: String,
+    pub owner: Principal,
+    pub created_at: u64,
+}
+
+impl Storable for CertifiedMint {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CertifiedMint {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct WitnessStep {
+    pub sibling: Vec<u8>,
+    // Whether the sibling is hashed before the running hash
+    pub sibling_is_left: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct MintCertificate {
+    pub mint: CertifiedMint,
+    pub leaf_index: u64,
+    pub tree_size: u64,
+    // From the leaf upwards
+    pub witness: Vec<WitnessStep>,
+    pub root: Vec<u8>,
+    // IC certificate over the canister's certified data, which is `root`
+    pub certificate: Vec<u8>,
+}
+
+// Every level of the tree, leaves first; the last level holds the root
+#[derive(Default)]
+struct MerkleTree {
+    token_ids: Vec<u64>,
+    levels: Vec<Vec<Hash>>,
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Certified mints (Memory ID 30)
+    static CERTIFIED_MINTS: RefCell<CertifiedMintStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))),
+        )
+    );
+
+    // Rebuilt from the certified mints after every upgrade
+    static TREE: RefCell<MerkleTree> = RefCell::new(MerkleTree::default());
+}
+
+// CERTIFICATION FUNCTIONS
+
+fn leaf_hash(mint: &CertifiedMint) -> Hash {
+    let owner = mint.owner.as_slice();
+    let content_hash = mint.content_hash.as_bytes();
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(mint.token_id.to_be_bytes());
+    hasher.update(mint.created_at.to_be_bytes());
+    hasher.update([owner.len() as u8]);
+    hasher.update(owner);
+    hasher.update((content_hash.len() as u16).to_be_bytes());
+    hasher.update(content_hash);
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+impl MerkleTree {
+    // Only the last node of each level changes, so appending costs one hash
+    // per level
+    fn append(&mut self, token_id: u64, leaf: Hash) {
+        self.token_ids.push(token_id);
+        if self.levels.is_empty() {
+            self.levels.push(Vec::new());
+        }
+        self.levels[0].push(leaf);
+
+        let mut depth = 0;
+        while self.levels[depth].len() > 1 {
+            let level = &self.levels[depth];
+            let index = (level.len() - 1) / 2;
+            let node = match level.get(2 * index + 1) {
+                Some(right) => node_hash(&level[2 * index], right),
+                None => level[2 * index],
+            };
+            if self.levels.len() == depth + 1 {
+                self.levels.push(Vec::new());
+            }
+            let parent = &mut self.levels[depth + 1];
+            if index < parent.len() {
+                parent[index] = node;
+            } else {
+                parent.push(node);
+            }
+            depth += 1;
+        }
+    }
+
+    fn root(&self) -> Hash {
+        self.levels.last().and_then(|level| level.first()).copied().unwrap_or([0; 32])
+    }
+
+    fn witness(&self, mut index: usize) -> Vec<WitnessStep> {
+        let mut witness = Vec::new();
+        for level in &self.levels[..self.levels.len().saturating_sub(1)] {
+            let sibling = index ^ 1;
+            if let Some(hash) = level.get(sibling) {
+                witness.push(WitnessStep {
+                    sibling: hash.to_vec(),
+                    sibling_is_left: sibling < index,
+                });
+            }
+            index /= 2;
+        }
+        witness
+    }
+}
+
+// Who minted `token_id`, whoever holds it now
+pub fn original_minter(token_id: u64) -> Option<Principal> {
+    CERTIFIED_MINTS.with(|mints| mints.borrow().get(&token_id)).map(|mint| mint.owner)
+}
+
+fn publish_root() {
+    let root = TREE.with(|tree| tree.borrow().root());
+    ic_cdk::api::set_certified_data(&root);
+}
+
+// Adds a new mint to the tree; token ids only ever grow, so it lands last
+pub fn certify_mint(token: &ResearchNFT) {
+    let mint = CertifiedMint {
+        token_id: token.token_id,
+        content_hash: token.content_hash.clone(),
+        owner: token.owner,
+        created_at: token.created_at,
+    };
+    TREE.with(|tree| tree.borrow_mut().append(mint.token_id, leaf_hash(&mint)));
+    CERTIFIED_MINTS.with(|mints| mints.borrow_mut().insert(mint.token_id, mint));
+    publish_root();
+}
+
+// Certified data does not survive upgrades. Tokens minted before mints were
+// certified are added first, with whoever owned them at that point.
+pub fn restore_certified_data() {
+    let uncertified: Vec<ResearchNFT> = RESEARCH_TOKENS.with(|tokens| {
+        tokens.borrow()
+            .iter()
+            .filter(|(token_id, _)| !CERTIFIED_MINTS.with(|mints| mints.borrow().contains_key(token_id)))
+            .map(|(_, token)| token)
+            .collect()
+    });
+    CERTIFIED_MINTS.with(|mints| {
+        let mut mints = mints.borrow_mut();
+        for token in uncertified {
+            mints.insert(token.token_id, CertifiedMint {
+                token_id: token.token_id,
+                content_hash: token.content_hash,
+                owner: token.owner,
+                created_at: token.created_at,
+            });
+        }
+    });
+
+    let mut tree = MerkleTree::default();
+    CERTIFIED_MINTS.with(|mints| {
+        for (token_id, mint) in mints.borrow().iter() {
+            tree.append(token_id, leaf_hash(&mint));
+        }
+    });
+    TREE.with(|cell| *cell.borrow_mut() = tree);
+    publish_root();
+}
+
+// Only answers query calls, which are the only ones carrying a certificate
+#[query]
+fn get_mint_certificate(token_id: u64) -> Result<MintCertificate, DeviteError> {
+    let mint = CERTIFIED_MINTS.with(|mints| mints.borrow().get(&token_id))
+        .ok_or_else(|| DeviteError::not_found(ResourceKind::ResearchToken, token_id))?;
+    let certificate = ic_cdk::api::data_certificate().ok_or(DeviteError::CertificateUnavailable)?;
+
+    TREE.with(|tree| {
+        let tree = tree.borrow();
+        let leaf_index = tree.token_ids.binary_search(&token_id)
+            .map_err(|_| DeviteError::not_found(ResourceKind::ResearchToken, token_id))?;
+        Ok(MintCertificate {
+            mint,
+            leaf_index: leaf_index as u64,
+            tree_size: tree.token_ids.len() as u64,
+            witness: tree.witness(leaf_index),
+            root: tree.root().to_vec(),
+            certificate,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mint(token_id: u64) -> CertifiedMint {
+        CertifiedMint {
+            token_id,
+            content_hash: format!("1220{:064x}", token_id),
+            owner: Principal::from_slice(&[token_id as u8; 29]),
+            created_at: 1_700_000_000_000_000_000 + token_id,
+        }
+    }
+
+    fn tree_of(count: u64) -> MerkleTree {
+        let mut tree = MerkleTree::default();
+        for token_id in 1..=count {
+            tree.append(token_id, leaf_hash(&mint(token_id)));
+        }
+        tree
+    }
+
+    // What an offline verifier does with a certificate
+    fn fold(leaf: Hash, witness: &[WitnessStep]) -> Hash {
+        witness.iter().fold(leaf, |hash, step| {
+            let sibling: Hash = step.sibling.as_slice().try_into().unwrap();
+            if step.sibling_is_left {
+                node_hash(&sibling, &hash)
+            } else {
+                node_hash(&hash, &sibling)
+            }
+        })
+    }
+
+    // The root as the format describes it, computed from scratch
+    fn reference_root(mut level: Vec<Hash>) -> Hash {
+        if level.is_empty() {
+            return [0; 32];
+        }
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+        }
+        level[0]
+    }
+
+    #[test]
+    fn appending_matches_a_full_rebuild() {
+        for count in 0..=17 {
+            let leaves = (1..=count).map(|token_id| leaf_hash(&mint(token_id))).collect();
+            assert_eq!(tree_of(count).root(), reference_root(leaves), "{} leaves", count);
+        }
+    }
+
+    #[test]
+    fn every_witness_folds_to_the_root() {
+        for count in 1..=17 {
+            let tree = tree_of(count);
+            for index in 0..count as usize {
+                let leaf = leaf_hash(&mint(index as u64 + 1));
+                assert_eq!(fold(leaf, &tree.witness(index)), tree.root(), "leaf {} of {}", index, count);
+            }
+        }
+    }
+
+    #[test]
+    fn witness_does_not_prove_a_changed_mint() {
+        let tree = tree_of(9);
+        let mut forged = mint(5);
+        forged.owner = Principal::anonymous();
+        assert_ne!(fold(leaf_hash(&forged), &tree.witness(4)), tree.root());
+        // Nor the right mint at another position
+        assert_ne!(fold(leaf_hash(&mint(5)), &tree.witness(5)), tree.root());
+    }
+
+    #[test]
+    fn leaf_hash_follows_the_documented_layout() {
+        let mint = mint(3);
+        let mut bytes = vec![LEAF_PREFIX];
+        bytes.extend_from_slice(&3u64.to_be_bytes());
+        bytes.extend_from_slice(&mint.created_at.to_be_bytes());
+        bytes.push(29);
+        bytes.extend_from_slice(mint.owner.as_slice());
+        bytes.extend_from_slice(&(mint.content_hash.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(mint.content_hash.as_bytes());
+        assert_eq!(leaf_hash(&mint), <Hash>::from(Sha256::digest(&bytes)));
+    }
+}
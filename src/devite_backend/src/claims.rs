@@ -0,0 +1,264 @@
+use candid::{CandidType, Deserialize, Principal, Encode, Decode};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable, BoundedStorable};
+use std::cell::RefCell;
+use std::borrow::Cow;
+
+use crate::access_control::{require_authenticated, require_role, Role};
+use crate::certification;
+use crate::error::{DeviteError, ResourceKind};
+use crate::validation::Validate;
+use crate::{retract_token, Memory, MEMORY_MANAGER, RESEARCH_TOKENS};
+
+type ClaimPolicyCell = StableCell<ClaimPolicy, Memory>;
+type DuplicateStorage = StableBTreeMap<u64, DuplicateMint, Memory>;
+
+// CLAIM TYPES
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq)]
+pub enum DuplicatePolicy {
+    // Mints of content that is already minted fail
+    Reject,
+    // Copies are minted without a reward and recorded against the prior
+    // claim, whose minter can dispute them
+    Flag,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ClaimPolicy {
+    pub duplicates: DuplicatePolicy,
+}
+
+impl Default for ClaimPolicy {
+    fn default() -> Self {
+        ClaimPolicy {
+            duplicates: DuplicatePolicy::Reject,
+        }
+    }
+}
+
+impl Storable for ClaimPolicy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq)]
+pub enum DisputeStatus {
+    Open,
+    // The copy was retracted
+    Upheld,
+    Dismissed,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Dispute {
+    pub reason: String,
+    pub opened_at: u64,
+    pub status: DisputeStatus,
+    pub resolved_by: Option<Principal>,
+    pub resolved_at: Option<u64>,
+}
+
+// Token minted under the Flag policy for content an earlier token claims
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DuplicateMint {
+    pub token_id: u64,
+    pub owner: Principal,
+    pub prior_token_id: u64,
+    // Minter of the prior token, who keeps the claim after a transfer
+    pub prior_owner: Principal,
+    pub flagged_at: u64,
+    pub dispute: Option<Dispute>,
+}
+
+impl Storable for DuplicateMint {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DuplicateMint {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct OpenDisputeRequest {
+    pub token_id: u64,
+    pub reason: String,
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Duplicate content policy and flagged copies (Memory ID 26, 27)
+    static CLAIM_POLICY: RefCell<ClaimPolicyCell> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))),
+            ClaimPolicy::default(),
+        ).expect("Failed to initialize claim policy")
+    );
+
+    static DUPLICATES: RefCell<DuplicateStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))),
+        )
+    );
+}
+
+// CLAIM FUNCTIONS
+
+fn claim_policy() -> ClaimPolicy {
+    CLAIM_POLICY.with(|cell| cell.borrow().get().clone())
+}
+
+fn get_duplicate(token_id: u64) -> Result<DuplicateMint, DeviteError> {
+    DUPLICATES.with(|duplicates| duplicates.borrow().get(&token_id))
+        .ok_or_else(|| DeviteError::not_found(ResourceKind::DuplicateMint, token_id))
+}
+
+fn store_duplicate(duplicate: DuplicateMint) {
+    DUPLICATES.with(|duplicates| duplicates.borrow_mut().insert(duplicate.token_id, duplicate));
+}
+
+// A retracted token gives up its claim, so its content can be minted again
+pub fn holds_claim(token_id: u64) -> bool {
+    RESEARCH_TOKENS.with(|tokens| tokens.borrow().get(&token_id)).is_some_and(|token| token.retracted_at.is_none())
+}
+
+// Decides whether `minter` may mint content `prior_token_id` already claims.
+// Minting the same content twice is never useful to its own minter.
+pub fn check_duplicate(minter: Principal, prior_token_id: u64) -> Result<(), DeviteError> {
+    let prior_minter = certification::original_minter(prior_token_id);
+    if claim_policy().duplicates == DuplicatePolicy::Reject || prior_minter == Some(minter) {
+        return Err(DeviteError::DuplicateContent { token_id: prior_token_id });
+    }
+    Ok(())
+}
+
+pub fn flag_duplicate(token_id: u64, owner: Principal, prior_token_id: u64, flagged_at: u64) {
+    let prior_owner = certification::original_minter(prior_token_id).unwrap_or_else(Principal::anonymous);
+    store_duplicate(DuplicateMint {
+        token_id,
+        owner,
+        prior_token_id,
+        prior_owner,
+        flagged_at,
+        dispute: None,
+    });
+}
+
+#[update]
+fn set_duplicate_policy(policy: DuplicatePolicy) -> Result<(), DeviteError> {
+    require_role(Role::Admin)?;
+    CLAIM_POLICY.with(|cell| {
+        cell.borrow_mut().set(ClaimPolicy { duplicates: policy }).expect("Failed to store claim policy")
+    });
+    Ok(())
+}
+
+#[query]
+fn get_claim_policy() -> ClaimPolicy {
+    claim_policy()
+}
+
+// Only the owner of the prior claim can contest a copy, once
+#[update]
+fn dispute_duplicate(request: OpenDisputeRequest) -> Result<DuplicateMint, DeviteError> {
+    let caller = require_authenticated()?;
+    request.validate()?;
+
+    let mut duplicate = get_duplicate(request.token_id)?;
+    if duplicate.prior_owner != caller {
+        return Err(DeviteError::NotOwner);
+    }
+    if duplicate.dispute.is_some() {
+        return Err(DeviteError::AlreadyRecorded);
+    }
+    let retracted = RESEARCH_TOKENS.with(|tokens| tokens.borrow().get(&request.token_id))
+        .is_none_or(|token| token.retracted_at.is_some());
+    if retracted {
+        return Err(DeviteError::TokenRetracted { token_id: request.token_id });
+    }
+
+    duplicate.dispute = Some(Dispute {
+        reason: request.reason,
+        opened_at: ic_cdk::api::time(),
+        status: DisputeStatus::Open,
+        resolved_by: None,
+        resolved_at: None,
+    });
+    store_duplicate(duplicate.clone());
+    Ok(duplicate)
+}
+
+// Upholding a dispute retracts the copy
+#[update]
+fn resolve_dispute(token_id: u64, uphold: bool) -> Result<DuplicateMint, DeviteError> {
+    let caller = require_authenticated()?;
+    require_role(Role::Moderator)?;
+
+    let mut duplicate = get_duplicate(token_id)?;
+    let dispute = duplicate.dispute.as_mut()
+        .ok_or_else(|| DeviteError::not_found(ResourceKind::Dispute, token_id))?;
+    if dispute.status != DisputeStatus::Open {
+        return Err(DeviteError::InvalidDisputeStatus { status: dispute.status });
+    }
+
+    let current_time = ic_cdk::api::time();
+    dispute.status = if uphold { DisputeStatus::Upheld } else { DisputeStatus::Dismissed };
+    dispute.resolved_by = Some(caller);
+    dispute.resolved_at = Some(current_time);
+
+    if uphold {
+        let token = RESEARCH_TOKENS.with(|tokens| tokens.borrow().get(&token_id))
+            .ok_or_else(|| DeviteError::not_found(ResourceKind::ResearchToken, token_id))?;
+        // The owner may have withdrawn the copy while the dispute was open
+        if token.retracted_at.is_none() {
+            retract_token(token, current_time);
+        }
+    }
+
+    store_duplicate(duplicate.clone());
+    Ok(duplicate)
+}
+
+#[query]
+fn get_duplicate_mint(token_id: u64) -> Option<DuplicateMint> {
+    DUPLICATES.with(|duplicates| duplicates.borrow().get(&token_id))
+}
+
+// Copies flagged against `prior_token_id`, oldest first
+#[query]
+fn get_duplicates_of(prior_token_id: u64) -> Vec<DuplicateMint> {
+    DUPLICATES.with(|duplicates| {
+        duplicates.borrow()
+            .iter()
+            .map(|(_, duplicate)| duplicate)
+            .filter(|duplicate| duplicate.prior_token_id == prior_token_id)
+            .collect()
+    })
+}
+
+#[query]
+fn list_open_disputes() -> Vec<DuplicateMint> {
+    DUPLICATES.with(|duplicates| {
+        duplicates.borrow()
+            .iter()
+            .map(|(_, duplicate)| duplicate)
+            .filter(|duplicate| {
+                duplicate.dispute.as_ref().is_some_and(|dispute| dispute.status == DisputeStatus::Open)
+            })
+            .collect()
+    })
+}
@@ -0,0 +1,318 @@
+// Content hashes are accepted as
+//   - a hex encoded multihash ("1220...")
+//   - a CIDv0 ("Qm...")
+//   - a CIDv1 in base32 ("b..."), base58btc ("z...") or base16 ("f...")
+//   - a bare hex SHA-256, as minted before hashes were parsed
+// and stored as the binary multihash they name, so every spelling of the
+// same digest finds the same token.
+
+use crate::{StorableString, CONTENT_HASHES, RESEARCH_TOKENS};
+
+const BASE32_ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+// Longest unsigned varint multiformats allow
+const MAX_VARINT_BYTES: usize = 9;
+const CID_VERSION_1: u64 = 1;
+// raw, dag-pb, dag-cbor and dag-json blocks
+const CID_CODECS: &[u64] = &[0x55, 0x70, 0x71, 0x0129];
+
+// CONTENT HASH TYPES
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HashAlgorithm {
+    Sha2_256,
+    Sha2_512,
+    Sha3_256,
+    Sha3_512,
+    Blake2b256,
+    Blake3,
+}
+
+struct AlgorithmSpec {
+    algorithm: HashAlgorithm,
+    code: u64,
+    digest_length: usize,
+}
+
+// Hash functions accepted for research content, with their multicodec codes
+const ALGORITHMS: &[AlgorithmSpec] = &[
+    AlgorithmSpec { algorithm: HashAlgorithm::Sha2_256, code: 0x12, digest_length: 32 },
+    AlgorithmSpec { algorithm: HashAlgorithm::Sha2_512, code: 0x13, digest_length: 64 },
+    AlgorithmSpec { algorithm: HashAlgorithm::Sha3_512, code: 0x14, digest_length: 64 },
+    AlgorithmSpec { algorithm: HashAlgorithm::Sha3_256, code: 0x16, digest_length: 32 },
+    AlgorithmSpec { algorithm: HashAlgorithm::Blake3, code: 0x1e, digest_length: 32 },
+    AlgorithmSpec { algorithm: HashAlgorithm::Blake2b256, code: 0xb220, digest_length: 32 },
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContentHash {
+    pub algorithm: HashAlgorithm,
+    pub digest: Vec<u8>,
+}
+
+// CONTENT HASH FUNCTIONS
+
+fn read_varint(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let mut value = 0u64;
+    for (index, byte) in bytes.iter().take(MAX_VARINT_BYTES).enumerate() {
+        value |= u64::from(byte & 0x7f) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Some((value, &bytes[index + 1..]));
+        }
+    }
+    None
+}
+
+fn write_varint(mut value: u64, bytes: &mut Vec<u8>) {
+    while value >= 0x80 {
+        bytes.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&text[index..index + 2], 16).ok())
+        .collect()
+}
+
+// Unpadded RFC 4648 base32, lowercase
+fn decode_base32(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let value = BASE32_ALPHABET.iter().position(|&symbol| symbol == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    // Leftover bits are padding and must be zero
+    (bits < 5 && buffer == 0).then_some(bytes)
+}
+
+fn decode_base58(text: &str) -> Option<Vec<u8>> {
+    // Little-endian while accumulating
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in text.bytes() {
+        let mut carry = BASE58_ALPHABET.iter().position(|&symbol| symbol == c)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += u32::from(*byte) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    let leading_zeros = text.bytes().take_while(|&c| c == BASE58_ALPHABET[0]).count();
+    bytes.extend(std::iter::repeat_n(0, leading_zeros));
+    bytes.reverse();
+    Some(bytes)
+}
+
+fn parse_multihash(bytes: &[u8]) -> Result<ContentHash, String> {
+    let (code, rest) = read_varint(bytes).ok_or("has a malformed multihash code")?;
+    let (length, digest) = read_varint(rest).ok_or("has a malformed multihash length")?;
+    let spec = ALGORITHMS.iter()
+        .find(|spec| spec.code == code)
+        .ok_or_else(|| format!("uses unsupported hash function 0x{:x}", code))?;
+    if length != spec.digest_length as u64 || digest.len() != spec.digest_length {
+        return Err(format!("must carry a {}-byte digest for hash function 0x{:x}", spec.digest_length, code));
+    }
+    Ok(ContentHash {
+        algorithm: spec.algorithm,
+        digest: digest.to_vec(),
+    })
+}
+
+fn parse_cid_v1(bytes: &[u8]) -> Result<ContentHash, String> {
+    let (version, rest) = read_varint(bytes).ok_or("has a malformed CID version")?;
+    if version != CID_VERSION_1 {
+        return Err(format!("uses unsupported CID version {}", version));
+    }
+    let (codec, multihash) = read_varint(rest).ok_or("has a malformed CID codec")?;
+    if !CID_CODECS.contains(&codec) {
+        return Err(format!("uses unsupported CID codec 0x{:x}", codec));
+    }
+    parse_multihash(multihash)
+}
+
+impl ContentHash {
+    // Errors read as the end of a sentence starting with the field name
+    pub fn parse(text: &str) -> Result<ContentHash, String> {
+        const UNRECOGNIZED: &str = "must be a multihash, a CID or a hex SHA-256";
+
+        if text.len() == 46 && text.starts_with("Qm") {
+            let bytes = decode_base58(text).ok_or("is not valid base58")?;
+            return parse_multihash(&bytes);
+        }
+        // Even-length hex never collides with a multibase CID, whose prefix
+        // makes it odd in base16 and non-hex in base32 and base58
+        if let Some(bytes) = decode_hex(text) {
+            if bytes.len() == 32 {
+                return Ok(ContentHash {
+                    algorithm: HashAlgorithm::Sha2_256,
+                    digest: bytes,
+                });
+            }
+            return parse_multihash(&bytes);
+        }
+
+        let mut chars = text.chars();
+        let bytes = match (chars.next(), chars.as_str()) {
+            (Some('b'), encoded) => decode_base32(encoded),
+            (Some('B'), encoded) => decode_base32(&encoded.to_ascii_lowercase()),
+            (Some('z'), encoded) => decode_base58(encoded),
+            (Some('f' | 'F'), encoded) => decode_hex(encoded),
+            _ => return Err(UNRECOGNIZED.to_string()),
+        };
+        parse_cid_v1(&bytes.ok_or(UNRECOGNIZED)?)
+    }
+
+    fn code(&self) -> u64 {
+        ALGORITHMS.iter()
+            .find(|spec| spec.algorithm == self.algorithm)
+            .map(|spec| spec.code)
+            .expect("every algorithm has a multicodec code")
+    }
+
+    pub fn multihash(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.digest.len() + 4);
+        write_varint(self.code(), &mut bytes);
+        write_varint(self.digest.len() as u64, &mut bytes);
+        bytes.extend_from_slice(&self.digest);
+        bytes
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Key of a content hash in `CONTENT_HASHES`: the hex multihash, or the text
+// itself for tokens minted before hashes were parsed
+pub fn index_key(text: &str) -> StorableString {
+    match ContentHash::parse(text) {
+        Ok(hash) => StorableString::from(to_hex(&hash.multihash())),
+        Err(_) => StorableString::from(text.to_string()),
+    }
+}
+
+// Gives tokens minted before hashes were parsed their multihash and moves
+// their index entry to the normalized key. Text that never parsed is left as is.
+pub fn normalize_stored_hashes() {
+    let pending: Vec<_> = RESEARCH_TOKENS.with(|tokens| {
+        tokens.borrow()
+            .iter()
+            .filter(|(_, nft)| nft.content_multihash.is_none())
+            .filter_map(|(_, nft)| ContentHash::parse(&nft.content_hash).ok().map(|hash| (nft, hash)))
+            .collect()
+    });
+
+    for (mut nft, hash) in pending {
+        CONTENT_HASHES.with(|hashes| {
+            let mut hashes = hashes.borrow_mut();
+            let legacy_key = StorableString::from(nft.content_hash.clone());
+            if hashes.get(&legacy_key) == Some(nft.token_id) {
+                hashes.remove(&legacy_key);
+            }
+            let key = index_key(&nft.content_hash);
+            if !hashes.contains_key(&key) {
+                hashes.insert(key, nft.token_id);
+            }
+        });
+        nft.content_multihash = Some(hash.multihash());
+        RESEARCH_TOKENS.with(|tokens| tokens.borrow_mut().insert(nft.token_id, nft));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SHA-256 of "devite" in every accepted spelling
+    const DIGEST: &str = "823970a70ee43c9ad43372e62a17ad717e9231e3aeab7021f5fa902096edf5c4";
+    const MULTIHASH: &str = "1220823970a70ee43c9ad43372e62a17ad717e9231e3aeab7021f5fa902096edf5c4";
+    const CID_V0: &str = "QmX6zWZdigqfL4V4JZQYJYpUXZJLNdbrefu6qYQcXdi6Qs";
+    const CID_V1_BASE32: &str = "bafkreiechfykodxehsnnim3s4yvbpllrp2jddy5ovnycd5p2saqjn3pvyq";
+    const CID_V1_BASE58: &str = "zb2rhfQczcqxY6gHFLcHfApzTCwHq1q67vbZShB9DJCHugGcb";
+    const CID_V1_BASE16: &str = "f01551220823970a70ee43c9ad43372e62a17ad717e9231e3aeab7021f5fa902096edf5c4";
+
+    fn sha256(hex: &str) -> ContentHash {
+        ContentHash {
+            algorithm: HashAlgorithm::Sha2_256,
+            digest: decode_hex(hex).unwrap(),
+        }
+    }
+
+    #[test]
+    fn every_spelling_names_the_same_digest() {
+        let expected = sha256(DIGEST);
+        let upper_base32 = format!("B{}", CID_V1_BASE32[1..].to_ascii_uppercase());
+        for text in [DIGEST, MULTIHASH, CID_V0, CID_V1_BASE32, &upper_base32, CID_V1_BASE58, CID_V1_BASE16] {
+            assert_eq!(ContentHash::parse(text), Ok(expected.clone()), "{}", text);
+        }
+    }
+
+    #[test]
+    fn multihash_round_trips() {
+        let hash = sha256(DIGEST);
+        assert_eq!(to_hex(&hash.multihash()), MULTIHASH);
+        assert_eq!(ContentHash::parse(&to_hex(&hash.multihash())), Ok(hash));
+
+        // Two-byte multicodec code
+        let blake = ContentHash {
+            algorithm: HashAlgorithm::Blake2b256,
+            digest: vec![7; 32],
+        };
+        assert!(to_hex(&blake.multihash()).starts_with("a0e40220"));
+        assert_eq!(ContentHash::parse(&to_hex(&blake.multihash())), Ok(blake));
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for value in [0, 1, 0x7f, 0x80, 0xb220, u32::MAX as u64] {
+            let mut bytes = Vec::new();
+            write_varint(value, &mut bytes);
+            bytes.push(0xaa);
+            assert_eq!(read_varint(&bytes), Some((value, &[0xaa][..])));
+        }
+        assert_eq!(read_varint(&[0x80; MAX_VARINT_BYTES + 1]), None);
+        assert_eq!(read_varint(&[0x80]), None);
+    }
+
+    #[test]
+    fn rejects_malformed_hashes() {
+        for text in [
+            "",
+            "not a hash",
+            // Odd-length hex
+            &DIGEST[1..],
+            // Unsupported hash function 0x11 (SHA-1)
+            "1114aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+            // Declared length disagrees with the digest
+            &MULTIHASH[..MULTIHASH.len() - 2],
+            // CIDv0 with a character outside base58
+            &CID_V0.replace('X', "0"),
+            // Unsupported CID codec 0x56
+            &CID_V1_BASE16.replace("f0155", "f0156"),
+            // CID version 2
+            &CID_V1_BASE16.replace("f0155", "f0255"),
+            // Nonzero padding bits in base32
+            &format!("{}r", &CID_V1_BASE32[..CID_V1_BASE32.len() - 1]),
+        ] {
+            assert!(ContentHash::parse(text).is_err(), "accepted {:?}", text);
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use candid::{CandidType, Deserialize, Principal, Encode, Decode};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, Storable, BoundedStorable};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::borrow::Cow;
+
+use crate::access_control::require_authenticated;
+use crate::error::DeviteError;
+use crate::validation::Validate;
+use crate::{Memory, StorablePrincipal, MEMORY_MANAGER, USER_PROFILES};
+
+type EncryptionKeyStorage = StableBTreeMap<StorablePrincipal, EncryptionKey, Memory>;
+
+const MAX_KEY_LOOKUPS: usize = 100;
+
+// ENCRYPTION KEY TYPES
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq)]
+pub enum KeyAlgorithm {
+    X25519,
+    EcdhP256,
+    RsaOaepSha256,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RegisterEncryptionKeyRequest {
+    pub algorithm: KeyAlgorithm,
+    // Raw key for X25519, SEC1 point for P-256, SPKI DER for RSA
+    pub public_key: Vec<u8>,
+}
+
+// Public key other users wrap item keys to. Personal storage canisters store
+// the fingerprint with every envelope so stale wraps can be spotted.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct EncryptionKey {
+    pub algorithm: KeyAlgorithm,
+    pub public_key: Vec<u8>,
+    pub fingerprint: Vec<u8>,
+    pub registered_at: u64,
+}
+
+impl Storable for EncryptionKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EncryptionKey {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct UserEncryptionKey {
+    pub user: Principal,
+    pub key: EncryptionKey,
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Registered public encryption keys (Memory ID 25)
+    static ENCRYPTION_KEYS: RefCell<EncryptionKeyStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))),
+        )
+    );
+}
+
+// ENCRYPTION KEY FUNCTIONS
+
+fn encryption_key(user: Principal) -> Option<EncryptionKey> {
+    ENCRYPTION_KEYS.with(|keys| keys.borrow().get(&StorablePrincipal::from(user)))
+}
+
+// Replacing a key leaves existing envelopes wrapped to the old one; owners
+// re-wrap them once they see the new fingerprint
+#[update]
+fn register_encryption_key(request: RegisterEncryptionKeyRequest) -> Result<EncryptionKey, DeviteError> {
+    let caller = require_authenticated()?;
+    let storable_caller = StorablePrincipal::from(caller);
+    if !USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&storable_caller)) {
+        return Err(DeviteError::NotRegistered);
+    }
+    request.validate()?;
+
+    let key = EncryptionKey {
+        algorithm: request.algorithm,
+        fingerprint: Sha256::digest(&request.public_key).to_vec(),
+        public_key: request.public_key,
+        registered_at: ic_cdk::api::time(),
+    };
+    ENCRYPTION_KEYS.with(|keys| keys.borrow_mut().insert(storable_caller, key.clone()));
+    Ok(key)
+}
+
+#[update]
+fn remove_encryption_key() -> Result<(), DeviteError> {
+    let caller = require_authenticated()?;
+    ENCRYPTION_KEYS.with(|keys| keys.borrow_mut().remove(&StorablePrincipal::from(caller)));
+    Ok(())
+}
+
+#[query]
+fn get_encryption_key(user: Principal) -> Option<EncryptionKey> {
+    encryption_key(user)
+}
+
+// Keys of everyone an item is about to be wrapped for; users without a
+// registered key are left out
+#[query]
+fn get_encryption_keys(users: Vec<Principal>) -> Vec<UserEncryptionKey> {
+    users.into_iter()
+        .take(MAX_KEY_LOOKUPS)
+        .filter_map(|user| encryption_key(user).map(|key| UserEncryptionKey { user, key }))
+        .collect()
+}
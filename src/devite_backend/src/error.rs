@@ -0,0 +1,86 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::access_control::Role;
+use crate::claims::DisputeStatus;
+use crate::fleet::RolloutPhase;
+use crate::ProposalStatus;
+
+// ERROR TYPES
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq)]
+pub enum ResourceKind {
+    User,
+    ResearchToken,
+    Proposal,
+    PersonalCanister,
+    StoredItem,
+    DuplicateMint,
+    Dispute,
+    AttestationKey,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct FieldViolation {
+    pub field: String,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum DeviteError {
+    AnonymousCaller,
+    NotRegistered,
+    AlreadyRegistered,
+    RegistrationInProgress,
+    RecipientNotRegistered,
+    NotOwner,
+    Unauthorized { required_role: Role },
+    NotFound { kind: ResourceKind, id: String },
+    InsufficientTokens { required: u64, available: u64 },
+    InsufficientCycles { required: u128, available: u128 },
+    NoVotingPower,
+    VotingClosed,
+    VotingStillActive,
+    AlreadyVoted,
+    InvalidProposalStatus { status: ProposalStatus },
+    ValidationFailed { field: String, reason: String },
+    // Every rule a request broke, reported together
+    InvalidRequest { violations: Vec<FieldViolation> },
+    DuplicateContent { token_id: u64 },
+    // The stored item's SHA-256 differs from the content hash being minted
+    ContentHashMismatch { expected: String, actual: String },
+    RateLimited { limit: u64, window_seconds: u64 },
+    TokenRetracted { token_id: u64 },
+    // The caller already endorsed, reviewed or cited this token
+    AlreadyRecorded,
+    SelfInteraction,
+    LastAdmin,
+    CanisterCallFailed { reason: String },
+    StorageWasmNotConfigured,
+    InvalidRolloutPhase { phase: RolloutPhase },
+    InvalidDisputeStatus { status: DisputeStatus },
+    // Certificates are only available to query calls
+    CertificateUnavailable,
+    // The personal canister runs code the backend did not install
+    UntrustedStorageModule { canister_id: Principal },
+}
+
+// Longest error text kept in stable records, whose size is bounded
+const MAX_ERROR_LENGTH: usize = 256;
+
+// Reject messages come from other canisters and can be arbitrarily long
+pub fn truncate_error(mut message: String) -> String {
+    if message.len() > MAX_ERROR_LENGTH {
+        let mut end = MAX_ERROR_LENGTH;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+    message
+}
+
+impl DeviteError {
+    pub fn not_found(kind: ResourceKind, id: impl ToString) -> Self {
+        DeviteError::NotFound { kind, id: id.to_string() }
+    }
+}
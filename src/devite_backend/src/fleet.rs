@@ -0,0 +1,467 @@
+use candid::{CandidType, Deserialize, Principal, Encode, Decode};
+use ic_cdk::api::management_canister::main::CanisterInstallMode;
+use ic_cdk::{query, update};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable, BoundedStorable};
+use std::cell::RefCell;
+use std::borrow::Cow;
+use std::time::Duration;
+
+use crate::access_control::{require_role, Role};
+use crate::error::{truncate_error, DeviteError};
+use crate::personal_storage;
+use crate::quota::{self, CyclesSpendKind};
+use crate::validation::Validate;
+use crate::{Memory, StorablePrincipal, MEMORY_MANAGER};
+
+type PersonalCanisterStorage = StableBTreeMap<StorablePrincipal, PersonalCanisterRecord, Memory>;
+type RolloutCell = StableCell<RolloutState, Memory>;
+
+
+// FLEET TYPES
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PersonalCanisterRecord {
+    pub canister_id: Principal,
+    pub owner: Principal,
+    pub wasm_sha256: Vec<u8>,
+    pub installed_at: u64,
+    pub last_upgrade_error: Option<String>,
+}
+
+impl Storable for PersonalCanisterRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PersonalCanisterRecord {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RolloutConfig {
+    // Share of the fleet upgraded first; the rollout pauses if any of it fails
+    pub canary_percent: u8,
+    pub batch_size: u32,
+    pub batch_interval_secs: u64,
+    pub max_retries: u32,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum RolloutPhase {
+    Idle,
+    Canary,
+    Rolling,
+    Paused,
+    Completed,
+    Cancelled,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RolloutTarget {
+    pub canister_id: Principal,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RolloutState {
+    pub phase: RolloutPhase,
+    // Phase to return to when a paused rollout is resumed
+    pub resume_phase: Option<RolloutPhase>,
+    pub pause_reason: Option<String>,
+    pub config: Option<RolloutConfig>,
+    pub target_sha256: Vec<u8>,
+    pub canary: Vec<RolloutTarget>,
+    pub remaining: Vec<RolloutTarget>,
+    pub upgraded: Vec<Principal>,
+    pub failed: Vec<RolloutTarget>,
+    pub started_at: u64,
+    pub updated_at: u64,
+}
+
+impl Default for RolloutState {
+    fn default() -> Self {
+        RolloutState {
+            phase: RolloutPhase::Idle,
+            resume_phase: None,
+            pause_reason: None,
+            config: None,
+            target_sha256: Vec::new(),
+            canary: Vec::new(),
+            remaining: Vec::new(),
+            upgraded: Vec::new(),
+            failed: Vec::new(),
+            started_at: 0,
+            updated_at: 0,
+        }
+    }
+}
+
+impl Storable for RolloutState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl RolloutState {
+    pub fn is_active(&self) -> bool {
+        matches!(self.phase, RolloutPhase::Canary | RolloutPhase::Rolling | RolloutPhase::Paused)
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RolloutProgress {
+    pub phase: RolloutPhase,
+    pub pause_reason: Option<String>,
+    pub config: Option<RolloutConfig>,
+    pub target_sha256: Vec<u8>,
+    pub total: u64,
+    pub upgraded: u64,
+    pub pending: u64,
+    pub failed: Vec<RolloutTarget>,
+    pub started_at: u64,
+    pub updated_at: u64,
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Personal canister registry and rollout state (Memory ID 16, 17)
+    static PERSONAL_CANISTERS: RefCell<PersonalCanisterStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))),
+        )
+    );
+
+    static ROLLOUT: RefCell<RolloutCell> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+            RolloutState::default(),
+        ).expect("Failed to initialize rollout state")
+    );
+
+    static ROLLOUT_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+    static BATCH_IN_FLIGHT: RefCell<bool> = const { RefCell::new(false) };
+}
+
+// REGISTRY FUNCTIONS
+
+pub fn register_personal_canister(canister_id: Principal, owner: Principal, wasm_sha256: Vec<u8>) {
+    let record = PersonalCanisterRecord {
+        canister_id,
+        owner,
+        wasm_sha256,
+        installed_at: ic_cdk::api::time(),
+        last_upgrade_error: None,
+    };
+    PERSONAL_CANISTERS.with(|canisters| {
+        canisters.borrow_mut().insert(StorablePrincipal::from(canister_id), record);
+    });
+}
+
+pub fn personal_canister(canister_id: Principal) -> Option<PersonalCanisterRecord> {
+    PERSONAL_CANISTERS.with(|canisters| canisters.borrow().get(&StorablePrincipal::from(canister_id)))
+}
+
+pub fn personal_canisters() -> Vec<PersonalCanisterRecord> {
+    PERSONAL_CANISTERS.with(|canisters| canisters.borrow().iter().map(|(_, record)| record).collect())
+}
+
+pub fn rollout_state() -> RolloutState {
+    ROLLOUT.with(|cell| cell.borrow().get().clone())
+}
+
+fn save_rollout(mut state: RolloutState) {
+    state.updated_at = ic_cdk::api::time();
+    ROLLOUT.with(|cell| {
+        cell.borrow_mut().set(state).expect("Failed to store rollout state");
+    });
+}
+
+// ROLLOUT FUNCTIONS
+
+fn schedule_batches(interval_secs: u64) {
+    stop_batches();
+    let timer_id = ic_cdk_timers::set_timer_interval(Duration::from_secs(interval_secs), || {
+        ic_cdk::spawn(run_batch());
+    });
+    ROLLOUT_TIMER.with(|timer| *timer.borrow_mut() = Some(timer_id));
+}
+
+fn stop_batches() {
+    if let Some(timer_id) = ROLLOUT_TIMER.with(|timer| timer.borrow_mut().take()) {
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+}
+
+// Timers do not survive upgrades of the backend
+pub fn resume_rollout_timer() {
+    let state = rollout_state();
+    if matches!(state.phase, RolloutPhase::Canary | RolloutPhase::Rolling) {
+        if let Some(config) = state.config {
+            schedule_batches(config.batch_interval_secs);
+        }
+    }
+}
+
+async fn run_batch() {
+    // Ticks can fire while the previous batch is still awaiting upgrades
+    if BATCH_IN_FLIGHT.with(|flag| flag.replace(true)) {
+        return;
+    }
+
+    let mut state = rollout_state();
+    let config = match (&state.phase, state.config.clone()) {
+        (RolloutPhase::Canary | RolloutPhase::Rolling, Some(config)) => config,
+        _ => {
+            BATCH_IN_FLIGHT.with(|flag| *flag.borrow_mut() = false);
+            return;
+        }
+    };
+
+    let queue = if state.phase == RolloutPhase::Canary { &mut state.canary } else { &mut state.remaining };
+    let take = queue.len().min(config.batch_size as usize);
+    let batch: Vec<RolloutTarget> = queue.drain(..take).collect();
+    // Identifies this rollout, which may be cancelled and replaced meanwhile
+    let started_at = state.started_at;
+    save_rollout(state);
+
+    let wasm = personal_storage::storage_wasm();
+    let mut outcomes = Vec::with_capacity(batch.len());
+    for mut target in batch {
+        let result = match (&wasm, personal_canister(target.canister_id)) {
+            (Ok(wasm), Some(record)) => {
+                // Other messages may run during the await, so this is an estimate
+                let balance_before = ic_cdk::api::canister_balance128();
+                let result = personal_storage::install_personal_storage(
+                    target.canister_id,
+                    record.owner,
+                    wasm,
+                    CanisterInstallMode::Upgrade,
+                ).await;
+                let spent = balance_before.saturating_sub(ic_cdk::api::canister_balance128());
+                quota::record_cycles_spent(record.owner, CyclesSpendKind::Upgrade, spent);
+                result
+            }
+            (Err(error), _) => Err(error.clone()),
+            (_, None) => Err(DeviteError::CanisterCallFailed {
+                reason: "Canister is not in the personal canister registry".to_string(),
+            }),
+        };
+        target.attempts += 1;
+        target.last_error = result.err().map(|error| truncate_error(format!("{:?}", error)));
+        outcomes.push(target);
+    }
+
+    // Re-read the state: the rollout may have been paused while we awaited.
+    // Records always note what was installed; the queues belong to the rollout.
+    let mut state = rollout_state();
+    let same_rollout = state.started_at == started_at;
+    for target in outcomes {
+        PERSONAL_CANISTERS.with(|canisters| {
+            let key = StorablePrincipal::from(target.canister_id);
+            if let Some(mut record) = canisters.borrow().get(&key) {
+                if let (None, Ok(wasm)) = (&target.last_error, &wasm) {
+                    record.wasm_sha256 = wasm.sha256.clone();
+                    record.installed_at = ic_cdk::api::time();
+                }
+                record.last_upgrade_error = target.last_error.clone();
+                canisters.borrow_mut().insert(key, record);
+            }
+        });
+        if !same_rollout {
+            continue;
+        }
+
+        let in_canary = state.phase == RolloutPhase::Canary
+            || state.resume_phase == Some(RolloutPhase::Canary);
+        if target.last_error.is_none() {
+            state.upgraded.push(target.canister_id);
+        } else if target.attempts < config.max_retries {
+            // Retry at the end of the queue it came from
+            if in_canary {
+                state.canary.push(target);
+            } else {
+                state.remaining.push(target);
+            }
+        } else {
+            state.failed.push(target);
+        }
+    }
+
+    if same_rollout {
+        advance_phase(&mut state);
+        save_rollout(state);
+    }
+    BATCH_IN_FLIGHT.with(|flag| *flag.borrow_mut() = false);
+}
+
+fn advance_phase(state: &mut RolloutState) {
+    match state.phase {
+        RolloutPhase::Canary if state.canary.is_empty() => {
+            if state.failed.is_empty() {
+                state.phase = RolloutPhase::Rolling;
+            } else {
+                // Do not touch the rest of the fleet if the canaries broke
+                state.phase = RolloutPhase::Paused;
+                state.resume_phase = Some(RolloutPhase::Rolling);
+                state.pause_reason = Some(format!("{} canary upgrades failed", state.failed.len()));
+                stop_batches();
+            }
+        }
+        RolloutPhase::Rolling if state.remaining.is_empty() => {
+            state.phase = RolloutPhase::Completed;
+            stop_batches();
+        }
+        _ => {}
+    }
+}
+
+pub fn pause_rollout(reason: String) -> Result<(), DeviteError> {
+    let mut state = rollout_state();
+    if !matches!(state.phase, RolloutPhase::Canary | RolloutPhase::Rolling) {
+        return Err(DeviteError::InvalidRolloutPhase { phase: state.phase });
+    }
+
+    stop_batches();
+    state.resume_phase = Some(state.phase.clone());
+    state.phase = RolloutPhase::Paused;
+    state.pause_reason = Some(reason);
+    save_rollout(state);
+    Ok(())
+}
+
+pub fn resume_rollout() -> Result<(), DeviteError> {
+    let mut state = rollout_state();
+    if state.phase != RolloutPhase::Paused {
+        return Err(DeviteError::InvalidRolloutPhase { phase: state.phase });
+    }
+
+    state.phase = state.resume_phase.take().unwrap_or(RolloutPhase::Rolling);
+    state.pause_reason = None;
+    advance_phase(&mut state);
+    let interval = state.config.as_ref().map(|config| config.batch_interval_secs);
+    let running = matches!(state.phase, RolloutPhase::Canary | RolloutPhase::Rolling);
+    save_rollout(state);
+
+    if let (true, Some(interval)) = (running, interval) {
+        schedule_batches(interval);
+    }
+    Ok(())
+}
+
+#[update]
+fn start_personal_storage_rollout(config: RolloutConfig) -> Result<RolloutProgress, DeviteError> {
+    require_role(Role::Admin)?;
+    config.validate()?;
+
+    let current = rollout_state();
+    if current.is_active() {
+        return Err(DeviteError::InvalidRolloutPhase { phase: current.phase });
+    }
+
+    let sha256 = personal_storage::storage_wasm_sha256()?;
+    let mut targets: Vec<RolloutTarget> = PERSONAL_CANISTERS.with(|canisters| {
+        canisters.borrow().iter()
+            .filter(|(_, record)| record.wasm_sha256 != sha256)
+            .map(|(_, record)| RolloutTarget {
+                canister_id: record.canister_id,
+                attempts: 0,
+                last_error: None,
+            })
+            .collect()
+    });
+
+    let canary_count = (targets.len() * config.canary_percent as usize).div_ceil(100);
+    let remaining = targets.split_off(canary_count.min(targets.len()));
+    let now = ic_cdk::api::time();
+    let mut state = RolloutState {
+        phase: RolloutPhase::Canary,
+        resume_phase: None,
+        pause_reason: None,
+        config: Some(config.clone()),
+        target_sha256: sha256,
+        canary: targets,
+        remaining,
+        upgraded: Vec::new(),
+        failed: Vec::new(),
+        started_at: now,
+        updated_at: now,
+    };
+    advance_phase(&mut state);
+    if state.phase == RolloutPhase::Rolling {
+        advance_phase(&mut state);
+    }
+    let running = matches!(state.phase, RolloutPhase::Canary | RolloutPhase::Rolling);
+    save_rollout(state);
+
+    if running {
+        schedule_batches(config.batch_interval_secs);
+    }
+    Ok(get_personal_storage_rollout())
+}
+
+#[update]
+fn pause_personal_storage_rollout() -> Result<(), DeviteError> {
+    require_role(Role::Admin)?;
+    pause_rollout("Paused by an administrator".to_string())
+}
+
+#[update]
+fn resume_personal_storage_rollout() -> Result<(), DeviteError> {
+    require_role(Role::Admin)?;
+    resume_rollout()
+}
+
+#[update]
+fn cancel_personal_storage_rollout() -> Result<(), DeviteError> {
+    require_role(Role::Admin)?;
+
+    let mut state = rollout_state();
+    if !state.is_active() {
+        return Err(DeviteError::InvalidRolloutPhase { phase: state.phase });
+    }
+
+    stop_batches();
+    state.phase = RolloutPhase::Cancelled;
+    state.resume_phase = None;
+    save_rollout(state);
+    Ok(())
+}
+
+#[query]
+fn get_personal_storage_rollout() -> RolloutProgress {
+    let state = rollout_state();
+    let pending = (state.canary.len() + state.remaining.len()) as u64;
+    let upgraded = state.upgraded.len() as u64;
+
+    RolloutProgress {
+        total: pending + upgraded + state.failed.len() as u64,
+        upgraded,
+        pending,
+        phase: state.phase,
+        pause_reason: state.pause_reason,
+        config: state.config,
+        target_sha256: state.target_sha256,
+        failed: state.failed,
+        started_at: state.started_at,
+        updated_at: state.updated_at,
+    }
+}
+
+#[query]
+fn get_personal_canister(canister_id: Principal) -> Option<PersonalCanisterRecord> {
+    personal_canister(canister_id)
+}
@@ -0,0 +1,336 @@
+use candid::{CandidType, Deserialize, Nat, Principal, Encode, Decode};
+use ic_cdk::api::management_canister::main::*;
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable, BoundedStorable};
+use std::cell::RefCell;
+use std::borrow::Cow;
+use std::time::Duration;
+
+use crate::access_control::{require_role, Role};
+use crate::error::{truncate_error, DeviteError};
+use crate::fleet::{self, PersonalCanisterRecord};
+use crate::quota::{self, CyclesSpendKind};
+use crate::validation::Validate;
+use crate::{Memory, StorablePrincipal, DAYS_TO_NANOSECONDS, MEMORY_MANAGER};
+
+type CanisterHealthStorage = StableBTreeMap<StorablePrincipal, CanisterHealth, Memory>;
+type TopUpPolicyCell = StableCell<TopUpPolicy, Memory>;
+type TopUpUsageStorage = StableBTreeMap<StorablePrincipal, TopUpUsage, Memory>;
+
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+// Cycles the backend keeps for itself no matter how much budget is left
+const BACKEND_CYCLES_RESERVE: u128 = 2_000_000_000_000;
+
+// HEALTH TYPES
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CanisterHealth {
+    pub canister_id: Principal,
+    pub owner: Principal,
+    pub status: Option<CanisterStatusType>,
+    pub cycles: u128,
+    pub memory_size: u64,
+    pub idle_cycles_burned_per_day: u128,
+    pub module_hash: Option<Vec<u8>>,
+    pub last_top_up_at: Option<u64>,
+    // Set when the last status call failed; the other fields are from the
+    // last successful check
+    pub last_error: Option<String>,
+    pub checked_at: u64,
+}
+
+impl Storable for CanisterHealth {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CanisterHealth {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TopUpPolicy {
+    // Canisters below this balance are at risk and get topped up
+    pub threshold_cycles: u128,
+    pub top_up_cycles: u128,
+    // Cycles one user's canister may receive per window
+    pub per_user_limit_cycles: u128,
+    pub limit_window_days: u64,
+    // Cycles left for top-ups across the whole fleet
+    pub budget_cycles: u128,
+}
+
+impl Default for TopUpPolicy {
+    fn default() -> Self {
+        TopUpPolicy {
+            threshold_cycles: 500_000_000_000,
+            top_up_cycles: 1_000_000_000_000,
+            per_user_limit_cycles: 3_000_000_000_000,
+            limit_window_days: 30,
+            budget_cycles: 0,
+        }
+    }
+}
+
+impl Storable for TopUpPolicy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct TopUpUsage {
+    pub window_started_at: u64,
+    pub window_cycles: u128,
+    pub total_cycles: u128,
+}
+
+impl Storable for TopUpUsage {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TopUpUsage {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct HealthCheckReport {
+    pub checked: u64,
+    pub topped_up: Vec<Principal>,
+    pub errors: Vec<String>,
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Canister health, top-up policy and per-user top-up usage (Memory ID 20, 21, 22)
+    static CANISTER_HEALTH: RefCell<CanisterHealthStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+        )
+    );
+
+    static TOP_UP_POLICY: RefCell<TopUpPolicyCell> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))),
+            TopUpPolicy::default(),
+        ).expect("Failed to initialize top-up policy")
+    );
+
+    static TOP_UP_USAGE: RefCell<TopUpUsageStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))),
+        )
+    );
+
+    static CHECK_IN_PROGRESS: RefCell<bool> = const { RefCell::new(false) };
+}
+
+// HEALTH FUNCTIONS
+
+pub fn start_health_check_timer() {
+    ic_cdk_timers::set_timer_interval(HEALTH_CHECK_INTERVAL, || {
+        ic_cdk::spawn(async {
+            let report = check_fleet().await;
+            if !report.errors.is_empty() {
+                ic_cdk::println!("Personal canister health check errors: {:?}", report.errors);
+            }
+        });
+    });
+}
+
+fn nat_to_u128(value: Nat) -> u128 {
+    value.0.try_into().unwrap_or(u128::MAX)
+}
+
+fn top_up_policy() -> TopUpPolicy {
+    TOP_UP_POLICY.with(|cell| cell.borrow().get().clone())
+}
+
+async fn check_fleet() -> HealthCheckReport {
+    let mut report = HealthCheckReport::default();
+    if CHECK_IN_PROGRESS.with(|flag| flag.replace(true)) {
+        report.errors.push("A health check is already running".to_string());
+        return report;
+    }
+
+    for record in fleet::personal_canisters() {
+        report.checked += 1;
+        match check_canister(&record).await {
+            Ok(true) => report.topped_up.push(record.canister_id),
+            Ok(false) => {}
+            Err(error) => report.errors.push(error),
+        }
+    }
+
+    CHECK_IN_PROGRESS.with(|flag| *flag.borrow_mut() = false);
+    report
+}
+
+// Records the status of one canister and tops it up if it is running low.
+// Returns whether cycles were deposited.
+async fn check_canister(record: &PersonalCanisterRecord) -> Result<bool, String> {
+    let key = StorablePrincipal::from(record.canister_id);
+    let now = ic_cdk::api::time();
+    let mut health = CANISTER_HEALTH.with(|health| health.borrow().get(&key)).unwrap_or(CanisterHealth {
+        canister_id: record.canister_id,
+        owner: record.owner,
+        status: None,
+        cycles: 0,
+        memory_size: 0,
+        idle_cycles_burned_per_day: 0,
+        module_hash: None,
+        last_top_up_at: None,
+        last_error: None,
+        checked_at: now,
+    });
+    health.owner = record.owner;
+    health.checked_at = now;
+
+    let status = canister_status(CanisterIdRecord { canister_id: record.canister_id }).await;
+    let status = match status {
+        Ok((status,)) => status,
+        Err((code, msg)) => {
+            let error = truncate_error(format!("Failed to query status of {}: {:?} - {}", record.canister_id, code, msg));
+            health.last_error = Some(error.clone());
+            CANISTER_HEALTH.with(|canisters| canisters.borrow_mut().insert(key, health));
+            return Err(error);
+        }
+    };
+
+    health.status = Some(status.status);
+    health.cycles = nat_to_u128(status.cycles);
+    health.memory_size = nat_to_u128(status.memory_size) as u64;
+    health.idle_cycles_burned_per_day = nat_to_u128(status.idle_cycles_burned_per_day);
+    health.module_hash = status.module_hash;
+    health.last_error = None;
+
+    let mut result = Ok(false);
+    if let Some(amount) = reserve_top_up(record.owner, health.cycles) {
+        match deposit_cycles(CanisterIdRecord { canister_id: record.canister_id }, amount).await {
+            Ok(()) => {
+                health.cycles = health.cycles.saturating_add(amount);
+                health.last_top_up_at = Some(ic_cdk::api::time());
+                quota::record_cycles_spent(record.owner, CyclesSpendKind::TopUp, amount);
+                result = Ok(true);
+            }
+            Err((code, msg)) => {
+                release_top_up(record.owner, amount);
+                let error = truncate_error(format!("Failed to top up {}: {:?} - {}", record.canister_id, code, msg));
+                health.last_error = Some(error.clone());
+                result = Err(error);
+            }
+        }
+    }
+
+    CANISTER_HEALTH.with(|canisters| canisters.borrow_mut().insert(key, health));
+    result
+}
+
+// Charges a top-up to the budget and the owner's window before the deposit
+// is awaited, so concurrent checks cannot overspend either of them
+fn reserve_top_up(owner: Principal, cycles: u128) -> Option<u128> {
+    let mut policy = top_up_policy();
+    if cycles >= policy.threshold_cycles || policy.budget_cycles < policy.top_up_cycles {
+        return None;
+    }
+    if ic_cdk::api::canister_balance128() < policy.top_up_cycles.saturating_add(BACKEND_CYCLES_RESERVE) {
+        return None;
+    }
+
+    let key = StorablePrincipal::from(owner);
+    let now = ic_cdk::api::time();
+    let mut usage = TOP_UP_USAGE.with(|usage| usage.borrow().get(&key)).unwrap_or_default();
+    if now.saturating_sub(usage.window_started_at) > policy.limit_window_days * DAYS_TO_NANOSECONDS {
+        usage.window_started_at = now;
+        usage.window_cycles = 0;
+    }
+    if usage.window_cycles.saturating_add(policy.top_up_cycles) > policy.per_user_limit_cycles {
+        return None;
+    }
+
+    let amount = policy.top_up_cycles;
+    usage.window_cycles += amount;
+    usage.total_cycles += amount;
+    policy.budget_cycles -= amount;
+    TOP_UP_USAGE.with(|usage_map| usage_map.borrow_mut().insert(key, usage));
+    TOP_UP_POLICY.with(|cell| cell.borrow_mut().set(policy).expect("Failed to store top-up policy"));
+    Some(amount)
+}
+
+fn release_top_up(owner: Principal, amount: u128) {
+    let key = StorablePrincipal::from(owner);
+    TOP_UP_USAGE.with(|usage_map| {
+        let mut usage_map = usage_map.borrow_mut();
+        if let Some(mut usage) = usage_map.get(&key) {
+            usage.window_cycles = usage.window_cycles.saturating_sub(amount);
+            usage.total_cycles = usage.total_cycles.saturating_sub(amount);
+            usage_map.insert(key, usage);
+        }
+    });
+
+    let mut policy = top_up_policy();
+    policy.budget_cycles = policy.budget_cycles.saturating_add(amount);
+    TOP_UP_POLICY.with(|cell| cell.borrow_mut().set(policy).expect("Failed to store top-up policy"));
+}
+
+fn is_at_risk(health: &CanisterHealth, threshold_cycles: u128) -> bool {
+    health.cycles < threshold_cycles
+        || health.last_error.is_some()
+        || health.module_hash.is_none()
+        || !matches!(health.status, Some(CanisterStatusType::Running))
+}
+
+#[update]
+async fn check_personal_canisters() -> Result<HealthCheckReport, DeviteError> {
+    require_role(Role::Admin)?;
+    Ok(check_fleet().await)
+}
+
+#[update]
+fn set_top_up_policy(policy: TopUpPolicy) -> Result<(), DeviteError> {
+    require_role(Role::Admin)?;
+    policy.validate()?;
+
+    TOP_UP_POLICY.with(|cell| cell.borrow_mut().set(policy).expect("Failed to store top-up policy"));
+    Ok(())
+}
+
+#[query]
+fn get_top_up_policy() -> TopUpPolicy {
+    top_up_policy()
+}
+
+#[query]
+fn get_canister_health(canister_id: Principal) -> Option<CanisterHealth> {
+    CANISTER_HEALTH.with(|health| health.borrow().get(&StorablePrincipal::from(canister_id)))
+}
+
+#[query]
+fn list_at_risk_canisters() -> Vec<CanisterHealth> {
+    let threshold_cycles = top_up_policy().threshold_cycles;
+    CANISTER_HEALTH.with(|health| {
+        health.borrow().iter()
+            .map(|(_, health)| health)
+            .filter(|health| is_at_risk(health, threshold_cycles))
+            .collect()
+    })
+}
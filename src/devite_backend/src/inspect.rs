@@ -0,0 +1,119 @@
+use candid::{decode_args, Principal};
+use ic_cdk::api::call::{accept_message, arg_data_raw, arg_data_raw_size, method_name};
+use ic_cdk::inspect_message;
+
+use crate::claims::OpenDisputeRequest;
+use crate::encryption::RegisterEncryptionKeyRequest;
+use crate::fleet::RolloutConfig;
+use crate::health::TopUpPolicy;
+use crate::quota::QuotaPolicy;
+use crate::reputation::ReputationConfig;
+use crate::{
+    CreateProposalRequest, CreateUserRequest, MintRequest, StorablePrincipal, SubmitReviewRequest,
+    USER_PROFILES,
+};
+
+struct IngressRule {
+    method: &'static str,
+    max_payload_bytes: usize,
+    requires_registration: bool,
+}
+
+const fn rule(method: &'static str, max_payload_bytes: usize, requires_registration: bool) -> IngressRule {
+    IngressRule {
+        method,
+        max_payload_bytes,
+        requires_registration,
+    }
+}
+
+// Update methods accepted over ingress. Anything else, including queries sent
+// as update calls, is dropped before execution.
+const INGRESS_RULES: &[IngressRule] = &[
+    rule("register_user", 4 * 1024, false),
+    rule("set_institution_verified", 256, false),
+    rule("mint_research_nft", 8 * 1024, true),
+    rule("endorse_research_token", 256, true),
+    rule("retract_research_token", 256, false),
+    rule("submit_review", 4 * 1024, false),
+    rule("cite_research_token", 256, true),
+    rule("transfer_research_token", 256, true),
+    rule("create_proposal", 8 * 1024, true),
+    rule("vote_on_proposal", 256, true),
+    rule("finalize_proposal", 256, false),
+    rule("execute_proposal", 256, false),
+    rule("grant_role", 256, false),
+    rule("revoke_role", 256, false),
+    rule("set_reputation_config", 1024, false),
+    rule("reconcile_pending_registrations", 64, false),
+    rule("set_personal_storage_wasm", 2 * 1024 * 1024, false),
+    rule("start_personal_storage_rollout", 256, false),
+    rule("pause_personal_storage_rollout", 64, false),
+    rule("resume_personal_storage_rollout", 64, false),
+    rule("cancel_personal_storage_rollout", 64, false),
+    rule("set_canister_pool_target", 64, false),
+    rule("check_personal_canisters", 64, false),
+    rule("set_top_up_policy", 256, false),
+    rule("deposit_storage_cycles", 64, true),
+    rule("purchase_storage_with_tokens", 64, true),
+    rule("sync_storage_quota", 64, true),
+    rule("set_quota_policy", 256, false),
+    rule("register_encryption_key", 1024, true),
+    rule("remove_encryption_key", 64, true),
+    rule("set_duplicate_policy", 64, false),
+    rule("dispute_duplicate", 2 * 1024, true),
+    rule("resolve_dispute", 64, false),
+    rule("set_attestation_key", 256, false),
+    rule("sign_pending_attestations", 64, false),
+];
+
+// Pre-filters ingress so that obviously doomed messages never pay for
+// execution. This runs on a single replica and is not a security boundary:
+// every method still performs its own checks.
+#[inspect_message]
+fn inspect_message() {
+    let method = method_name();
+    let rule = match INGRESS_RULES.iter().find(|rule| rule.method == method) {
+        Some(rule) => rule,
+        None => return,
+    };
+
+    let caller = ic_cdk::api::caller();
+    if caller == Principal::anonymous() {
+        return;
+    }
+
+    if arg_data_raw_size() > rule.max_payload_bytes {
+        return;
+    }
+
+    if rule.requires_registration
+        && !USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&StorablePrincipal::from(caller)))
+    {
+        return;
+    }
+
+    if !arguments_well_formed(&method, &arg_data_raw()) {
+        return;
+    }
+
+    accept_message();
+}
+
+// Only checks that request payloads decode. Field-level validation is left to
+// the method so callers still receive the full list of violations.
+fn arguments_well_formed(method: &str, args: &[u8]) -> bool {
+    match method {
+        "register_user" => decode_args::<(CreateUserRequest,)>(args).is_ok(),
+        "mint_research_nft" => decode_args::<(MintRequest,)>(args).is_ok(),
+        "submit_review" => decode_args::<(u64, SubmitReviewRequest)>(args).is_ok(),
+        "create_proposal" => decode_args::<(CreateProposalRequest,)>(args).is_ok(),
+        "set_reputation_config" => decode_args::<(ReputationConfig,)>(args).is_ok(),
+        "set_quota_policy" => decode_args::<(QuotaPolicy,)>(args).is_ok(),
+        "set_top_up_policy" => decode_args::<(TopUpPolicy,)>(args).is_ok(),
+        "start_personal_storage_rollout" => decode_args::<(RolloutConfig,)>(args).is_ok(),
+        "register_encryption_key" => decode_args::<(RegisterEncryptionKeyRequest,)>(args).is_ok(),
+        "dispute_duplicate" => decode_args::<(OpenDisputeRequest,)>(args).is_ok(),
+        _ => true,
+    }
+}
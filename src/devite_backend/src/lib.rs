@@ -1,11 +1,36 @@
 use candid::{CandidType, Deserialize, Principal, Encode, Decode};
-use ic_cdk::api::management_canister::main::*;
-use ic_cdk::{query, update};
+use ic_cdk::{init, post_upgrade, query, update};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable, BoundedStorable};
 use std::cell::RefCell;
 use std::borrow::Cow;
 
+mod access_control;
+mod attestation;
+mod certification;
+mod claims;
+mod content_hash;
+mod encryption;
+mod error;
+mod fleet;
+mod health;
+mod inspect;
+mod personal_storage;
+mod pool;
+mod quota;
+mod registration;
+mod reputation;
+mod similarity;
+mod validation;
+
+use access_control::{Role, has_role, require_authenticated, require_role};
+use content_hash::ContentHash;
+use error::{DeviteError, ResourceKind};
+use personal_storage::StorageLocation;
+use reputation::{ContributionKind, ReputationConfig, record_contribution};
+use similarity::SimilarResearch;
+use validation::Validate;
+
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
 // Wrapper types to work around orphan rules
@@ -18,6 +43,13 @@ impl From<Principal> for StorablePrincipal {
     }
 }
 
+// Required to use StorablePrincipal inside composite (tuple) keys
+impl Default for StorablePrincipal {
+    fn default() -> Self {
+        StorablePrincipal(Principal::anonymous())
+    }
+}
+
 impl From<StorablePrincipal> for Principal {
     fn from(sp: StorablePrincipal) -> Self {
         sp.0
@@ -71,11 +103,42 @@ impl BoundedStorable for StorableVecU64 {
     const IS_FIXED_SIZE: bool = false;
 }
 
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StorableString(pub String);
+
+impl From<String> for StorableString {
+    fn from(s: String) -> Self {
+        StorableString(s)
+    }
+}
+
+impl Storable for StorableString {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&self.0).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let string: String = Decode!(bytes.as_ref(), String).unwrap();
+        StorableString(string)
+    }
+}
+
+impl BoundedStorable for StorableString {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
 type UserStore = StableBTreeMap<StorablePrincipal, UserProfile, Memory>;
 type TokenStorage = StableBTreeMap<u64, ResearchNFT, Memory>;
 type OwnerStorage = StableBTreeMap<StorablePrincipal, StorableVecU64, Memory>;
 type ProposalStorage = StableBTreeMap<u64, Proposal, Memory>;
 type GovernanceTokenStorage = StableBTreeMap<StorablePrincipal, u64, Memory>;
+type MintHistoryStorage = StableBTreeMap<StorablePrincipal, StorableVecU64, Memory>;
+type ContentHashIndex = StableBTreeMap<StorableString, u64, Memory>;
+type MintRewardStorage = StableBTreeMap<u64, MintReward, Memory>;
+type EndorsementStorage = StableBTreeMap<(u64, StorablePrincipal), u64, Memory>;
+type ReviewStorage = StableBTreeMap<(u64, StorablePrincipal), Review, Memory>;
+type CitationStorage = StableBTreeMap<(u64, u64), u64, Memory>;
 
 // USER MANAGEMENT TYPES
 
@@ -88,6 +151,7 @@ pub struct UserProfile {
     pub research_domains: Vec<String>,
     pub personal_canister_id: Option<Principal>,
     pub created_at: u64,
+    pub institution_verified_at: Option<u64>,
 }
 
 impl Storable for UserProfile {
@@ -105,6 +169,11 @@ impl BoundedStorable for UserProfile {
     const IS_FIXED_SIZE: bool = false;
 }
 
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct InitArgs {
+    pub bootstrap_admins: Vec<Principal>,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct CreateUserRequest {
     pub username: String,
@@ -123,10 +192,17 @@ pub struct ResearchNFT {
     pub description: String,
     pub authors: Vec<String>,
     pub research_type: ResearchType,
+    // Text as submitted
     pub content_hash: String,
+    // Binary multihash the text names; None for tokens minted before hashes
+    // were parsed whose text is not a recognized hash
+    pub content_multihash: Option<Vec<u8>>,
     pub license: License,
     pub created_at: u64,
     pub metadata: ResearchMetadata,
+    pub retracted_at: Option<u64>,
+    // Set when the content hash was verified against personal storage
+    pub storage_location: Option<StorageLocation>,
 }
 
 impl Storable for ResearchNFT {
@@ -178,6 +254,96 @@ pub struct MintRequest {
     pub content_hash: String,
     pub license: License,
     pub metadata: ResearchMetadata,
+    // Item in the caller's personal canister whose SHA-256 must equal
+    // `content_hash`; the token then records where the content is stored
+    pub storage_item: Option<String>,
+}
+
+// Things about a successful mint the minter should look at
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum MintWarning {
+    // Minted under the Flag policy as a copy of an earlier token
+    DuplicateContent { prior_token_id: u64 },
+    SimilarResearch(SimilarResearch),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct MintReceipt {
+    pub token_id: u64,
+    pub warnings: Vec<MintWarning>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct MintReward {
+    pub token_id: u64,
+    pub recipient: Principal,
+    pub amount: u64,
+    pub endorsement_count: u64,
+    pub status: RewardStatus,
+    pub settled_at: Option<u64>,
+}
+
+impl Storable for MintReward {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for MintReward {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum RewardStatus {
+    // Waiting for community validation before tokens are paid out
+    Pending,
+    Paid,
+    // Token was retracted after the reward was paid; tokens were taken back
+    ClawedBack,
+    // Token was retracted before the reward was ever paid
+    Forfeited,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Review {
+    pub token_id: u64,
+    pub reviewer: Principal,
+    pub summary: String,
+    pub recommendation: ReviewRecommendation,
+    pub created_at: u64,
+}
+
+impl Storable for Review {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Review {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum ReviewRecommendation {
+    Accept,
+    Revise,
+    Reject,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SubmitReviewRequest {
+    pub summary: String,
+    pub recommendation: ReviewRecommendation,
 }
 
 // GOVERNANCE TYPES
@@ -195,6 +361,8 @@ pub struct Proposal {
     pub voters: Vec<Principal>,
     pub created_at: u64,
     pub voting_ends_at: u64,
+    pub tally_mode: Option<TallyMode>,
+    pub action: Option<ProposalAction>,
 }
 
 impl Storable for Proposal {
@@ -228,37 +396,38 @@ pub enum ProposalStatus {
     Executed,
 }
 
+// How votes are weighted; proposals without a mode use token balances
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum TallyMode {
+    TokenWeighted,
+    ReputationWeighted,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct CreateProposalRequest {
     pub title: String,
     pub description: String,
     pub proposal_type: ProposalType,
     pub voting_duration_days: u64,
+    pub tally_mode: Option<TallyMode>,
+    pub action: Option<ProposalAction>,
 }
 
+// Change applied by `execute_proposal` once a proposal has passed
 #[derive(Clone, Debug, CandidType, Deserialize)]
-pub enum Vote {
-    For,
-    Against,
-}
-
-// PERSONAL STORAGE TYPES
-
-#[derive(Clone, Debug, CandidType, Deserialize)]
-pub struct StoredItem {
-    pub id: String,
-    pub content: Vec<u8>,
-    pub metadata: ItemMetadata,
-    pub created_at: u64,
-    pub updated_at: u64,
+pub enum ProposalAction {
+    GrantRole { principal: Principal, role: Role },
+    RevokeRole { principal: Principal, role: Role },
+    UpdateReputationConfig(ReputationConfig),
+    PausePersonalStorageRollout,
+    ResumePersonalStorageRollout,
+    SetDefaultStorageTier(personal_storage::StorageQuota),
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
-pub struct ItemMetadata {
-    pub content_type: String,
-    pub filename: String,
-    pub description: String,
-    pub tags: Vec<String>,
+pub enum Vote {
+    For,
+    Against,
 }
 
 // GLOBAL STATE
@@ -300,9 +469,48 @@ thread_local! {
         )
     );
     
-    // Counters
-    static NEXT_TOKEN_ID: RefCell<u64> = RefCell::new(1);
-    static NEXT_PROPOSAL_ID: RefCell<u64> = RefCell::new(1);
+    // Anti-farming (Memory ID 5, 6, 7, 8)
+    static MINT_HISTORY: RefCell<MintHistoryStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
+        )
+    );
+    
+    static CONTENT_HASHES: RefCell<ContentHashIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
+        )
+    );
+    
+    static MINT_REWARDS: RefCell<MintRewardStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+        )
+    );
+    
+    static ENDORSEMENTS: RefCell<EndorsementStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+        )
+    );
+    
+    // Reviews and citations (Memory ID 11, 12)
+    static REVIEWS: RefCell<ReviewStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+        )
+    );
+    
+    // Keyed by (cited token, citing token)
+    static CITATIONS: RefCell<CitationStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+        )
+    );
+    
+    // Counters, restored from the stored ids after every upgrade
+    static NEXT_TOKEN_ID: RefCell<u64> = const { RefCell::new(1) };
+    static NEXT_PROPOSAL_ID: RefCell<u64> = const { RefCell::new(1) };
 }
 
 // CONSTANTS
@@ -311,64 +519,60 @@ const DAYS_TO_NANOSECONDS: u64 = 24 * 60 * 60 * 1_000_000_000;
 const MIN_PROPOSAL_THRESHOLD: u64 = 100;
 const QUORUM_PERCENTAGE: u64 = 20;
 const INITIAL_GOVERNANCE_TOKENS: u64 = 1000;
-
-// USER MANAGEMENT FUNCTIONS
-
-#[update]
-async fn register_user(request: CreateUserRequest) -> Result<UserProfile, String> {
-    let caller = ic_cdk::api::caller();
-    let storable_caller = StorablePrincipal::from(caller);
+const MINT_REWARD_TOKENS: u64 = 50;
+const MINT_RATE_WINDOW: u64 = DAYS_TO_NANOSECONDS;
+const MAX_MINTS_PER_WINDOW: usize = 5;
+const ENDORSEMENT_THRESHOLD: u64 = 3;
+
+// LIFECYCLE
+
+#[init]
+fn init(args: Option<InitArgs>) {
+    // Without explicit bootstrap admins the deployer administers the platform
+    let bootstrap_admins = args
+        .map(|args| args.bootstrap_admins)
+        .filter(|admins| !admins.is_empty())
+        .unwrap_or_else(|| vec![ic_cdk::api::caller()]);
     
-    // Check if user already exists
-    if USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&storable_caller)) {
-        return Err("User already registered".to_string());
+    for admin in bootstrap_admins.into_iter().filter(|admin| *admin != Principal::anonymous()) {
+        access_control::assign_role(admin, Role::Admin);
     }
     
-    // Create personal canister for user
-    let personal_canister_id = create_personal_canister(caller).await?;
-    
-    let user_profile = UserProfile {
-        principal: caller,
-        username: request.username,
-        email: request.email,
-        institution: request.institution,
-        research_domains: request.research_domains,
-        personal_canister_id: Some(personal_canister_id),
-        created_at: ic_cdk::api::time(),
-    };
-    
-    USER_PROFILES.with(|profiles| {
-        profiles.borrow_mut().insert(storable_caller.clone(), user_profile.clone())
-    });
-    
-    // Award initial governance tokens
-    GOVERNANCE_TOKENS.with(|tokens| {
-        tokens.borrow_mut().insert(storable_caller, INITIAL_GOVERNANCE_TOKENS);
-    });
-    
-    Ok(user_profile)
+    personal_storage::install_embedded_wasm();
+    certification::restore_certified_data();
+    start_timers();
 }
 
-async fn create_personal_canister(owner: Principal) -> Result<Principal, String> {
-    let create_args = CreateCanisterArgument {
-        settings: Some(CanisterSettings {
-            controllers: Some(vec![owner, ic_cdk::api::id()]),
-            compute_allocation: None,
-            memory_allocation: None,
-            freezing_threshold: None,
-        }),
-    };
-    
-    match create_canister(create_args).await {
-        Ok((canister_id,)) => {
-            // Note: In a real implementation, you would install the personal storage WASM here
-            // install_personal_storage_code(canister_id.canister_id, owner).await?;
-            Ok(canister_id.canister_id)
-        }
-        Err((code, msg)) => Err(format!("Failed to create canister: {:?} - {}", code, msg)),
-    }
+// Ids are never reused, so the next one follows the highest stored id
+fn restore_counters() {
+    let next_token_id = RESEARCH_TOKENS.with(|tokens| tokens.borrow().last_key_value()).map_or(1, |(id, _)| id + 1);
+    let next_proposal_id = PROPOSALS.with(|proposals| proposals.borrow().last_key_value()).map_or(1, |(id, _)| id + 1);
+    NEXT_TOKEN_ID.with(|id| *id.borrow_mut() = next_token_id);
+    NEXT_PROPOSAL_ID.with(|id| *id.borrow_mut() = next_proposal_id);
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    restore_counters();
+    personal_storage::install_embedded_wasm();
+    content_hash::normalize_stored_hashes();
+    similarity::ensure_indexed();
+    certification::restore_certified_data();
+    attestation::ensure_recorded();
+    // Timers do not survive upgrades
+    start_timers();
 }
 
+fn start_timers() {
+    registration::start_reconciliation_timer();
+    pool::start_refill_timer();
+    health::start_health_check_timer();
+    fleet::resume_rollout_timer();
+    attestation::start_retry_timer();
+}
+
+// USER MANAGEMENT FUNCTIONS
+
 #[query]
 fn get_user_profile(user_id: Principal) -> Option<UserProfile> {
     let storable_user = StorablePrincipal::from(user_id);
@@ -388,18 +592,84 @@ fn list_all_users() -> Vec<UserProfile> {
     })
 }
 
+#[update]
+fn set_institution_verified(user_id: Principal, verified: bool) -> Result<(), DeviteError> {
+    require_role(Role::InstitutionVerifier)?;
+    
+    let storable_user = StorablePrincipal::from(user_id);
+    let mut profile = USER_PROFILES.with(|profiles| profiles.borrow().get(&storable_user))
+        .ok_or_else(|| DeviteError::not_found(ResourceKind::User, user_id))?;
+    
+    profile.institution_verified_at = if verified { Some(ic_cdk::api::time()) } else { None };
+    USER_PROFILES.with(|profiles| {
+        profiles.borrow_mut().insert(storable_user, profile);
+    });
+    
+    Ok(())
+}
+
 // RESEARCH NFT FUNCTIONS
 
+// Mint times of `caller` still inside the rate window, or an error once the
+// window is full
+fn recent_mints(caller: &StorablePrincipal, now: u64) -> Result<Vec<u64>, DeviteError> {
+    let mut recent_mints = MINT_HISTORY.with(|history| {
+        history.borrow()
+            .get(caller)
+            .map(|v| v.0)
+            .unwrap_or_default()
+    });
+    recent_mints.retain(|&minted_at| now.saturating_sub(minted_at) < MINT_RATE_WINDOW);
+    
+    if recent_mints.len() >= MAX_MINTS_PER_WINDOW {
+        return Err(DeviteError::RateLimited {
+            limit: MAX_MINTS_PER_WINDOW as u64,
+            window_seconds: MINT_RATE_WINDOW / 1_000_000_000,
+        });
+    }
+    Ok(recent_mints)
+}
+
 #[update]
-fn mint_research_nft(request: MintRequest) -> Result<u64, String> {
-    let caller = ic_cdk::api::caller();
+async fn mint_research_nft(request: MintRequest) -> Result<MintReceipt, DeviteError> {
+    let caller = require_authenticated()?;
     let storable_caller = StorablePrincipal::from(caller);
     
     // Check if user is registered
     if !USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&storable_caller)) {
-        return Err("User must be registered first".to_string());
+        return Err(DeviteError::NotRegistered);
+    }
+    
+    request.validate()?;
+    let content_hash = ContentHash::parse(&request.content_hash).map_err(|reason| DeviteError::ValidationFailed {
+        field: "content_hash".to_string(),
+        reason,
+    })?;
+    
+    // Turn away callers over the limit before paying for inter-canister calls
+    recent_mints(&storable_caller, ic_cdk::api::time())?;
+    
+    // Only storage-backed mints wait on another canister; the checks below
+    // run after the call so they see the state the token is minted into
+    let storage_location = match &request.storage_item {
+        Some(item_id) => Some(personal_storage::verify_stored_content(caller, item_id, &content_hash.digest).await?),
+        None => None,
+    };
+    let current_time = ic_cdk::api::time();
+    
+    // The first live token minted for a content hash, in any spelling of it,
+    // holds the claim; the duplicate policy decides what happens to later copies
+    let storable_hash = content_hash::index_key(&request.content_hash);
+    let prior_token_id = CONTENT_HASHES.with(|hashes| hashes.borrow().get(&storable_hash))
+        .filter(|&prior_token_id| claims::holds_claim(prior_token_id));
+    if let Some(prior_token_id) = prior_token_id {
+        claims::check_duplicate(caller, prior_token_id)?;
     }
     
+    // Enforce the per-principal mint rate limit again, since other mints by
+    // the same caller may have completed during the await
+    let mut recent_mints = recent_mints(&storable_caller, current_time)?;
+    
     let token_id = NEXT_TOKEN_ID.with(|id| {
         let current_id = *id.borrow();
         *id.borrow_mut() = current_id + 1;
@@ -414,16 +684,37 @@ fn mint_research_nft(request: MintRequest) -> Result<u64, String> {
         authors: request.authors,
         research_type: request.research_type,
         content_hash: request.content_hash,
+        content_multihash: Some(content_hash.multihash()),
         license: request.license,
-        created_at: ic_cdk::api::time(),
+        created_at: current_time,
         metadata: request.metadata,
+        retracted_at: None,
+        storage_location,
     };
     
     // Store the NFT
+    let similar = similarity::index_token(&nft);
+    certification::certify_mint(&nft);
+    attestation::attest_mint(&nft);
     RESEARCH_TOKENS.with(|tokens| {
         tokens.borrow_mut().insert(token_id, nft);
     });
     
+    match prior_token_id {
+        Some(prior_token_id) => claims::flag_duplicate(token_id, caller, prior_token_id, current_time),
+        // Takes over the index entry of a retracted claim, if there is one
+        None => {
+            CONTENT_HASHES.with(|hashes| {
+                hashes.borrow_mut().insert(storable_hash, token_id);
+            });
+        }
+    }
+    
+    recent_mints.push(current_time);
+    MINT_HISTORY.with(|history| {
+        history.borrow_mut().insert(storable_caller.clone(), StorableVecU64::from(recent_mints));
+    });
+    
     // Update owner's token list
     TOKEN_OWNERS.with(|owners| {
         let mut owner_tokens = owners.borrow()
@@ -434,13 +725,299 @@ fn mint_research_nft(request: MintRequest) -> Result<u64, String> {
         owners.borrow_mut().insert(storable_caller.clone(), StorableVecU64::from(owner_tokens));
     });
     
-    // Award governance tokens for contributing research
+    // Governance tokens for contributing research are held back until the
+    // community has endorsed the token. Flagged copies earn nothing.
+    if prior_token_id.is_none() {
+        MINT_REWARDS.with(|rewards| {
+            rewards.borrow_mut().insert(token_id, MintReward {
+                token_id,
+                recipient: caller,
+                amount: MINT_REWARD_TOKENS,
+                endorsement_count: 0,
+                status: RewardStatus::Pending,
+                settled_at: None,
+            });
+        });
+    }
+    
+    let warnings = prior_token_id
+        .map(|prior_token_id| MintWarning::DuplicateContent { prior_token_id })
+        .into_iter()
+        .chain(similar.into_iter().map(MintWarning::SimilarResearch))
+        .collect();
+    Ok(MintReceipt { token_id, warnings })
+}
+
+#[update]
+fn endorse_research_token(token_id: u64) -> Result<(), DeviteError> {
+    let caller = require_authenticated()?;
+    let storable_caller = StorablePrincipal::from(caller);
+    
+    // Check if caller is registered
+    if !USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&storable_caller)) {
+        return Err(DeviteError::NotRegistered);
+    }
+    
+    let token = RESEARCH_TOKENS.with(|tokens| {
+        tokens.borrow().get(&token_id)
+    }).ok_or_else(|| DeviteError::not_found(ResourceKind::ResearchToken, token_id))?;
+    
+    if token.retracted_at.is_some() {
+        return Err(DeviteError::TokenRetracted { token_id });
+    }
+    
+    if token.owner == caller {
+        return Err(DeviteError::SelfInteraction);
+    }
+    
+    let endorsement_key = (token_id, storable_caller);
+    if ENDORSEMENTS.with(|endorsements| endorsements.borrow().contains_key(&endorsement_key)) {
+        return Err(DeviteError::AlreadyRecorded);
+    }
+    
+    ENDORSEMENTS.with(|endorsements| {
+        endorsements.borrow_mut().insert(endorsement_key, ic_cdk::api::time());
+    });
+    record_contribution(token.owner, ContributionKind::EndorsementReceived);
+    
+    MINT_REWARDS.with(|rewards| {
+        let mut reward = match rewards.borrow().get(&token_id) {
+            Some(reward) => reward,
+            None => return,
+        };
+        reward.endorsement_count += 1;
+        rewards.borrow_mut().insert(token_id, reward.clone());
+        
+        // Pay out the minting reward once the token has been validated
+        if reward.endorsement_count >= ENDORSEMENT_THRESHOLD {
+            pay_mint_reward(&mut rewards.borrow_mut(), token_id);
+        }
+    });
+    
+    Ok(())
+}
+
+fn pay_mint_reward(rewards: &mut MintRewardStorage, token_id: u64) {
+    let mut reward = match rewards.get(&token_id) {
+        Some(reward) if reward.status == RewardStatus::Pending => reward,
+        _ => return,
+    };
+    
+    let storable_recipient = StorablePrincipal::from(reward.recipient);
     GOVERNANCE_TOKENS.with(|tokens| {
-        let current_balance = tokens.borrow().get(&storable_caller).unwrap_or(0);
-        tokens.borrow_mut().insert(storable_caller, current_balance + 50); // 50 tokens for minting
+        let current_balance = tokens.borrow().get(&storable_recipient).unwrap_or(0);
+        tokens.borrow_mut().insert(storable_recipient, current_balance + reward.amount);
+    });
+    reward.status = RewardStatus::Paid;
+    reward.settled_at = Some(ic_cdk::api::time());
+    rewards.insert(token_id, reward.clone());
+    
+    // Research only counts towards reputation once it has been validated
+    record_contribution(reward.recipient, ContributionKind::ResearchMinted);
+}
+
+#[update]
+fn retract_research_token(token_id: u64) -> Result<(), DeviteError> {
+    let caller = require_authenticated()?;
+    
+    let token = RESEARCH_TOKENS.with(|tokens| {
+        tokens.borrow().get(&token_id)
+    }).ok_or_else(|| DeviteError::not_found(ResourceKind::ResearchToken, token_id))?;
+    
+    // Owners may withdraw their own research; moderators may take down anyone's
+    if token.owner != caller && !has_role(caller, Role::Moderator) {
+        return Err(DeviteError::NotOwner);
+    }
+    
+    if token.retracted_at.is_some() {
+        return Err(DeviteError::TokenRetracted { token_id });
+    }
+    
+    retract_token(token, ic_cdk::api::time());
+    
+    Ok(())
+}
+
+fn retract_token(mut token: ResearchNFT, current_time: u64) {
+    let token_id = token.token_id;
+    let owner = token.owner;
+    token.retracted_at = Some(current_time);
+    RESEARCH_TOKENS.with(|tokens| {
+        tokens.borrow_mut().insert(token_id, token);
+    });
+    
+    claw_back_mint_reward(token_id, current_time);
+    reverse_received_credit(token_id, owner);
+}
+
+// Endorsements and citations of retracted research stop counting towards the
+// reputation of its holder
+fn reverse_received_credit(token_id: u64, owner: Principal) {
+    let endorsed_at: Vec<u64> = ENDORSEMENTS.with(|endorsements| {
+        endorsements.borrow()
+            .range((token_id, StorablePrincipal::default())..)
+            .take_while(|((id, _), _)| *id == token_id)
+            .map(|(_, endorsed_at)| endorsed_at)
+            .collect()
+    });
+    for recorded_at in endorsed_at {
+        reputation::reverse_contribution(owner, ContributionKind::EndorsementReceived, recorded_at);
+    }
+    
+    let cited_at: Vec<u64> = CITATIONS.with(|citations| {
+        citations.borrow()
+            .range((token_id, 0)..)
+            .take_while(|((cited, _), _)| *cited == token_id)
+            .map(|(_, cited_at)| cited_at)
+            .collect()
+    });
+    for recorded_at in cited_at {
+        reputation::reverse_contribution(owner, ContributionKind::CitationReceived, recorded_at);
+    }
+}
+
+fn claw_back_mint_reward(token_id: u64, current_time: u64) {
+    MINT_REWARDS.with(|rewards| {
+        let mut reward = match rewards.borrow().get(&token_id) {
+            Some(reward) => reward,
+            None => return,
+        };
+        
+        match reward.status {
+            RewardStatus::Pending => reward.status = RewardStatus::Forfeited,
+            RewardStatus::Paid => {
+                if let Some(paid_at) = reward.settled_at {
+                    reputation::reverse_contribution(reward.recipient, ContributionKind::ResearchMinted, paid_at);
+                }
+                // Take back as much of the reward as the recipient still holds
+                let storable_recipient = StorablePrincipal::from(reward.recipient);
+                GOVERNANCE_TOKENS.with(|tokens| {
+                    let current_balance = tokens.borrow().get(&storable_recipient).unwrap_or(0);
+                    tokens.borrow_mut().insert(storable_recipient, current_balance.saturating_sub(reward.amount));
+                });
+                reward.status = RewardStatus::ClawedBack;
+            }
+            RewardStatus::ClawedBack | RewardStatus::Forfeited => return,
+        }
+        
+        reward.settled_at = Some(current_time);
+        rewards.borrow_mut().insert(token_id, reward);
+    });
+}
+
+#[update]
+fn submit_review(token_id: u64, request: SubmitReviewRequest) -> Result<(), DeviteError> {
+    let caller = require_authenticated()?;
+    let storable_caller = StorablePrincipal::from(caller);
+    
+    require_role(Role::Reviewer)?;
+    request.validate()?;
+    
+    let token = RESEARCH_TOKENS.with(|tokens| {
+        tokens.borrow().get(&token_id)
+    }).ok_or_else(|| DeviteError::not_found(ResourceKind::ResearchToken, token_id))?;
+    
+    if token.retracted_at.is_some() {
+        return Err(DeviteError::TokenRetracted { token_id });
+    }
+    
+    if token.owner == caller {
+        return Err(DeviteError::SelfInteraction);
+    }
+    
+    let review_key = (token_id, storable_caller);
+    if REVIEWS.with(|reviews| reviews.borrow().contains_key(&review_key)) {
+        return Err(DeviteError::AlreadyRecorded);
+    }
+    
+    let accepted = request.recommendation == ReviewRecommendation::Accept;
+    let review = Review {
+        token_id,
+        reviewer: caller,
+        summary: request.summary,
+        recommendation: request.recommendation,
+        created_at: ic_cdk::api::time(),
+    };
+    
+    REVIEWS.with(|reviews| {
+        reviews.borrow_mut().insert(review_key, review);
+    });
+    record_contribution(caller, ContributionKind::ReviewWritten);
+    
+    // A positive peer review validates the research as well as endorsements do
+    if accepted {
+        MINT_REWARDS.with(|rewards| pay_mint_reward(&mut rewards.borrow_mut(), token_id));
+    }
+    
+    Ok(())
+}
+
+#[query]
+fn get_reviews(token_id: u64) -> Vec<Review> {
+    let start = (token_id, StorablePrincipal::default());
+    REVIEWS.with(|reviews| {
+        reviews.borrow().range(start..)
+            .take_while(|((id, _), _)| *id == token_id)
+            .map(|(_, review)| review)
+            .collect()
+    })
+}
+
+#[update]
+fn cite_research_token(citing_token_id: u64, cited_token_id: u64) -> Result<(), DeviteError> {
+    let caller = require_authenticated()?;
+    
+    let citing_token = RESEARCH_TOKENS.with(|tokens| {
+        tokens.borrow().get(&citing_token_id)
+    }).ok_or_else(|| DeviteError::not_found(ResourceKind::ResearchToken, citing_token_id))?;
+    
+    if citing_token.owner != caller {
+        return Err(DeviteError::NotOwner);
+    }
+    
+    let cited_token = RESEARCH_TOKENS.with(|tokens| {
+        tokens.borrow().get(&cited_token_id)
+    }).ok_or_else(|| DeviteError::not_found(ResourceKind::ResearchToken, cited_token_id))?;
+    
+    if citing_token.retracted_at.is_some() {
+        return Err(DeviteError::TokenRetracted { token_id: citing_token_id });
+    }
+    
+    if cited_token.retracted_at.is_some() {
+        return Err(DeviteError::TokenRetracted { token_id: cited_token_id });
+    }
+    
+    // Citing your own work does not earn reputation
+    if cited_token.owner == caller {
+        return Err(DeviteError::SelfInteraction);
+    }
+    
+    let citation_key = (cited_token_id, citing_token_id);
+    if CITATIONS.with(|citations| citations.borrow().contains_key(&citation_key)) {
+        return Err(DeviteError::AlreadyRecorded);
+    }
+    
+    CITATIONS.with(|citations| {
+        citations.borrow_mut().insert(citation_key, ic_cdk::api::time());
     });
+    record_contribution(cited_token.owner, ContributionKind::CitationReceived);
     
-    Ok(token_id)
+    Ok(())
+}
+
+#[query]
+fn get_citations(token_id: u64) -> Vec<u64> {
+    CITATIONS.with(|citations| {
+        citations.borrow().range((token_id, 0)..)
+            .take_while(|((cited, _), _)| *cited == token_id)
+            .map(|((_, citing), _)| citing)
+            .collect()
+    })
+}
+
+#[query]
+fn get_mint_reward(token_id: u64) -> Option<MintReward> {
+    MINT_REWARDS.with(|rewards| rewards.borrow().get(&token_id))
 }
 
 #[query]
@@ -448,6 +1025,15 @@ fn get_research_token(token_id: u64) -> Option<ResearchNFT> {
     RESEARCH_TOKENS.with(|tokens| tokens.borrow().get(&token_id))
 }
 
+// Accepts any spelling of a content hash: multihash, CID or hex SHA-256
+#[query]
+fn get_token_by_content_hash(content_hash: String) -> Result<ResearchNFT, DeviteError> {
+    let token_id = CONTENT_HASHES.with(|hashes| hashes.borrow().get(&content_hash::index_key(&content_hash)))
+        .ok_or_else(|| DeviteError::not_found(ResourceKind::ResearchToken, &content_hash))?;
+    RESEARCH_TOKENS.with(|tokens| tokens.borrow().get(&token_id))
+        .ok_or_else(|| DeviteError::not_found(ResourceKind::ResearchToken, token_id))
+}
+
 #[query]
 fn get_tokens_by_owner(owner: Principal) -> Vec<u64> {
     let storable_owner = StorablePrincipal::from(owner);
@@ -463,6 +1049,7 @@ fn get_tokens_by_owner(owner: Principal) -> Vec<u64> {
 fn get_tokens_by_research_type(research_type: ResearchType) -> Vec<ResearchNFT> {
     RESEARCH_TOKENS.with(|tokens| {
         tokens.borrow().iter()
+            .filter(|(_, token)| token.retracted_at.is_none())
             .filter(|(_, token)| std::mem::discriminant(&token.research_type) == std::mem::discriminant(&research_type))
             .map(|(_, token)| token)
             .collect()
@@ -474,6 +1061,7 @@ fn search_research_by_keyword(keyword: String) -> Vec<ResearchNFT> {
     let keyword_lower = keyword.to_lowercase();
     RESEARCH_TOKENS.with(|tokens| {
         tokens.borrow().iter()
+            .filter(|(_, token)| token.retracted_at.is_none())
             .filter(|(_, token)| {
                 token.metadata.keywords.iter().any(|k| k.to_lowercase().contains(&keyword_lower)) ||
                 token.title.to_lowercase().contains(&keyword_lower) ||
@@ -485,23 +1073,27 @@ fn search_research_by_keyword(keyword: String) -> Vec<ResearchNFT> {
 }
 
 #[update]
-fn transfer_research_token(token_id: u64, to: Principal) -> Result<(), String> {
-    let caller = ic_cdk::api::caller();
+fn transfer_research_token(token_id: u64, to: Principal) -> Result<(), DeviteError> {
+    let caller = require_authenticated()?;
     let storable_caller = StorablePrincipal::from(caller);
     let storable_to = StorablePrincipal::from(to);
     
     // Get the token first
     let mut token = RESEARCH_TOKENS.with(|tokens| {
         tokens.borrow().get(&token_id)
-    }).ok_or("Token not found")?;
+    }).ok_or_else(|| DeviteError::not_found(ResourceKind::ResearchToken, token_id))?;
     
     if token.owner != caller {
-        return Err("Not the owner".to_string());
+        return Err(DeviteError::NotOwner);
+    }
+    
+    if token.retracted_at.is_some() {
+        return Err(DeviteError::TokenRetracted { token_id });
     }
     
     // Check if recipient is registered
     if !USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&storable_to)) {
-        return Err("Recipient must be registered".to_string());
+        return Err(DeviteError::RecipientNotRegistered);
     }
     
     // Update token owner
@@ -540,22 +1132,27 @@ fn total_research_tokens() -> u64 {
 // GOVERNANCE FUNCTIONS
 
 #[update]
-fn create_proposal(request: CreateProposalRequest) -> Result<u64, String> {
-    let caller = ic_cdk::api::caller();
+fn create_proposal(request: CreateProposalRequest) -> Result<u64, DeviteError> {
+    let caller = require_authenticated()?;
     let storable_caller = StorablePrincipal::from(caller);
     
     // Check if caller is registered
     if !USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&storable_caller)) {
-        return Err("User must be registered first".to_string());
+        return Err(DeviteError::NotRegistered);
     }
     
+    request.validate()?;
+    
     // Check if caller has enough tokens to create proposal
     let caller_tokens = GOVERNANCE_TOKENS.with(|tokens| {
         tokens.borrow().get(&storable_caller).unwrap_or(0)
     });
     
     if caller_tokens < MIN_PROPOSAL_THRESHOLD {
-        return Err(format!("Need at least {} governance tokens to create proposal", MIN_PROPOSAL_THRESHOLD));
+        return Err(DeviteError::InsufficientTokens {
+            required: MIN_PROPOSAL_THRESHOLD,
+            available: caller_tokens,
+        });
     }
     
     let proposal_id = NEXT_PROPOSAL_ID.with(|id| {
@@ -576,6 +1173,8 @@ fn create_proposal(request: CreateProposalRequest) -> Result<u64, String> {
         voters: Vec::new(),
         created_at: ic_cdk::api::time(),
         voting_ends_at: ic_cdk::api::time() + (request.voting_duration_days * DAYS_TO_NANOSECONDS),
+        tally_mode: request.tally_mode,
+        action: request.action,
     };
     
     PROPOSALS.with(|proposals| {
@@ -586,38 +1185,36 @@ fn create_proposal(request: CreateProposalRequest) -> Result<u64, String> {
 }
 
 #[update]
-fn vote_on_proposal(proposal_id: u64, vote: Vote) -> Result<(), String> {
-    let caller = ic_cdk::api::caller();
+fn vote_on_proposal(proposal_id: u64, vote: Vote) -> Result<(), DeviteError> {
+    let caller = require_authenticated()?;
     let storable_caller = StorablePrincipal::from(caller);
     
     // Check if caller is registered
     if !USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&storable_caller)) {
-        return Err("User must be registered first".to_string());
-    }
-    
-    // Get caller's voting power
-    let voting_power = GOVERNANCE_TOKENS.with(|tokens| {
-        tokens.borrow().get(&storable_caller).unwrap_or(0)
-    });
-    
-    if voting_power == 0 {
-        return Err("No governance tokens to vote".to_string());
+        return Err(DeviteError::NotRegistered);
     }
     
     let current_time = ic_cdk::api::time();
     
     PROPOSALS.with(|proposals| {
         let mut proposal = proposals.borrow().get(&proposal_id)
-            .ok_or("Proposal not found")?;
+            .ok_or_else(|| DeviteError::not_found(ResourceKind::Proposal, proposal_id))?;
         
         // Check if voting period is still active
         if current_time > proposal.voting_ends_at {
-            return Err("Voting period has ended".to_string());
+            return Err(DeviteError::VotingClosed);
         }
         
         // Check if already voted
         if proposal.voters.contains(&caller) {
-            return Err("Already voted on this proposal".to_string());
+            return Err(DeviteError::AlreadyVoted);
+        }
+        
+        // Get caller's voting power under the proposal's tally mode
+        let voting_power = voting_power(caller, proposal.tally_mode.as_ref());
+        
+        if voting_power == 0 {
+            return Err(DeviteError::NoVotingPower);
         }
         
         // Record vote
@@ -629,32 +1226,45 @@ fn vote_on_proposal(proposal_id: u64, vote: Vote) -> Result<(), String> {
         proposal.voters.push(caller);
         
         proposals.borrow_mut().insert(proposal_id, proposal);
+        record_contribution(caller, ContributionKind::GovernanceParticipation);
         Ok(())
     })
 }
 
+fn voting_power(voter: Principal, tally_mode: Option<&TallyMode>) -> u64 {
+    match tally_mode {
+        Some(TallyMode::ReputationWeighted) => reputation::reputation_score(voter).floor() as u64,
+        Some(TallyMode::TokenWeighted) | None => get_governance_token_balance(voter),
+    }
+}
+
 #[update]
-fn finalize_proposal(proposal_id: u64) -> Result<(), String> {
+fn finalize_proposal(proposal_id: u64) -> Result<(), DeviteError> {
+    require_authenticated()?;
+    
     let current_time = ic_cdk::api::time();
     
     PROPOSALS.with(|proposals| {
         let mut proposal = proposals.borrow().get(&proposal_id)
-            .ok_or("Proposal not found")?;
+            .ok_or_else(|| DeviteError::not_found(ResourceKind::Proposal, proposal_id))?;
         
         // Check if voting period has ended
         if current_time <= proposal.voting_ends_at {
-            return Err("Voting period still active".to_string());
+            return Err(DeviteError::VotingStillActive);
         }
         
         // Check if already finalized
         if proposal.status != ProposalStatus::Active {
-            return Err("Proposal already finalized".to_string());
+            return Err(DeviteError::InvalidProposalStatus { status: proposal.status });
         }
         
         // Calculate total votes and determine outcome
         let total_votes = proposal.votes_for + proposal.votes_against;
-        let total_tokens = get_total_governance_tokens();
-        let quorum = (total_tokens * QUORUM_PERCENTAGE) / 100;
+        let total_voting_power = match proposal.tally_mode {
+            Some(TallyMode::ReputationWeighted) => reputation::total_reputation().floor() as u64,
+            Some(TallyMode::TokenWeighted) | None => get_total_governance_tokens(),
+        };
+        let quorum = (total_voting_power * QUORUM_PERCENTAGE) / 100;
         
         if total_votes < quorum {
             proposal.status = ProposalStatus::Rejected;
@@ -669,6 +1279,37 @@ fn finalize_proposal(proposal_id: u64) -> Result<(), String> {
     })
 }
 
+#[update]
+fn execute_proposal(proposal_id: u64) -> Result<(), DeviteError> {
+    require_authenticated()?;
+    
+    let mut proposal = PROPOSALS.with(|proposals| proposals.borrow().get(&proposal_id))
+        .ok_or_else(|| DeviteError::not_found(ResourceKind::Proposal, proposal_id))?;
+    
+    if proposal.status != ProposalStatus::Passed {
+        return Err(DeviteError::InvalidProposalStatus { status: proposal.status });
+    }
+    
+    match proposal.action.clone() {
+        Some(ProposalAction::GrantRole { principal, role }) => access_control::assign_role(principal, role),
+        Some(ProposalAction::RevokeRole { principal, role }) => access_control::remove_role(principal, role)?,
+        Some(ProposalAction::UpdateReputationConfig(config)) => reputation::apply_reputation_config(config),
+        Some(ProposalAction::PausePersonalStorageRollout) => {
+            fleet::pause_rollout(format!("Paused by proposal {}", proposal_id))?
+        }
+        Some(ProposalAction::ResumePersonalStorageRollout) => fleet::resume_rollout()?,
+        Some(ProposalAction::SetDefaultStorageTier(tier)) => quota::apply_default_tier(tier),
+        None => {}
+    }
+    
+    proposal.status = ProposalStatus::Executed;
+    PROPOSALS.with(|proposals| {
+        proposals.borrow_mut().insert(proposal_id, proposal);
+    });
+    
+    Ok(())
+}
+
 #[query]
 fn get_proposal(proposal_id: u64) -> Option<Proposal> {
     PROPOSALS.with(|proposals| proposals.borrow().get(&proposal_id))
@@ -0,0 +1,372 @@
+use candid::{CandidType, Deserialize, Principal, Encode, Decode};
+use ic_cdk::api::management_canister::main::*;
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableCell, Storable};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::borrow::Cow;
+
+use crate::access_control::{require_role, Role};
+use crate::content_hash::to_hex;
+use crate::error::{DeviteError, ResourceKind};
+use crate::fleet;
+use crate::quota;
+use crate::{Memory, MEMORY_MANAGER};
+
+type StorageWasmCell = StableCell<StorageWasm, Memory>;
+
+const WASM_MAGIC: &[u8] = b"\0asm";
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+// Module built together with the backend, if there was one (see build.rs)
+#[cfg(embedded_storage_wasm)]
+const EMBEDDED_WASM: Option<&[u8]> = Some(include_bytes!(concat!(env!("OUT_DIR"), "/personal_storage.wasm")));
+#[cfg(not(embedded_storage_wasm))]
+const EMBEDDED_WASM: Option<&[u8]> = None;
+
+// PERSONAL STORAGE TYPES
+
+// Init argument of the devite_personal_storage canister
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StorageInitArgs {
+    pub owner: Principal,
+    pub quota: Option<StorageQuota>,
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq)]
+pub struct StorageQuota {
+    pub max_bytes: u64,
+    pub max_items: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StorageUsage {
+    pub used_bytes: u64,
+    pub item_count: u64,
+    pub reserved_bytes: u64,
+    pub reserved_items: u64,
+    pub quota: StorageQuota,
+}
+
+// Error type of the devite_personal_storage canister
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum StorageError {
+    NotOwner,
+    NotBackend,
+    NotFound { id: String },
+    AlreadyExists { id: String },
+    InvalidInput { field: String, reason: String },
+    QuotaExceeded { usage: StorageUsage },
+    ContentTooLarge { size: u64, limit: u64 },
+    UploadNotFound { upload_id: u64 },
+    UploadInProgress { id: String },
+    ChecksumMismatch { expected: Vec<u8>, actual: Vec<u8> },
+}
+
+// Fields of the storage canister's item record the backend relies on
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StoredItemRecord {
+    pub id: String,
+    pub size: u64,
+    pub sha256: Vec<u8>,
+    pub version: u64,
+    pub revision: Option<u64>,
+}
+
+// Stored item a research token was verified against when it was minted
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StorageLocation {
+    pub canister_id: Principal,
+    pub item_id: String,
+    // Content version and revision whose SHA-256 matched the content hash
+    pub version: u64,
+    pub revision: Option<u64>,
+    pub size: u64,
+    pub verified_at: u64,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct StorageWasm {
+    pub module: Vec<u8>,
+    pub sha256: Vec<u8>,
+    pub uploaded_at: u64,
+}
+
+impl Storable for StorageWasm {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct WasmInfo {
+    pub sha256: Vec<u8>,
+    pub size: u64,
+    pub uploaded_at: u64,
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Personal storage wasm (Memory ID 15)
+    static STORAGE_WASM: RefCell<StorageWasmCell> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+            StorageWasm::default(),
+        ).expect("Failed to initialize personal storage wasm")
+    );
+}
+
+// PERSONAL STORAGE FUNCTIONS
+
+pub fn storage_wasm() -> Result<StorageWasm, DeviteError> {
+    let wasm = STORAGE_WASM.with(|cell| cell.borrow().get().clone());
+    if wasm.module.is_empty() {
+        Err(DeviteError::StorageWasmNotConfigured)
+    } else {
+        Ok(wasm)
+    }
+}
+
+// Hash of the current storage wasm, read without copying the module
+pub fn storage_wasm_sha256() -> Result<Vec<u8>, DeviteError> {
+    STORAGE_WASM.with(|cell| {
+        let cell = cell.borrow();
+        let wasm = cell.get();
+        if wasm.module.is_empty() {
+            Err(DeviteError::StorageWasmNotConfigured)
+        } else {
+            Ok(wasm.sha256.clone())
+        }
+    })
+}
+
+pub async fn create_storage_canister(controllers: Vec<Principal>) -> Result<Principal, DeviteError> {
+    let create_args = CreateCanisterArgument {
+        settings: Some(CanisterSettings {
+            controllers: Some(controllers),
+            compute_allocation: None,
+            memory_allocation: None,
+            freezing_threshold: None,
+        }),
+    };
+
+    match create_canister(create_args).await {
+        Ok((canister_id,)) => Ok(canister_id.canister_id),
+        Err((code, msg)) => Err(DeviteError::CanisterCallFailed {
+            reason: format!("Failed to create canister: {:?} - {}", code, msg),
+        }),
+    }
+}
+
+pub async fn install_personal_storage(
+    canister_id: Principal,
+    owner: Principal,
+    wasm: &StorageWasm,
+    mode: CanisterInstallMode,
+) -> Result<(), DeviteError> {
+    let install_args = InstallCodeArgument {
+        mode,
+        canister_id,
+        wasm_module: wasm.module.clone(),
+        arg: Encode!(&StorageInitArgs {
+            owner,
+            quota: Some(quota::effective_quota(owner)),
+        }).unwrap(),
+    };
+
+    install_code(install_args).await.map_err(|(code, msg)| DeviteError::CanisterCallFailed {
+        reason: format!("Failed to install personal storage in {}: {:?} - {}", canister_id, code, msg),
+    })
+}
+
+// Makes `owner` the owner of the storage canister and its controller next to
+// the backend. Safe to repeat for a canister that is already handed over.
+pub async fn hand_over_storage(canister_id: Principal, owner: Principal) -> Result<(), DeviteError> {
+    let (result,): (Result<(), StorageError>,) = ic_cdk::call(canister_id, "assign_owner", (owner,))
+        .await
+        .map_err(|(code, msg)| DeviteError::CanisterCallFailed {
+            reason: format!("Failed to assign owner of {}: {:?} - {}", canister_id, code, msg),
+        })?;
+    result.map_err(|error| DeviteError::CanisterCallFailed {
+        reason: format!("Storage canister {} rejected the owner: {:?}", canister_id, error),
+    })?;
+
+    set_controllers(canister_id, vec![owner, ic_cdk::api::id()]).await
+}
+
+pub async fn set_controllers(canister_id: Principal, controllers: Vec<Principal>) -> Result<(), DeviteError> {
+    update_settings(UpdateSettingsArgument {
+        canister_id,
+        settings: CanisterSettings {
+            controllers: Some(controllers),
+            compute_allocation: None,
+            memory_allocation: None,
+            freezing_threshold: None,
+        },
+    })
+    .await
+    .map_err(|(code, msg)| DeviteError::CanisterCallFailed {
+        reason: format!("Failed to update controllers of {}: {:?} - {}", canister_id, code, msg),
+    })
+}
+
+pub async fn set_storage_quota(canister_id: Principal, quota: StorageQuota) -> Result<(), DeviteError> {
+    let (result,): (Result<(), StorageError>,) = ic_cdk::call(canister_id, "set_quota", (quota,))
+        .await
+        .map_err(|(code, msg)| DeviteError::CanisterCallFailed {
+            reason: format!("Failed to set quota of {}: {:?} - {}", canister_id, code, msg),
+        })?;
+    result.map_err(|error| DeviteError::CanisterCallFailed {
+        reason: format!("Storage canister {} rejected the quota: {:?}", canister_id, error),
+    })
+}
+
+pub async fn fetch_item_record(canister_id: Principal, item_id: &str) -> Result<StoredItemRecord, DeviteError> {
+    let (result,): (Result<StoredItemRecord, StorageError>,) =
+        ic_cdk::call(canister_id, "get_item_record", (item_id.to_string(),))
+            .await
+            .map_err(|(code, msg)| DeviteError::CanisterCallFailed {
+                reason: format!("Failed to read item {} of {}: {:?} - {}", item_id, canister_id, code, msg),
+            })?;
+    result.map_err(|error| match error {
+        StorageError::NotFound { id } => DeviteError::not_found(ResourceKind::StoredItem, id),
+        error => DeviteError::CanisterCallFailed {
+            reason: format!("Storage canister {} refused item {}: {:?}", canister_id, item_id, error),
+        },
+    })
+}
+
+// Owners control their personal canisters and could install code that reports
+// any record, so only a module the backend installed is believed: the current
+// storage wasm, or the one recorded for the canister while a rollout is on
+async fn require_trusted_module(canister_id: Principal) -> Result<(), DeviteError> {
+    let (status,) = canister_status(CanisterIdRecord { canister_id })
+        .await
+        .map_err(|(code, msg)| DeviteError::CanisterCallFailed {
+            reason: format!("Failed to query status of {}: {:?} - {}", canister_id, code, msg),
+        })?;
+
+    let trusted = status.module_hash.is_some_and(|hash| {
+        storage_wasm_sha256().is_ok_and(|sha256| sha256 == hash)
+            || fleet::personal_canister(canister_id)
+                .is_some_and(|record| !record.wasm_sha256.is_empty() && record.wasm_sha256 == hash)
+    });
+    if trusted {
+        Ok(())
+    } else {
+        Err(DeviteError::UntrustedStorageModule { canister_id })
+    }
+}
+
+// Checks that `item_id` in the personal canister of `owner` holds content
+// whose SHA-256 is `sha256`
+pub async fn verify_stored_content(
+    owner: Principal,
+    item_id: &str,
+    sha256: &[u8],
+) -> Result<StorageLocation, DeviteError> {
+    let canister_id = quota::personal_canister_of(owner)?;
+    require_trusted_module(canister_id).await?;
+    let record = fetch_item_record(canister_id, item_id).await?;
+
+    if record.sha256 != sha256 {
+        return Err(DeviteError::ContentHashMismatch {
+            expected: to_hex(sha256),
+            actual: to_hex(&record.sha256),
+        });
+    }
+
+    Ok(StorageLocation {
+        canister_id,
+        item_id: record.id,
+        version: record.version,
+        revision: record.revision,
+        size: record.size,
+        verified_at: ic_cdk::api::time(),
+    })
+}
+
+// Installs the current wasm into `canister_id` unless it already runs code.
+// Returns the sha256 of the installed wasm, empty if the existing code is of
+// unknown version so that the next rollout upgrades it.
+pub async fn ensure_installed(canister_id: Principal, owner: Principal) -> Result<Vec<u8>, DeviteError> {
+    let (status,) = canister_status(CanisterIdRecord { canister_id })
+        .await
+        .map_err(|(code, msg)| DeviteError::CanisterCallFailed {
+            reason: format!("Failed to query status of {}: {:?} - {}", canister_id, code, msg),
+        })?;
+
+    if status.module_hash.is_some() {
+        return Ok(Vec::new());
+    }
+
+    let wasm = storage_wasm()?;
+    install_personal_storage(canister_id, owner, &wasm, CanisterInstallMode::Install).await?;
+    Ok(wasm.sha256)
+}
+
+#[update]
+fn set_personal_storage_wasm(wasm_module: Vec<u8>) -> Result<WasmInfo, DeviteError> {
+    require_role(Role::Admin)?;
+
+    // Upgrades in flight would otherwise mix two versions into one rollout
+    let rollout = fleet::rollout_state();
+    if rollout.is_active() {
+        return Err(DeviteError::InvalidRolloutPhase { phase: rollout.phase });
+    }
+
+    if !wasm_module.starts_with(WASM_MAGIC) && !wasm_module.starts_with(GZIP_MAGIC) {
+        return Err(DeviteError::ValidationFailed {
+            field: "wasm_module".to_string(),
+            reason: "must be a wasm module or a gzipped wasm module".to_string(),
+        });
+    }
+
+    Ok(store_wasm(wasm_module))
+}
+
+fn store_wasm(module: Vec<u8>) -> WasmInfo {
+    let wasm = StorageWasm {
+        sha256: Sha256::digest(&module).to_vec(),
+        module,
+        uploaded_at: ic_cdk::api::time(),
+    };
+    let info = WasmInfo {
+        sha256: wasm.sha256.clone(),
+        size: wasm.module.len() as u64,
+        uploaded_at: wasm.uploaded_at,
+    };
+
+    STORAGE_WASM.with(|cell| {
+        cell.borrow_mut().set(wasm).expect("Failed to store personal storage wasm");
+    });
+    info
+}
+
+// Gives a fresh deploy the module embedded at build time. An uploaded module
+// is never replaced, since moving users to a new one goes through a rollout.
+pub fn install_embedded_wasm() {
+    if let Some(module) = EMBEDDED_WASM {
+        if storage_wasm_sha256().is_err() {
+            store_wasm(module.to_vec());
+        }
+    }
+}
+
+#[query]
+fn get_personal_storage_wasm_info() -> Option<WasmInfo> {
+    STORAGE_WASM.with(|cell| {
+        let cell = cell.borrow();
+        let wasm = cell.get();
+        (!wasm.module.is_empty()).then(|| WasmInfo {
+            size: wasm.module.len() as u64,
+            sha256: wasm.sha256.clone(),
+            uploaded_at: wasm.uploaded_at,
+        })
+    })
+}
@@ -0,0 +1,261 @@
+use candid::{CandidType, Deserialize, Principal, Encode, Decode};
+use ic_cdk::api::management_canister::main::CanisterInstallMode;
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable, BoundedStorable};
+use std::cell::RefCell;
+use std::borrow::Cow;
+use std::time::Duration;
+
+use crate::access_control::{require_role, Role};
+use crate::error::DeviteError;
+use crate::personal_storage;
+use crate::quota;
+use crate::{Memory, StorablePrincipal, MEMORY_MANAGER};
+
+type CanisterPoolStorage = StableBTreeMap<StorablePrincipal, PooledCanister, Memory>;
+type PoolTargetCell = StableCell<u32, Memory>;
+
+const DEFAULT_POOL_TARGET: u32 = 10;
+const MAX_POOL_TARGET: u32 = 100;
+// Canisters created per refill tick, so one tick cannot drain the backend
+const REFILL_BATCH_SIZE: u32 = 5;
+const REFILL_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// CANISTER POOL TYPES
+
+// A personal storage canister installed with the backend as owner, waiting to
+// be handed to a new user
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PooledCanister {
+    pub canister_id: Principal,
+    // Empty if the install failed and has to be retried on assignment
+    pub wasm_sha256: Vec<u8>,
+    pub created_at: u64,
+}
+
+impl Storable for PooledCanister {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PooledCanister {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CanisterPoolStatus {
+    pub available: u64,
+    pub target_size: u32,
+    pub refill_in_progress: bool,
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Canister pool and its target size (Memory ID 18, 19)
+    static CANISTER_POOL: RefCell<CanisterPoolStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+        )
+    );
+
+    static POOL_TARGET: RefCell<PoolTargetCell> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+            DEFAULT_POOL_TARGET,
+        ).expect("Failed to initialize canister pool target")
+    );
+
+    static REFILL_IN_PROGRESS: RefCell<bool> = const { RefCell::new(false) };
+}
+
+// CANISTER POOL FUNCTIONS
+
+pub fn start_refill_timer() {
+    ic_cdk_timers::set_timer_interval(REFILL_INTERVAL, || {
+        ic_cdk::spawn(refill());
+    });
+}
+
+async fn refill() {
+    if REFILL_IN_PROGRESS.with(|flag| flag.replace(true)) {
+        return;
+    }
+
+    // Nothing to install yet; registration reports the missing wasm itself
+    if let Ok(wasm) = personal_storage::storage_wasm() {
+        let available = CANISTER_POOL.with(|pool| pool.borrow().len()) as u32;
+        let target = POOL_TARGET.with(|cell| *cell.borrow().get());
+        let missing = target.saturating_sub(available).min(REFILL_BATCH_SIZE);
+
+        for _ in 0..missing {
+            let backend = ic_cdk::api::id();
+            let canister_id = match personal_storage::create_storage_canister(vec![backend]).await {
+                Ok(canister_id) => canister_id,
+                Err(error) => {
+                    ic_cdk::println!("Canister pool refill stopped: {:?}", error);
+                    break;
+                }
+            };
+
+            let installed = personal_storage::install_personal_storage(
+                canister_id,
+                backend,
+                &wasm,
+                CanisterInstallMode::Install,
+            ).await;
+            let pooled = PooledCanister {
+                canister_id,
+                wasm_sha256: if installed.is_ok() { wasm.sha256.clone() } else { Vec::new() },
+                created_at: ic_cdk::api::time(),
+            };
+            CANISTER_POOL.with(|pool| {
+                pool.borrow_mut().insert(StorablePrincipal::from(canister_id), pooled);
+            });
+        }
+    }
+
+    REFILL_IN_PROGRESS.with(|flag| *flag.borrow_mut() = false);
+}
+
+// Removes a canister from the pool. This happens before any await so that two
+// registrations can never be handed the same canister.
+pub fn take_pooled_canister() -> Option<PooledCanister> {
+    CANISTER_POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        let key = pool.iter().next().map(|(key, _)| key)?;
+        pool.remove(&key)
+    })
+}
+
+pub fn return_pooled_canister(pooled: PooledCanister) {
+    CANISTER_POOL.with(|pool| {
+        pool.borrow_mut().insert(StorablePrincipal::from(pooled.canister_id), pooled);
+    });
+}
+
+// Until its module is installed a canister cannot be handed over, so one
+// without a recorded module never left the backend's hands
+fn needs_reset(pooled: &PooledCanister) -> bool {
+    !pooled.wasm_sha256.is_empty()
+}
+
+// A reset canister carries the module it was just reinstalled with; one whose
+// reset failed is never pooled again
+fn after_reset(mut pooled: PooledCanister, reset: Result<Vec<u8>, DeviteError>) -> Option<PooledCanister> {
+    pooled.wasm_sha256 = reset.ok()?;
+    Some(pooled)
+}
+
+// Prepares a canister whose hand-over failed for the pool again. The
+// registrant may already own and control it and could have changed its code
+// or state since, so the backend takes back sole control and reinstalls the
+// module with itself as owner. Returns None if the canister must be deleted.
+pub async fn reset_pooled_canister(
+    pooled: PooledCanister,
+    wasm: &personal_storage::StorageWasm,
+) -> Option<PooledCanister> {
+    if !needs_reset(&pooled) {
+        return Some(pooled);
+    }
+
+    let backend = ic_cdk::api::id();
+    let reset = match personal_storage::set_controllers(pooled.canister_id, vec![backend]).await {
+        Ok(()) => personal_storage::install_personal_storage(
+            pooled.canister_id,
+            backend,
+            wasm,
+            CanisterInstallMode::Reinstall,
+        ).await.map(|()| wasm.sha256.clone()),
+        Err(error) => Err(error),
+    };
+    after_reset(pooled, reset)
+}
+
+// Brings a pooled canister onto the current wasm and hands it to `owner`.
+// `pooled` keeps track of what was installed in case the hand-over fails.
+pub async fn assign_pooled_canister(
+    pooled: &mut PooledCanister,
+    owner: Principal,
+    wasm: &personal_storage::StorageWasm,
+) -> Result<(), DeviteError> {
+    if pooled.wasm_sha256 != wasm.sha256 {
+        let mode = if pooled.wasm_sha256.is_empty() {
+            CanisterInstallMode::Install
+        } else {
+            CanisterInstallMode::Reinstall
+        };
+        personal_storage::install_personal_storage(pooled.canister_id, owner, wasm, mode).await?;
+        pooled.wasm_sha256 = wasm.sha256.clone();
+    }
+
+    personal_storage::hand_over_storage(pooled.canister_id, owner).await?;
+    // The default tier may have changed since the canister was pooled
+    personal_storage::set_storage_quota(pooled.canister_id, quota::effective_quota(owner)).await
+}
+
+#[update]
+fn set_canister_pool_target(target_size: u32) -> Result<(), DeviteError> {
+    require_role(Role::Admin)?;
+
+    if target_size > MAX_POOL_TARGET {
+        return Err(DeviteError::ValidationFailed {
+            field: "target_size".to_string(),
+            reason: format!("must be at most {}", MAX_POOL_TARGET),
+        });
+    }
+
+    POOL_TARGET.with(|cell| {
+        cell.borrow_mut().set(target_size).expect("Failed to store canister pool target");
+    });
+    Ok(())
+}
+
+#[query]
+fn get_canister_pool_status() -> CanisterPoolStatus {
+    CanisterPoolStatus {
+        available: CANISTER_POOL.with(|pool| pool.borrow().len()),
+        target_size: POOL_TARGET.with(|cell| *cell.borrow().get()),
+        refill_in_progress: REFILL_IN_PROGRESS.with(|flag| *flag.borrow()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pooled(wasm_sha256: Vec<u8>) -> PooledCanister {
+        PooledCanister {
+            canister_id: Principal::from_slice(&[7; 10]),
+            wasm_sha256,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn only_canisters_with_a_module_can_have_been_handed_over() {
+        assert!(!needs_reset(&pooled(Vec::new())));
+        assert!(needs_reset(&pooled(vec![1; 32])));
+    }
+
+    #[test]
+    fn failed_resets_are_not_pooled_again() {
+        let failed = Err(DeviteError::CanisterCallFailed {
+            reason: "Failed to update controllers".to_string(),
+        });
+        assert!(after_reset(pooled(vec![1; 32]), failed).is_none());
+    }
+
+    #[test]
+    fn reset_canisters_record_the_reinstalled_module() {
+        let reset = after_reset(pooled(vec![1; 32]), Ok(vec![2; 32])).unwrap();
+        assert_eq!(reset.wasm_sha256, vec![2; 32]);
+    }
+}
@@ -0,0 +1,300 @@
+use candid::{CandidType, Deserialize, Principal, Encode, Decode};
+use ic_cdk::api::call::{msg_cycles_accept128, msg_cycles_available128};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable, BoundedStorable};
+use std::cell::RefCell;
+use std::borrow::Cow;
+
+use crate::access_control::{require_authenticated, require_role, Role};
+use crate::error::{DeviteError, ResourceKind};
+use crate::fleet;
+use crate::personal_storage::{self, StorageQuota};
+use crate::validation::Validate;
+use crate::{Memory, StorablePrincipal, GOVERNANCE_TOKENS, MEMORY_MANAGER, USER_PROFILES};
+
+type QuotaPolicyCell = StableCell<QuotaPolicy, Memory>;
+type StorageAccountStorage = StableBTreeMap<StorablePrincipal, StorageAccount, Memory>;
+
+const MIB: u64 = 1024 * 1024;
+
+// QUOTA TYPES
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct QuotaPolicy {
+    // Quota every personal canister starts with
+    pub default_tier: StorageQuota,
+    // Price of one extra MiB, which also buys `items_per_mib` extra items
+    pub cycles_per_mib: u128,
+    pub tokens_per_mib: u64,
+    pub items_per_mib: u64,
+}
+
+impl Default for QuotaPolicy {
+    fn default() -> Self {
+        QuotaPolicy {
+            default_tier: StorageQuota {
+                max_bytes: 100 * MIB,
+                max_items: 1000,
+            },
+            cycles_per_mib: 5_000_000_000,
+            tokens_per_mib: 1,
+            items_per_mib: 10,
+        }
+    }
+}
+
+impl Storable for QuotaPolicy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum CyclesSpendKind {
+    Creation,
+    TopUp,
+    Upgrade,
+}
+
+// What a user has bought on top of the default tier and what the platform
+// has spent on their personal canister
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct StorageAccount {
+    pub extra_bytes: u64,
+    pub extra_items: u64,
+    pub deposited_cycles: u128,
+    pub spent_tokens: u64,
+    pub creation_cycles: u128,
+    pub top_up_cycles: u128,
+    pub upgrade_cycles: u128,
+}
+
+impl Storable for StorageAccount {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for StorageAccount {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct QuotaPurchase {
+    pub quota: StorageQuota,
+    // False if the personal canister could not be updated yet; call
+    // `sync_storage_quota` to retry
+    pub synced: bool,
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Quota policy and per-user storage accounts (Memory ID 23, 24)
+    static QUOTA_POLICY: RefCell<QuotaPolicyCell> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))),
+            QuotaPolicy::default(),
+        ).expect("Failed to initialize quota policy")
+    );
+
+    static STORAGE_ACCOUNTS: RefCell<StorageAccountStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))),
+        )
+    );
+}
+
+// QUOTA FUNCTIONS
+
+fn quota_policy() -> QuotaPolicy {
+    QUOTA_POLICY.with(|cell| cell.borrow().get().clone())
+}
+
+fn set_quota_policy_value(policy: QuotaPolicy) {
+    QUOTA_POLICY.with(|cell| cell.borrow_mut().set(policy).expect("Failed to store quota policy"));
+}
+
+fn storage_account(user: Principal) -> StorageAccount {
+    STORAGE_ACCOUNTS.with(|accounts| accounts.borrow().get(&StorablePrincipal::from(user))).unwrap_or_default()
+}
+
+fn update_account(user: Principal, update: impl FnOnce(&mut StorageAccount)) {
+    let mut account = storage_account(user);
+    update(&mut account);
+    STORAGE_ACCOUNTS.with(|accounts| {
+        accounts.borrow_mut().insert(StorablePrincipal::from(user), account);
+    });
+}
+
+pub fn effective_quota(user: Principal) -> StorageQuota {
+    let default_tier = quota_policy().default_tier;
+    let account = storage_account(user);
+    StorageQuota {
+        max_bytes: default_tier.max_bytes.saturating_add(account.extra_bytes),
+        max_items: default_tier.max_items.saturating_add(account.extra_items),
+    }
+}
+
+pub fn record_cycles_spent(user: Principal, kind: CyclesSpendKind, cycles: u128) {
+    update_account(user, |account| {
+        let total = match kind {
+            CyclesSpendKind::Creation => &mut account.creation_cycles,
+            CyclesSpendKind::TopUp => &mut account.top_up_cycles,
+            CyclesSpendKind::Upgrade => &mut account.upgrade_cycles,
+        };
+        *total = total.saturating_add(cycles);
+    });
+}
+
+fn credit_mib(user: Principal, mib: u64, items_per_mib: u64, update: impl FnOnce(&mut StorageAccount)) {
+    update_account(user, |account| {
+        account.extra_bytes = account.extra_bytes.saturating_add(mib.saturating_mul(MIB));
+        account.extra_items = account.extra_items.saturating_add(mib.saturating_mul(items_per_mib));
+        update(account);
+    });
+}
+
+pub fn personal_canister_of(user: Principal) -> Result<Principal, DeviteError> {
+    let profile = USER_PROFILES.with(|profiles| profiles.borrow().get(&StorablePrincipal::from(user)))
+        .ok_or(DeviteError::NotRegistered)?;
+    profile.personal_canister_id
+        .ok_or_else(|| DeviteError::not_found(ResourceKind::PersonalCanister, user))
+}
+
+async fn push_quota(user: Principal) -> Result<StorageQuota, DeviteError> {
+    let canister_id = personal_canister_of(user)?;
+    let quota = effective_quota(user);
+    personal_storage::set_storage_quota(canister_id, quota).await?;
+    Ok(quota)
+}
+
+async fn purchase_result(user: Principal) -> QuotaPurchase {
+    match push_quota(user).await {
+        Ok(quota) => QuotaPurchase { quota, synced: true },
+        Err(_) => QuotaPurchase {
+            quota: effective_quota(user),
+            synced: false,
+        },
+    }
+}
+
+// Pushes the current quota to every personal canister after the default tier
+// changed. Canisters that miss it pick it up on their owner's next sync.
+async fn sync_all_quotas() {
+    for record in fleet::personal_canisters() {
+        let quota = effective_quota(record.owner);
+        if let Err(error) = personal_storage::set_storage_quota(record.canister_id, quota).await {
+            ic_cdk::println!("Failed to sync quota of {}: {:?}", record.canister_id, error);
+        }
+    }
+}
+
+pub fn apply_default_tier(default_tier: StorageQuota) {
+    let mut policy = quota_policy();
+    policy.default_tier = default_tier;
+    set_quota_policy_value(policy);
+    ic_cdk::spawn(sync_all_quotas());
+}
+
+#[update]
+async fn deposit_storage_cycles() -> Result<QuotaPurchase, DeviteError> {
+    let caller = require_authenticated()?;
+    personal_canister_of(caller)?;
+
+    let policy = quota_policy();
+    let available = msg_cycles_available128();
+    let mib = (available / policy.cycles_per_mib).min(u64::MAX as u128) as u64;
+    if mib == 0 {
+        return Err(DeviteError::InsufficientCycles {
+            required: policy.cycles_per_mib,
+            available,
+        });
+    }
+
+    // Only whole MiB are bought; the rest is refunded with the reply
+    let accepted = msg_cycles_accept128(mib as u128 * policy.cycles_per_mib);
+    credit_mib(caller, mib, policy.items_per_mib, |account| {
+        account.deposited_cycles = account.deposited_cycles.saturating_add(accepted);
+    });
+
+    Ok(purchase_result(caller).await)
+}
+
+#[update]
+async fn purchase_storage_with_tokens(amount: u64) -> Result<QuotaPurchase, DeviteError> {
+    let caller = require_authenticated()?;
+    personal_canister_of(caller)?;
+
+    let policy = quota_policy();
+    let mib = amount / policy.tokens_per_mib;
+    if mib == 0 {
+        return Err(DeviteError::ValidationFailed {
+            field: "amount".to_string(),
+            reason: format!("must be at least {} tokens", policy.tokens_per_mib),
+        });
+    }
+
+    let cost = mib * policy.tokens_per_mib;
+    let storable_caller = StorablePrincipal::from(caller);
+    let balance = GOVERNANCE_TOKENS.with(|tokens| tokens.borrow().get(&storable_caller).unwrap_or(0));
+    if balance < cost {
+        return Err(DeviteError::InsufficientTokens {
+            required: cost,
+            available: balance,
+        });
+    }
+
+    GOVERNANCE_TOKENS.with(|tokens| {
+        tokens.borrow_mut().insert(storable_caller, balance - cost);
+    });
+    credit_mib(caller, mib, policy.items_per_mib, |account| {
+        account.spent_tokens = account.spent_tokens.saturating_add(cost);
+    });
+
+    Ok(purchase_result(caller).await)
+}
+
+#[update]
+async fn sync_storage_quota() -> Result<StorageQuota, DeviteError> {
+    let caller = require_authenticated()?;
+    push_quota(caller).await
+}
+
+#[update]
+fn set_quota_policy(policy: QuotaPolicy) -> Result<(), DeviteError> {
+    require_role(Role::Admin)?;
+    policy.validate()?;
+
+    let tier_changed = quota_policy().default_tier != policy.default_tier;
+    set_quota_policy_value(policy);
+    if tier_changed {
+        ic_cdk::spawn(sync_all_quotas());
+    }
+    Ok(())
+}
+
+#[query]
+fn get_quota_policy() -> QuotaPolicy {
+    quota_policy()
+}
+
+#[query]
+fn get_storage_account(user: Principal) -> StorageAccount {
+    storage_account(user)
+}
+
+#[query]
+fn get_storage_quota(user: Principal) -> StorageQuota {
+    effective_quota(user)
+}
@@ -0,0 +1,283 @@
+use candid::{CandidType, Deserialize, Principal, Encode, Decode};
+use ic_cdk::api::management_canister::main::*;
+use ic_cdk::update;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, Storable, BoundedStorable};
+use std::cell::RefCell;
+use std::borrow::Cow;
+use std::time::Duration;
+
+use crate::access_control::{require_authenticated, require_role, Role};
+use crate::error::DeviteError;
+use crate::fleet;
+use crate::personal_storage;
+use crate::pool;
+use crate::quota::{self, CyclesSpendKind};
+use crate::validation::Validate;
+use crate::{
+    CreateUserRequest, Memory, StorablePrincipal, UserProfile, GOVERNANCE_TOKENS,
+    INITIAL_GOVERNANCE_TOKENS, MEMORY_MANAGER, USER_PROFILES,
+};
+
+type PendingRegistrationStorage = StableBTreeMap<StorablePrincipal, PendingRegistration, Memory>;
+
+// Registrations still pending after this long are handed to reconciliation
+const REGISTRATION_TIMEOUT: u64 = 10 * 60 * 1_000_000_000;
+const RECONCILIATION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// REGISTRATION TYPES
+
+// Doubles as the per-principal lock held across the canister creation await
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PendingRegistration {
+    pub request: CreateUserRequest,
+    pub started_at: u64,
+    pub canister_id: Option<Principal>,
+}
+
+impl Storable for PendingRegistration {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PendingRegistration {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct ReconciliationReport {
+    // Registrations completed with the canister that was created for them
+    pub adopted: Vec<Principal>,
+    // Canisters deleted because their owner already had another one
+    pub deleted_canisters: Vec<Principal>,
+    // Locks released for registrations that never got a canister
+    pub released: Vec<Principal>,
+    pub errors: Vec<String>,
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Pending registrations (Memory ID 14)
+    static PENDING_REGISTRATIONS: RefCell<PendingRegistrationStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+        )
+    );
+}
+
+// REGISTRATION FUNCTIONS
+
+pub fn start_reconciliation_timer() {
+    ic_cdk_timers::set_timer_interval(RECONCILIATION_INTERVAL, || {
+        ic_cdk::spawn(async {
+            let report = reconcile().await;
+            if !report.errors.is_empty() {
+                ic_cdk::println!("Registration reconciliation errors: {:?}", report.errors);
+            }
+        });
+    });
+}
+
+#[update]
+async fn register_user(request: CreateUserRequest) -> Result<UserProfile, DeviteError> {
+    let caller = require_authenticated()?;
+    let storable_caller = StorablePrincipal::from(caller);
+
+    request.validate()?;
+
+    // Check if user already exists
+    if USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&storable_caller)) {
+        return Err(DeviteError::AlreadyRegistered);
+    }
+
+    // Fail before spending cycles if there is no code to install
+    let storage_wasm = personal_storage::storage_wasm()?;
+
+    // Take the lock before the first await so concurrent calls from the same
+    // principal cannot both create a canister
+    if PENDING_REGISTRATIONS.with(|pending| pending.borrow().contains_key(&storable_caller)) {
+        return Err(DeviteError::RegistrationInProgress);
+    }
+
+    let mut pending = PendingRegistration {
+        request,
+        started_at: ic_cdk::api::time(),
+        canister_id: None,
+    };
+    PENDING_REGISTRATIONS.with(|registrations| {
+        registrations.borrow_mut().insert(storable_caller.clone(), pending.clone());
+    });
+
+    // Prefer a pre-provisioned canister and only create one when the pool is empty
+    if let Some(mut pooled) = pool::take_pooled_canister() {
+        pending.canister_id = Some(pooled.canister_id);
+        PENDING_REGISTRATIONS.with(|registrations| {
+            registrations.borrow_mut().insert(storable_caller.clone(), pending.clone());
+        });
+
+        if let Err(error) = pool::assign_pooled_canister(&mut pooled, caller, &storage_wasm).await {
+            let canister_id = pooled.canister_id;
+            // Keep the lock if cleanup fails so reconciliation can retry it
+            match pool::reset_pooled_canister(pooled, &storage_wasm).await {
+                Some(pooled) => {
+                    pool::return_pooled_canister(pooled);
+                    release_lock(&storable_caller);
+                }
+                None => {
+                    if delete_orphaned_canister(canister_id).await.is_ok() {
+                        release_lock(&storable_caller);
+                    }
+                }
+            }
+            return Err(error);
+        }
+
+        return Ok(complete_registration(caller, pending.request, pooled.canister_id, pooled.wasm_sha256));
+    }
+
+    // Create personal canister for user
+    let personal_canister_id = match personal_storage::create_storage_canister(vec![caller, ic_cdk::api::id()]).await {
+        Ok(canister_id) => canister_id,
+        Err(error) => {
+            release_lock(&storable_caller);
+            return Err(error);
+        }
+    };
+
+    // Remember the canister so reconciliation can adopt it if we never finish
+    pending.canister_id = Some(personal_canister_id);
+    PENDING_REGISTRATIONS.with(|registrations| {
+        registrations.borrow_mut().insert(storable_caller.clone(), pending.clone());
+    });
+
+    if let Err(error) = personal_storage::install_personal_storage(
+        personal_canister_id,
+        caller,
+        &storage_wasm,
+        CanisterInstallMode::Install,
+    ).await {
+        // Keep the lock if cleanup fails so reconciliation can retry it
+        if delete_orphaned_canister(personal_canister_id).await.is_ok() {
+            release_lock(&storable_caller);
+        }
+        return Err(error);
+    }
+
+    Ok(complete_registration(caller, pending.request, personal_canister_id, storage_wasm.sha256))
+}
+
+fn complete_registration(
+    caller: Principal,
+    request: CreateUserRequest,
+    personal_canister_id: Principal,
+    wasm_sha256: Vec<u8>,
+) -> UserProfile {
+    let storable_caller = StorablePrincipal::from(caller);
+
+    let user_profile = UserProfile {
+        principal: caller,
+        username: request.username,
+        email: request.email,
+        institution: request.institution,
+        research_domains: request.research_domains,
+        personal_canister_id: Some(personal_canister_id),
+        created_at: ic_cdk::api::time(),
+        institution_verified_at: None,
+    };
+
+    USER_PROFILES.with(|profiles| {
+        profiles.borrow_mut().insert(storable_caller.clone(), user_profile.clone())
+    });
+
+    fleet::register_personal_canister(personal_canister_id, caller, wasm_sha256);
+    quota::record_cycles_spent(caller, CyclesSpendKind::Creation, CREATE_CANISTER_CYCLES);
+
+    // Award initial governance tokens
+    GOVERNANCE_TOKENS.with(|tokens| {
+        tokens.borrow_mut().insert(storable_caller.clone(), INITIAL_GOVERNANCE_TOKENS);
+    });
+
+    release_lock(&storable_caller);
+    user_profile
+}
+
+fn release_lock(principal: &StorablePrincipal) {
+    PENDING_REGISTRATIONS.with(|registrations| {
+        registrations.borrow_mut().remove(principal);
+    });
+}
+
+async fn delete_orphaned_canister(canister_id: Principal) -> Result<(), String> {
+    let record = CanisterIdRecord { canister_id };
+    stop_canister(record)
+        .await
+        .map_err(|(code, msg)| format!("Failed to stop canister {}: {:?} - {}", canister_id, code, msg))?;
+    delete_canister(record)
+        .await
+        .map_err(|(code, msg)| format!("Failed to delete canister {}: {:?} - {}", canister_id, code, msg))
+}
+
+// Resolves registrations that were interrupted after their lock was taken:
+// canisters created for a principal without a profile are adopted, duplicates
+// are deleted, and locks without a canister are released.
+async fn reconcile() -> ReconciliationReport {
+    let now = ic_cdk::api::time();
+    let stale: Vec<(StorablePrincipal, PendingRegistration)> = PENDING_REGISTRATIONS.with(|registrations| {
+        registrations.borrow().iter()
+            .filter(|(_, pending)| now.saturating_sub(pending.started_at) > REGISTRATION_TIMEOUT)
+            .collect()
+    });
+
+    let mut report = ReconciliationReport::default();
+    for (storable_principal, pending) in stale {
+        let principal = Principal::from(storable_principal.clone());
+        let profile = USER_PROFILES.with(|profiles| profiles.borrow().get(&storable_principal));
+
+        match (profile, pending.canister_id) {
+            (None, Some(canister_id)) => {
+                // Pooled canisters may still be owned by the backend
+                let adopted = match personal_storage::ensure_installed(canister_id, principal).await {
+                    Ok(wasm_sha256) => personal_storage::hand_over_storage(canister_id, principal)
+                        .await
+                        .map(|()| wasm_sha256),
+                    Err(error) => Err(error),
+                };
+                match adopted {
+                    Ok(wasm_sha256) => {
+                        complete_registration(principal, pending.request, canister_id, wasm_sha256);
+                        report.adopted.push(principal);
+                    }
+                    Err(error) => report.errors.push(format!("{:?}", error)),
+                }
+            }
+            (Some(profile), Some(canister_id)) if profile.personal_canister_id != Some(canister_id) => {
+                match delete_orphaned_canister(canister_id).await {
+                    Ok(()) => {
+                        release_lock(&storable_principal);
+                        report.deleted_canisters.push(canister_id);
+                    }
+                    Err(error) => report.errors.push(error),
+                }
+            }
+            _ => {
+                release_lock(&storable_principal);
+                report.released.push(principal);
+            }
+        }
+    }
+
+    report
+}
+
+#[update]
+async fn reconcile_pending_registrations() -> Result<ReconciliationReport, DeviteError> {
+    require_role(Role::Admin)?;
+    Ok(reconcile().await)
+}
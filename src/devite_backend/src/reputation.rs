@@ -0,0 +1,279 @@
+use candid::{CandidType, Deserialize, Principal, Encode, Decode};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable, BoundedStorable};
+use std::cell::RefCell;
+use std::borrow::Cow;
+
+use crate::access_control::{require_role, Role};
+use crate::error::DeviteError;
+use crate::validation::Validate;
+use crate::{Memory, StorablePrincipal, DAYS_TO_NANOSECONDS, MEMORY_MANAGER};
+
+type ReputationStorage = StableBTreeMap<StorablePrincipal, ReputationRecord, Memory>;
+type ReputationConfigCell = StableCell<ReputationConfig, Memory>;
+
+// REPUTATION TYPES
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq)]
+pub enum ContributionKind {
+    ResearchMinted,
+    ReviewWritten,
+    CitationReceived,
+    EndorsementReceived,
+    GovernanceParticipation,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ReputationConfig {
+    pub research_minted_weight: f64,
+    pub review_written_weight: f64,
+    pub citation_received_weight: f64,
+    pub endorsement_received_weight: f64,
+    pub governance_participation_weight: f64,
+    // Contributions lose half of their value after this many days
+    pub half_life_days: u64,
+}
+
+impl Default for ReputationConfig {
+    fn default() -> Self {
+        ReputationConfig {
+            research_minted_weight: 10.0,
+            review_written_weight: 5.0,
+            citation_received_weight: 3.0,
+            endorsement_received_weight: 2.0,
+            governance_participation_weight: 1.0,
+            half_life_days: 180,
+        }
+    }
+}
+
+impl Storable for ReputationConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl ReputationConfig {
+    fn weight(&self, kind: ContributionKind) -> f64 {
+        match kind {
+            ContributionKind::ResearchMinted => self.research_minted_weight,
+            ContributionKind::ReviewWritten => self.review_written_weight,
+            ContributionKind::CitationReceived => self.citation_received_weight,
+            ContributionKind::EndorsementReceived => self.endorsement_received_weight,
+            ContributionKind::GovernanceParticipation => self.governance_participation_weight,
+        }
+    }
+}
+
+// Contribution counts per kind, decayed to `updated_at`. Weights are applied
+// at read time so that changing the config re-weights every ledger entry.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct ReputationRecord {
+    pub research_minted: f64,
+    pub reviews_written: f64,
+    pub citations_received: f64,
+    pub endorsements_received: f64,
+    pub governance_participation: f64,
+    pub updated_at: u64,
+}
+
+impl Storable for ReputationRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ReputationRecord {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl ReputationRecord {
+    fn count_mut(&mut self, kind: ContributionKind) -> &mut f64 {
+        match kind {
+            ContributionKind::ResearchMinted => &mut self.research_minted,
+            ContributionKind::ReviewWritten => &mut self.reviews_written,
+            ContributionKind::CitationReceived => &mut self.citations_received,
+            ContributionKind::EndorsementReceived => &mut self.endorsements_received,
+            ContributionKind::GovernanceParticipation => &mut self.governance_participation,
+        }
+    }
+
+    fn decayed(mut self, now: u64, half_life_days: u64) -> Self {
+        let elapsed = now.saturating_sub(self.updated_at);
+        if elapsed > 0 && half_life_days > 0 {
+            let half_life = (half_life_days * DAYS_TO_NANOSECONDS) as f64;
+            let factor = 0.5f64.powf(elapsed as f64 / half_life);
+            self.research_minted *= factor;
+            self.reviews_written *= factor;
+            self.citations_received *= factor;
+            self.endorsements_received *= factor;
+            self.governance_participation *= factor;
+        }
+        self.updated_at = now;
+        self
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ReputationComponent {
+    pub kind: ContributionKind,
+    pub decayed_count: f64,
+    pub weight: f64,
+    pub points: f64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ReputationBreakdown {
+    pub principal: Principal,
+    pub components: Vec<ReputationComponent>,
+    pub total: f64,
+    pub computed_at: u64,
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Reputation (Memory ID 9, 10)
+    static REPUTATION_CONFIG: RefCell<ReputationConfigCell> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+            ReputationConfig::default(),
+        ).expect("Failed to initialize reputation config")
+    );
+
+    static REPUTATION_LEDGER: RefCell<ReputationStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+        )
+    );
+}
+
+// REPUTATION FUNCTIONS
+
+pub fn record_contribution(principal: Principal, kind: ContributionKind) {
+    let now = ic_cdk::api::time();
+    let half_life_days = REPUTATION_CONFIG.with(|config| config.borrow().get().half_life_days);
+    let storable_principal = StorablePrincipal::from(principal);
+
+    REPUTATION_LEDGER.with(|ledger| {
+        let mut record = ledger.borrow()
+            .get(&storable_principal)
+            .unwrap_or_default()
+            .decayed(now, half_life_days);
+        *record.count_mut(kind) += 1.0;
+        ledger.borrow_mut().insert(storable_principal, record);
+    });
+}
+
+// Takes back a contribution credited at `recorded_at`, as much of it as has
+// not decayed yet
+pub fn reverse_contribution(principal: Principal, kind: ContributionKind, recorded_at: u64) {
+    let now = ic_cdk::api::time();
+    let half_life_days = REPUTATION_CONFIG.with(|config| config.borrow().get().half_life_days);
+    let storable_principal = StorablePrincipal::from(principal);
+    let remaining = if half_life_days > 0 {
+        let half_life = (half_life_days * DAYS_TO_NANOSECONDS) as f64;
+        0.5f64.powf(now.saturating_sub(recorded_at) as f64 / half_life)
+    } else {
+        1.0
+    };
+
+    REPUTATION_LEDGER.with(|ledger| {
+        let Some(record) = ledger.borrow().get(&storable_principal) else {
+            return;
+        };
+        let mut record = record.decayed(now, half_life_days);
+        let count = record.count_mut(kind);
+        *count = (*count - remaining).max(0.0);
+        ledger.borrow_mut().insert(storable_principal, record);
+    });
+}
+
+pub fn reputation_score(principal: Principal) -> f64 {
+    get_reputation(principal).total
+}
+
+pub fn total_reputation() -> f64 {
+    let now = ic_cdk::api::time();
+    let config = REPUTATION_CONFIG.with(|config| config.borrow().get().clone());
+
+    REPUTATION_LEDGER.with(|ledger| {
+        ledger.borrow().iter()
+            .map(|(_, record)| score_components(&record.decayed(now, config.half_life_days), &config)
+                .iter()
+                .map(|component| component.points)
+                .sum::<f64>())
+            .sum()
+    })
+}
+
+fn score_components(record: &ReputationRecord, config: &ReputationConfig) -> Vec<ReputationComponent> {
+    [
+        (ContributionKind::ResearchMinted, record.research_minted),
+        (ContributionKind::ReviewWritten, record.reviews_written),
+        (ContributionKind::CitationReceived, record.citations_received),
+        (ContributionKind::EndorsementReceived, record.endorsements_received),
+        (ContributionKind::GovernanceParticipation, record.governance_participation),
+    ]
+    .into_iter()
+    .map(|(kind, decayed_count)| {
+        let weight = config.weight(kind);
+        ReputationComponent {
+            kind,
+            decayed_count,
+            weight,
+            points: decayed_count * weight,
+        }
+    })
+    .collect()
+}
+
+pub fn apply_reputation_config(config: ReputationConfig) {
+    REPUTATION_CONFIG.with(|cell| {
+        cell.borrow_mut().set(config).expect("Failed to store reputation config");
+    });
+}
+
+#[update]
+fn set_reputation_config(config: ReputationConfig) -> Result<(), DeviteError> {
+    require_role(Role::Admin)?;
+    config.validate()?;
+    apply_reputation_config(config);
+    Ok(())
+}
+
+#[query]
+fn get_reputation_config() -> ReputationConfig {
+    REPUTATION_CONFIG.with(|config| config.borrow().get().clone())
+}
+
+#[query]
+fn get_reputation(principal: Principal) -> ReputationBreakdown {
+    let now = ic_cdk::api::time();
+    let config = REPUTATION_CONFIG.with(|config| config.borrow().get().clone());
+    let record = REPUTATION_LEDGER.with(|ledger| {
+        ledger.borrow()
+            .get(&StorablePrincipal::from(principal))
+            .unwrap_or_default()
+    });
+
+    let components = score_components(&record.decayed(now, config.half_life_days), &config);
+    let total = components.iter().map(|component| component.points).sum();
+
+    ReputationBreakdown {
+        principal,
+        components,
+        total,
+        computed_at: now,
+    }
+}
@@ -0,0 +1,308 @@
+// Near-duplicate detection over research metadata. Every token gets a MinHash
+// signature of the word pairs in its title and description plus its keywords;
+// signatures are split into bands and tokens sharing any band bucket are
+// compared. Pairs with a Jaccard similarity around 0.5 or more are found
+// reliably, so thresholds much below that miss matches.
+
+use candid::{CandidType, Deserialize, Encode, Decode};
+use ic_cdk::query;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, Storable, BoundedStorable};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+
+use crate::error::{DeviteError, ResourceKind};
+use crate::{Memory, ResearchNFT, MEMORY_MANAGER, RESEARCH_TOKENS};
+
+type SignatureStorage = StableBTreeMap<u64, MinHashSignature, Memory>;
+// (bucket, token_id)
+type BucketIndex = StableBTreeMap<(u64, u64), (), Memory>;
+
+const NUM_HASHES: usize = 128;
+const BANDS: usize = 32;
+const ROWS_PER_BAND: usize = NUM_HASHES / BANDS;
+const MERSENNE_61: u64 = (1 << 61) - 1;
+const COEFFICIENT_SEED: u64 = 0x6465_7669_7465_6d68;
+// Mints this similar to existing research come back with a warning
+const WARNING_THRESHOLD: f64 = 0.8;
+const MAX_SIMILAR_RESULTS: usize = 50;
+
+// SIMILARITY TYPES
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct MinHashSignature(pub Vec<u32>);
+
+impl Storable for MinHashSignature {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&self.0).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        MinHashSignature(Decode!(bytes.as_ref(), Vec<u32>).unwrap())
+    }
+}
+
+impl BoundedStorable for MinHashSignature {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct SimilarResearch {
+    pub token_id: u64,
+    // Estimated Jaccard similarity of the metadata, from 0 to 1
+    pub similarity: f64,
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // MinHash signatures and their LSH buckets (Memory ID 28, 29)
+    static SIGNATURES: RefCell<SignatureStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))),
+        )
+    );
+
+    static BUCKETS: RefCell<BucketIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))),
+        )
+    );
+}
+
+// SIMILARITY FUNCTIONS
+
+fn hash64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(Sha256::digest(bytes)[..8].try_into().unwrap())
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// Coefficients (a, b) of the hash functions (a * x + b) mod 2^61 - 1. They are
+// derived from a fixed seed, so signatures stay comparable across upgrades.
+fn coefficients() -> Vec<(u64, u64)> {
+    let mut state = COEFFICIENT_SEED;
+    (0..NUM_HASHES)
+        .map(|_| {
+            let a = splitmix64(&mut state) % (MERSENNE_61 - 1) + 1;
+            let b = splitmix64(&mut state) % MERSENNE_61;
+            (a, b)
+        })
+        .collect()
+}
+
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+// Adjacent word pairs survive small edits better than whole sentences and
+// still tell apart texts that share a vocabulary
+fn shingles(token: &ResearchNFT) -> BTreeSet<String> {
+    let mut shingles = BTreeSet::new();
+    for text in [&token.title, &token.description] {
+        let words = words(text);
+        if words.len() == 1 {
+            shingles.insert(words[0].clone());
+        }
+        shingles.extend(words.windows(2).map(|pair| pair.join(" ")));
+    }
+    shingles.extend(token.metadata.keywords.iter().map(|keyword| format!("#{}", keyword.trim().to_lowercase())));
+    shingles
+}
+
+pub fn signature(token: &ResearchNFT) -> Option<MinHashSignature> {
+    let hashes: Vec<u64> = shingles(token).iter().map(|shingle| hash64(shingle.as_bytes()) % MERSENNE_61).collect();
+    if hashes.is_empty() {
+        return None;
+    }
+    let values = coefficients()
+        .into_iter()
+        .map(|(a, b)| {
+            hashes.iter()
+                .map(|&x| ((u128::from(a) * u128::from(x) + u128::from(b)) % u128::from(MERSENNE_61)) as u64)
+                .min()
+                .unwrap_or_default() as u32
+        })
+        .collect();
+    Some(MinHashSignature(values))
+}
+
+fn buckets(signature: &MinHashSignature) -> Vec<u64> {
+    signature.0
+        .chunks(ROWS_PER_BAND)
+        .enumerate()
+        .map(|(band, rows)| {
+            let mut bytes = (band as u32).to_le_bytes().to_vec();
+            rows.iter().for_each(|row| bytes.extend_from_slice(&row.to_le_bytes()));
+            hash64(&bytes)
+        })
+        .collect()
+}
+
+fn estimate(a: &MinHashSignature, b: &MinHashSignature) -> f64 {
+    let equal = a.0.iter().zip(&b.0).filter(|(x, y)| x == y).count();
+    equal as f64 / NUM_HASHES as f64
+}
+
+// Live tokens at least `threshold` similar to `signature`, most similar first
+fn similar_to(token_id: u64, signature: &MinHashSignature, threshold: f64) -> Vec<SimilarResearch> {
+    let candidates: BTreeSet<u64> = BUCKETS.with(|index| {
+        let index = index.borrow();
+        buckets(signature)
+            .into_iter()
+            .flat_map(|bucket| index.range((bucket, 0)..=(bucket, u64::MAX)).map(|((_, id), _)| id).collect::<Vec<_>>())
+            .filter(|&id| id != token_id)
+            .collect()
+    });
+
+    let mut similar: Vec<SimilarResearch> = candidates
+        .into_iter()
+        .filter(|id| RESEARCH_TOKENS.with(|tokens| tokens.borrow().get(id)).is_some_and(|token| token.retracted_at.is_none()))
+        .filter_map(|id| {
+            let other = SIGNATURES.with(|signatures| signatures.borrow().get(&id))?;
+            let similarity = estimate(signature, &other);
+            (similarity >= threshold).then_some(SimilarResearch { token_id: id, similarity })
+        })
+        .collect();
+    similar.sort_by(|a, b| b.similarity.total_cmp(&a.similarity).then(a.token_id.cmp(&b.token_id)));
+    similar.truncate(MAX_SIMILAR_RESULTS);
+    similar
+}
+
+// Indexes a newly minted token and returns the research it closely resembles
+pub fn index_token(token: &ResearchNFT) -> Vec<SimilarResearch> {
+    let Some(signature) = signature(token) else {
+        return Vec::new();
+    };
+    let similar = similar_to(token.token_id, &signature, WARNING_THRESHOLD);
+
+    BUCKETS.with(|index| {
+        let mut index = index.borrow_mut();
+        for bucket in buckets(&signature) {
+            index.insert((bucket, token.token_id), ());
+        }
+    });
+    SIGNATURES.with(|signatures| signatures.borrow_mut().insert(token.token_id, signature));
+    similar
+}
+
+// Tokens minted before signatures existed are indexed after the upgrade
+pub fn ensure_indexed() {
+    let unindexed: Vec<ResearchNFT> = RESEARCH_TOKENS.with(|tokens| {
+        tokens.borrow()
+            .iter()
+            .filter(|(token_id, _)| !SIGNATURES.with(|signatures| signatures.borrow().contains_key(token_id)))
+            .map(|(_, token)| token)
+            .collect()
+    });
+    for token in &unindexed {
+        index_token(token);
+    }
+}
+
+#[query]
+fn find_similar_research(token_id: u64, threshold: f64) -> Result<Vec<SimilarResearch>, DeviteError> {
+    if !(threshold > 0.0 && threshold <= 1.0) {
+        return Err(DeviteError::ValidationFailed {
+            field: "threshold".to_string(),
+            reason: "must be greater than 0 and at most 1".to_string(),
+        });
+    }
+    if !RESEARCH_TOKENS.with(|tokens| tokens.borrow().contains_key(&token_id)) {
+        return Err(DeviteError::not_found(ResourceKind::ResearchToken, token_id));
+    }
+    // Metadata without a single word has no signature and resembles nothing
+    let similar = SIGNATURES.with(|signatures| signatures.borrow().get(&token_id))
+        .map(|signature| similar_to(token_id, &signature, threshold))
+        .unwrap_or_default();
+    Ok(similar)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{License, ResearchMetadata, ResearchType};
+    use candid::Principal;
+
+    fn token(title: &str, description: &str, keywords: &[&str]) -> ResearchNFT {
+        ResearchNFT {
+            token_id: 1,
+            owner: Principal::anonymous(),
+            title: title.to_string(),
+            description: description.to_string(),
+            authors: Vec::new(),
+            research_type: ResearchType::Paper,
+            content_hash: String::new(),
+            content_multihash: None,
+            license: License {
+                license_type: "CC-BY-4.0".to_string(),
+                commercial_use: true,
+                attribution_required: true,
+            },
+            created_at: 0,
+            metadata: ResearchMetadata {
+                keywords: keywords.iter().map(|keyword| keyword.to_string()).collect(),
+                research_domain: String::new(),
+                institution: String::new(),
+                doi: None,
+                peer_reviewed: false,
+            },
+            retracted_at: None,
+            storage_location: None,
+        }
+    }
+
+    fn set(items: &[&str]) -> BTreeSet<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    #[test]
+    fn shingles_are_word_pairs_and_keywords() {
+        let found = shingles(&token("Deep Sea, Vents", "Microbes", &[" Ocean "]));
+        assert_eq!(found, set(&["deep sea", "sea vents", "microbes", "#ocean"]));
+        assert!(shingles(&token(" -- ", "", &[])).is_empty());
+    }
+
+    #[test]
+    fn signatures_are_deterministic() {
+        let a = token("Thermal vents host diverse microbes", "Sampled along the ridge", &["ocean"]);
+        let signature = signature(&a).unwrap();
+        assert_eq!(signature.0.len(), NUM_HASHES);
+        assert_eq!(super::signature(&a), Some(signature.clone()));
+        assert_eq!(buckets(&signature).len(), BANDS);
+        assert_eq!(estimate(&signature, &signature), 1.0);
+        assert_eq!(MinHashSignature::from_bytes(signature.to_bytes()), signature);
+    }
+
+    #[test]
+    fn metadata_without_words_has_no_signature() {
+        assert_eq!(signature(&token("", "?!", &[])), None);
+    }
+
+    #[test]
+    fn estimates_follow_jaccard_similarity() {
+        let text = |prefix: &str, range: std::ops::Range<u32>| {
+            range.map(|i| format!("{}{}", prefix, i)).collect::<Vec<_>>().join(" ")
+        };
+        let base = signature(&token(&text("w", 0..200), "", &[])).unwrap();
+        // Replacing the last quarter shares 149 of 249 distinct pairs, about 0.6
+        let edited = token(&format!("{} {}", text("w", 0..150), text("x", 0..50)), "", &[]);
+        let unrelated = token(&text("y", 0..200), "", &[]);
+
+        let close = estimate(&base, &signature(&edited).unwrap());
+        assert!((0.45..0.75).contains(&close), "estimated {}", close);
+        assert!(estimate(&base, &signature(&unrelated).unwrap()) < 0.1);
+    }
+}
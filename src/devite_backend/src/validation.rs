@@ -0,0 +1,455 @@
+use crate::claims::OpenDisputeRequest;
+use crate::content_hash::{ContentHash, HashAlgorithm};
+use crate::encryption::{KeyAlgorithm, RegisterEncryptionKeyRequest};
+use crate::error::{DeviteError, FieldViolation};
+use crate::fleet::RolloutConfig;
+use crate::health::TopUpPolicy;
+use crate::personal_storage::StorageQuota;
+use crate::quota::QuotaPolicy;
+use crate::reputation::ReputationConfig;
+use crate::{
+    CreateProposalRequest, CreateUserRequest, MintRequest, ProposalAction, ProposalType,
+    SubmitReviewRequest,
+};
+
+// Byte limits keep every stored record well inside its `MAX_SIZE` bound
+const USERNAME_LENGTH: (usize, usize) = (3, 32);
+const EMAIL_MAX_LENGTH: usize = 254;
+const INSTITUTION_MAX_LENGTH: usize = 128;
+const MAX_RESEARCH_DOMAINS: usize = 10;
+const RESEARCH_DOMAIN_MAX_LENGTH: usize = 64;
+const TITLE_MAX_LENGTH: usize = 200;
+const NFT_DESCRIPTION_MAX_LENGTH: usize = 1200;
+const PROPOSAL_DESCRIPTION_MAX_LENGTH: usize = 1500;
+const MAX_AUTHORS: usize = 10;
+const AUTHOR_MAX_LENGTH: usize = 80;
+const MAX_KEYWORDS: usize = 10;
+const KEYWORD_MAX_LENGTH: usize = 40;
+// Fits a base16 CIDv1 of a 64-byte digest
+const CONTENT_HASH_MAX_LENGTH: usize = 144;
+const LICENSE_TYPE_MAX_LENGTH: usize = 64;
+const DOI_MAX_LENGTH: usize = 128;
+// Item id limit of the personal storage canister
+const STORAGE_ITEM_ID_MAX_LENGTH: usize = 64;
+const REVIEW_SUMMARY_MAX_LENGTH: usize = 1500;
+const DISPUTE_REASON_MAX_LENGTH: usize = 1000;
+const VOTING_DURATION_DAYS: (u64, u64) = (1, 30);
+const MIN_BATCH_INTERVAL_SECS: u64 = 10;
+const MAX_UPGRADE_RETRIES: u32 = 10;
+// SPKI DER sizes of 2048 to 4096-bit RSA keys
+const RSA_PUBLIC_KEY_LENGTH: (usize, usize) = (290, 560);
+
+pub trait Validate {
+    fn validate(&self) -> Result<(), DeviteError>;
+}
+
+// Collects every violation so callers can fix a request in one round trip
+#[derive(Default)]
+struct Validator {
+    violations: Vec<FieldViolation>,
+}
+
+impl Validator {
+    fn check(&mut self, field: &str, ok: bool, reason: impl Into<String>) -> &mut Self {
+        if !ok {
+            self.violations.push(FieldViolation {
+                field: field.to_string(),
+                reason: reason.into(),
+            });
+        }
+        self
+    }
+
+    fn length(&mut self, field: &str, value: &str, min: usize, max: usize) -> &mut Self {
+        let len = value.trim().len();
+        if min > 0 && len == 0 {
+            self.check(field, false, "must not be empty")
+        } else {
+            self.check(
+                field,
+                len >= min && value.len() <= max,
+                format!("must be between {} and {} bytes", min, max),
+            )
+        }
+    }
+
+    // Free text that may span several lines, such as descriptions
+    fn text(&mut self, field: &str, value: &str, min: usize, max: usize) -> &mut Self {
+        self.length(field, value, min, max).check(
+            field,
+            value.chars().all(|c| !c.is_control() || c == '\n' || c == '\t'),
+            "must not contain control characters",
+        )
+    }
+
+    fn single_line(&mut self, field: &str, value: &str, min: usize, max: usize) -> &mut Self {
+        self.length(field, value, min, max).check(
+            field,
+            !value.chars().any(char::is_control),
+            "must be a single line without control characters",
+        )
+    }
+
+    fn list(&mut self, field: &str, values: &[String], min: usize, max: usize, item_max: usize) -> &mut Self {
+        self.check(
+            field,
+            values.len() >= min && values.len() <= max,
+            format!("must contain between {} and {} entries", min, max),
+        );
+        for (index, value) in values.iter().enumerate() {
+            self.single_line(&format!("{}[{}]", field, index), value, 1, item_max);
+        }
+        let mut normalized: Vec<String> = values.iter().map(|v| v.trim().to_lowercase()).collect();
+        normalized.sort();
+        normalized.dedup();
+        self.check(field, normalized.len() == values.len(), "must not contain duplicates")
+    }
+
+    fn finish(&mut self) -> Result<(), DeviteError> {
+        if self.violations.is_empty() {
+            Ok(())
+        } else {
+            Err(DeviteError::InvalidRequest {
+                violations: std::mem::take(&mut self.violations),
+            })
+        }
+    }
+}
+
+fn is_valid_email(email: &str) -> bool {
+    let (local, domain) = match email.split_once('@') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    !local.is_empty()
+        && local.len() <= 64
+        && !domain.contains('@')
+        && domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+        && email.chars().all(|c| c.is_ascii_graphic())
+}
+
+impl Validate for CreateUserRequest {
+    fn validate(&self) -> Result<(), DeviteError> {
+        Validator::default()
+            .length("username", &self.username, USERNAME_LENGTH.0, USERNAME_LENGTH.1)
+            .check(
+                "username",
+                self.username.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')),
+                "may only contain letters, digits, '_', '-' and '.'",
+            )
+            .length("email", &self.email, 3, EMAIL_MAX_LENGTH)
+            .check("email", is_valid_email(&self.email), "must be a valid email address")
+            .single_line("institution", &self.institution, 1, INSTITUTION_MAX_LENGTH)
+            .list("research_domains", &self.research_domains, 0, MAX_RESEARCH_DOMAINS, RESEARCH_DOMAIN_MAX_LENGTH)
+            .finish()
+    }
+}
+
+impl Validate for MintRequest {
+    fn validate(&self) -> Result<(), DeviteError> {
+        let mut validator = Validator::default();
+        validator
+            .single_line("title", &self.title, 1, TITLE_MAX_LENGTH)
+            .text("description", &self.description, 1, NFT_DESCRIPTION_MAX_LENGTH)
+            .list("authors", &self.authors, 1, MAX_AUTHORS, AUTHOR_MAX_LENGTH)
+            .length("content_hash", &self.content_hash, 1, CONTENT_HASH_MAX_LENGTH)
+            .single_line("license.license_type", &self.license.license_type, 1, LICENSE_TYPE_MAX_LENGTH)
+            .list("metadata.keywords", &self.metadata.keywords, 0, MAX_KEYWORDS, KEYWORD_MAX_LENGTH)
+            .single_line("metadata.research_domain", &self.metadata.research_domain, 1, RESEARCH_DOMAIN_MAX_LENGTH)
+            .single_line("metadata.institution", &self.metadata.institution, 0, INSTITUTION_MAX_LENGTH);
+
+        let content_hash = ContentHash::parse(&self.content_hash);
+        if let Err(reason) = &content_hash {
+            validator.check("content_hash", false, reason.clone());
+        }
+
+        if let Some(item_id) = &self.storage_item {
+            validator
+                .length("storage_item", item_id, 1, STORAGE_ITEM_ID_MAX_LENGTH)
+                .check(
+                    "content_hash",
+                    content_hash.is_err() || content_hash.is_ok_and(|hash| hash.algorithm == HashAlgorithm::Sha2_256),
+                    "must be a SHA-256 hash when minting from personal storage",
+                );
+        }
+
+        if let Some(doi) = &self.metadata.doi {
+            validator
+                .length("metadata.doi", doi, 1, DOI_MAX_LENGTH)
+                .check(
+                    "metadata.doi",
+                    doi.starts_with("10.") && doi.contains('/') && !doi.chars().any(char::is_whitespace),
+                    "must look like 10.<registrant>/<suffix>",
+                );
+        }
+
+        validator.finish()
+    }
+}
+
+impl Validate for CreateProposalRequest {
+    fn validate(&self) -> Result<(), DeviteError> {
+        let mut validator = Validator::default();
+        validator
+            .single_line("title", &self.title, 1, TITLE_MAX_LENGTH)
+            .text("description", &self.description, 1, PROPOSAL_DESCRIPTION_MAX_LENGTH)
+            .check(
+                "voting_duration_days",
+                (VOTING_DURATION_DAYS.0..=VOTING_DURATION_DAYS.1).contains(&self.voting_duration_days),
+                format!("must be between {} and {} days", VOTING_DURATION_DAYS.0, VOTING_DURATION_DAYS.1),
+            );
+
+        // Actions must be filed under the proposal type that governs them
+        if let Some(action) = &self.action {
+            let expected_type = match action {
+                ProposalAction::GrantRole { .. }
+                | ProposalAction::RevokeRole { .. }
+                | ProposalAction::UpdateReputationConfig(_)
+                | ProposalAction::SetDefaultStorageTier(_) => ProposalType::GovernanceChange,
+                ProposalAction::PausePersonalStorageRollout
+                | ProposalAction::ResumePersonalStorageRollout => ProposalType::PlatformUpgrade,
+            };
+            validator.check(
+                "action",
+                self.proposal_type == expected_type,
+                format!("requires proposal_type {:?}", expected_type),
+            );
+
+            if let ProposalAction::UpdateReputationConfig(config) = action {
+                validator.violations.extend(reputation_config_violations(config));
+            }
+            if let ProposalAction::SetDefaultStorageTier(tier) = action {
+                validator.violations.extend(storage_quota_violations(tier));
+            }
+        }
+
+        validator.finish()
+    }
+}
+
+impl Validate for SubmitReviewRequest {
+    fn validate(&self) -> Result<(), DeviteError> {
+        Validator::default()
+            .text("summary", &self.summary, 1, REVIEW_SUMMARY_MAX_LENGTH)
+            .finish()
+    }
+}
+
+impl Validate for OpenDisputeRequest {
+    fn validate(&self) -> Result<(), DeviteError> {
+        Validator::default()
+            .text("reason", &self.reason, 1, DISPUTE_REASON_MAX_LENGTH)
+            .finish()
+    }
+}
+
+impl Validate for ReputationConfig {
+    fn validate(&self) -> Result<(), DeviteError> {
+        Validator {
+            violations: reputation_config_violations(self),
+        }
+        .finish()
+    }
+}
+
+impl Validate for RolloutConfig {
+    fn validate(&self) -> Result<(), DeviteError> {
+        Validator::default()
+            .check("canary_percent", (1..=100).contains(&self.canary_percent), "must be between 1 and 100")
+            .check("batch_size", self.batch_size > 0, "must be at least 1")
+            .check(
+                "batch_interval_secs",
+                self.batch_interval_secs >= MIN_BATCH_INTERVAL_SECS,
+                format!("must be at least {} seconds", MIN_BATCH_INTERVAL_SECS),
+            )
+            .check(
+                "max_retries",
+                (1..=MAX_UPGRADE_RETRIES).contains(&self.max_retries),
+                format!("must be between 1 and {}", MAX_UPGRADE_RETRIES),
+            )
+            .finish()
+    }
+}
+
+impl Validate for TopUpPolicy {
+    fn validate(&self) -> Result<(), DeviteError> {
+        Validator::default()
+            .check("threshold_cycles", self.threshold_cycles > 0, "must be greater than zero")
+            .check("top_up_cycles", self.top_up_cycles > 0, "must be greater than zero")
+            .check(
+                "per_user_limit_cycles",
+                self.per_user_limit_cycles >= self.top_up_cycles,
+                "must allow at least one top-up",
+            )
+            .check("limit_window_days", self.limit_window_days > 0, "must be at least one day")
+            .finish()
+    }
+}
+
+impl Validate for QuotaPolicy {
+    fn validate(&self) -> Result<(), DeviteError> {
+        let mut validator = Validator {
+            violations: storage_quota_violations(&self.default_tier),
+        };
+        validator
+            .check("cycles_per_mib", self.cycles_per_mib > 0, "must be greater than zero")
+            .check("tokens_per_mib", self.tokens_per_mib > 0, "must be greater than zero");
+        validator.finish()
+    }
+}
+
+impl Validate for RegisterEncryptionKeyRequest {
+    fn validate(&self) -> Result<(), DeviteError> {
+        let key = &self.public_key;
+        let (ok, reason) = match self.algorithm {
+            KeyAlgorithm::X25519 => (key.len() == 32, "must be a 32-byte X25519 key"),
+            KeyAlgorithm::EcdhP256 => (
+                (key.len() == 65 && key[0] == 0x04) || (key.len() == 33 && matches!(key[0], 0x02 | 0x03)),
+                "must be a compressed or uncompressed SEC1 P-256 point",
+            ),
+            KeyAlgorithm::RsaOaepSha256 => (
+                (RSA_PUBLIC_KEY_LENGTH.0..=RSA_PUBLIC_KEY_LENGTH.1).contains(&key.len()),
+                "must be a DER-encoded SPKI RSA key of 2048 to 4096 bits",
+            ),
+        };
+        Validator::default().check("public_key", ok, reason).finish()
+    }
+}
+
+fn storage_quota_violations(quota: &StorageQuota) -> Vec<FieldViolation> {
+    let mut validator = Validator::default();
+    validator
+        .check("max_bytes", quota.max_bytes > 0, "must be greater than zero")
+        .check("max_items", quota.max_items > 0, "must be greater than zero");
+    validator.violations
+}
+
+fn reputation_config_violations(config: &ReputationConfig) -> Vec<FieldViolation> {
+    let mut validator = Validator::default();
+    for (field, weight) in [
+        ("research_minted_weight", config.research_minted_weight),
+        ("review_written_weight", config.review_written_weight),
+        ("citation_received_weight", config.citation_received_weight),
+        ("endorsement_received_weight", config.endorsement_received_weight),
+        ("governance_participation_weight", config.governance_participation_weight),
+    ] {
+        validator.check(field, weight.is_finite() && weight >= 0.0, "must be a finite, non-negative number");
+    }
+    validator.check("half_life_days", config.half_life_days > 0, "must be at least one day");
+    validator.violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{License, ResearchMetadata, ResearchType};
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    fn violated_fields(result: Result<(), DeviteError>) -> Vec<String> {
+        match result {
+            Ok(()) => Vec::new(),
+            Err(DeviteError::InvalidRequest { violations }) => {
+                violations.into_iter().map(|violation| violation.field).collect()
+            }
+            Err(error) => panic!("unexpected error {:?}", error),
+        }
+    }
+
+    fn user() -> CreateUserRequest {
+        CreateUserRequest {
+            username: "ada.lovelace".to_string(),
+            email: "ada@example.org".to_string(),
+            institution: "Analytical Engine Society".to_string(),
+            research_domains: strings(&["mathematics", "computing"]),
+        }
+    }
+
+    fn mint() -> MintRequest {
+        MintRequest {
+            title: "Notes on the Analytical Engine".to_string(),
+            description: "Translation with notes.\nIncludes the first published algorithm.".to_string(),
+            authors: strings(&["Ada Lovelace"]),
+            research_type: ResearchType::Paper,
+            content_hash: "1220823970a70ee43c9ad43372e62a17ad717e9231e3aeab7021f5fa902096edf5c4".to_string(),
+            license: License {
+                license_type: "CC-BY-4.0".to_string(),
+                commercial_use: true,
+                attribution_required: true,
+            },
+            metadata: ResearchMetadata {
+                keywords: strings(&["algorithms"]),
+                research_domain: "computing".to_string(),
+                institution: String::new(),
+                doi: Some("10.1000/182".to_string()),
+                peer_reviewed: false,
+            },
+            storage_item: None,
+        }
+    }
+
+    #[test]
+    fn accepts_valid_requests() {
+        assert_eq!(user().validate(), Ok(()));
+        assert_eq!(mint().validate(), Ok(()));
+    }
+
+    #[test]
+    fn reports_every_violation_at_once() {
+        let request = CreateUserRequest {
+            username: "a b".to_string(),
+            email: "ada@@example".to_string(),
+            institution: "line\nbreak".to_string(),
+            research_domains: strings(&["Physics", " physics "]),
+        };
+        assert_eq!(violated_fields(request.validate()), ["username", "email", "institution", "research_domains"]);
+    }
+
+    #[test]
+    fn checks_email_addresses() {
+        for email in ["ada@example.org", "a.b+c@sub.example-domain.io"] {
+            assert!(is_valid_email(email), "rejected {}", email);
+        }
+        for email in ["ada", "@example.org", "ada@example", "ada@-example.org", "ada@example..org", "ada lovelace@example.org"] {
+            assert!(!is_valid_email(email), "accepted {}", email);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_mints() {
+        let mut request = mint();
+        request.title = "   ".to_string();
+        request.authors = Vec::new();
+        request.content_hash = "not a hash".to_string();
+        request.metadata.doi = Some("doi:10.1000/182".to_string());
+        assert_eq!(violated_fields(request.validate()), ["title", "authors", "content_hash", "metadata.doi"]);
+    }
+
+    #[test]
+    fn storage_mints_need_sha256() {
+        let mut request = mint();
+        request.storage_item = Some("notes".to_string());
+        assert_eq!(request.validate(), Ok(()));
+
+        // SHA-512 multihash
+        request.content_hash = format!("1340{}", "ab".repeat(64));
+        assert_eq!(violated_fields(request.validate()), ["content_hash"]);
+    }
+
+    #[test]
+    fn checks_encryption_key_shapes() {
+        let key = |algorithm, public_key: Vec<u8>| RegisterEncryptionKeyRequest { algorithm, public_key };
+        assert_eq!(key(KeyAlgorithm::X25519, vec![9; 32]).validate(), Ok(()));
+        assert_eq!(key(KeyAlgorithm::EcdhP256, [vec![0x02], vec![1; 32]].concat()).validate(), Ok(()));
+        assert_eq!(violated_fields(key(KeyAlgorithm::EcdhP256, vec![0x04; 33]).validate()), ["public_key"]);
+        assert_eq!(violated_fields(key(KeyAlgorithm::RsaOaepSha256, vec![0; 64]).validate()), ["public_key"]);
+    }
+}
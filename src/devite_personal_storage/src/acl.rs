@@ -0,0 +1,464 @@
+use candid::{CandidType, Deserialize, Principal, Encode, Decode};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, Storable, BoundedStorable};
+use std::cell::RefCell;
+use std::borrow::Cow;
+
+use crate::archive;
+use crate::{
+    get_record, invalid, owner, require_owner, ItemMetadata, ItemRecord, Memory, StorableString,
+    StorageError, ITEMS, MAX_TAG_LENGTH, MEMORY_MANAGER,
+};
+
+type GrantStorage = StableBTreeMap<GrantKey, AccessGrant, Memory>;
+type GroupStorage = StableBTreeMap<StorableString, Group, Memory>;
+
+const MAX_GROUP_NAME_LENGTH: usize = 32;
+const MAX_GROUP_MEMBERS: usize = 100;
+const MAX_GRANTS_PER_SCOPE: usize = 100;
+
+// ACCESS CONTROL TYPES
+
+// Each level includes the ones below it
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    Read,
+    Write,
+    // Delete the item and manage who can access it
+    Admin,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AccessScope {
+    Item(String),
+    // Every item currently carrying the tag
+    Tag(String),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Grantee {
+    User(Principal),
+    Group(String),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GrantKey {
+    pub scope: AccessScope,
+    pub grantee: Grantee,
+}
+
+impl Storable for GrantKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for GrantKey {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AccessGrant {
+    pub scope: AccessScope,
+    pub grantee: Grantee,
+    pub permission: Permission,
+    // Grants past this time are ignored
+    pub expires_at: Option<u64>,
+    pub granted_by: Principal,
+    pub granted_at: u64,
+}
+
+impl Storable for AccessGrant {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AccessGrant {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct GrantRequest {
+    pub scope: AccessScope,
+    pub grantee: Grantee,
+    pub permission: Permission,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Group {
+    pub name: String,
+    pub members: Vec<Principal>,
+    pub updated_at: u64,
+}
+
+impl Storable for Group {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Group {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SharedItem {
+    pub record: ItemRecord,
+    pub permission: Permission,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ItemAccess {
+    pub id: String,
+    pub owner: Principal,
+    // Active grants on the item itself and on its tags
+    pub grants: Vec<AccessGrant>,
+    // Groups named by those grants
+    pub groups: Vec<Group>,
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Access grants and groups (Memory ID 9, 10)
+    pub(crate) static GRANTS: RefCell<GrantStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+        )
+    );
+
+    pub(crate) static GROUPS: RefCell<GroupStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+        )
+    );
+}
+
+// ACCESS CONTROL FUNCTIONS
+
+fn is_active(grant: &AccessGrant, now: u64) -> bool {
+    grant.expires_at.is_none_or(|expires_at| expires_at > now)
+}
+
+fn get_group(name: &str) -> Option<Group> {
+    GROUPS.with(|groups| groups.borrow().get(&StorableString::from(name.to_string())))
+}
+
+fn groups_of(principal: Principal) -> Vec<String> {
+    GROUPS.with(|groups| {
+        groups.borrow().iter()
+            .filter(|(_, group)| group.members.contains(&principal))
+            .map(|(_, group)| group.name)
+            .collect()
+    })
+}
+
+fn scope_grants(scope: &AccessScope) -> Vec<AccessGrant> {
+    // The management canister id is empty, so this is the smallest grantee
+    let start = GrantKey {
+        scope: scope.clone(),
+        grantee: Grantee::User(Principal::management_canister()),
+    };
+    GRANTS.with(|grants| {
+        grants.borrow().range(start..)
+            .take_while(|(key, _)| key.scope == *scope)
+            .map(|(_, grant)| grant)
+            .collect()
+    })
+}
+
+fn remove_grants(keys: Vec<GrantKey>) {
+    GRANTS.with(|grants| {
+        let mut grants = grants.borrow_mut();
+        for key in keys {
+            grants.remove(&key);
+        }
+    });
+}
+
+fn prune_expired(scope: &AccessScope) {
+    let now = ic_cdk::api::time();
+    let expired = scope_grants(scope).into_iter()
+        .filter(|grant| !is_active(grant, now))
+        .map(|grant| GrantKey {
+            scope: grant.scope,
+            grantee: grant.grantee,
+        })
+        .collect();
+    remove_grants(expired);
+}
+
+fn item_grants(record: &ItemRecord) -> Vec<AccessGrant> {
+    let mut grants = scope_grants(&AccessScope::Item(record.id.clone()));
+    for tag in &record.metadata.tags {
+        grants.extend(scope_grants(&AccessScope::Tag(tag.clone())));
+    }
+
+    let now = ic_cdk::api::time();
+    grants.retain(|grant| is_active(grant, now));
+    grants
+}
+
+fn granted_permission(principal: Principal, record: &ItemRecord) -> Option<Permission> {
+    let groups = groups_of(principal);
+    item_grants(record).into_iter()
+        .filter(|grant| match &grant.grantee {
+            Grantee::User(user) => *user == principal,
+            Grantee::Group(name) => groups.contains(name),
+        })
+        .map(|grant| grant.permission)
+        .max()
+}
+
+pub fn permission_of(principal: Principal, record: &ItemRecord) -> Option<Permission> {
+    if principal == owner() {
+        Some(Permission::Admin)
+    } else {
+        granted_permission(principal, record)
+    }
+}
+
+// Everyone who can currently read an item, with groups expanded
+pub fn readers(record: &ItemRecord) -> Vec<Principal> {
+    let mut readers = vec![owner()];
+    for grant in item_grants(record) {
+        match grant.grantee {
+            Grantee::User(user) => readers.push(user),
+            Grantee::Group(name) => readers.extend(get_group(&name).map(|group| group.members).unwrap_or_default()),
+        }
+    }
+    readers.sort();
+    readers.dedup();
+    readers
+}
+
+fn access_denied(id: &str, required: Permission) -> StorageError {
+    StorageError::AccessDenied {
+        id: id.to_string(),
+        required,
+    }
+}
+
+// Loads an item the caller holds at least `required` on
+pub fn authorize_item(id: &str, required: Permission) -> Result<ItemRecord, StorageError> {
+    let record = get_record(id)?;
+    match permission_of(ic_cdk::api::caller(), &record) {
+        Some(permission) if permission >= required => Ok(record),
+        _ => Err(access_denied(id, required)),
+    }
+}
+
+// Tags decide who else can reach an item, so changing them takes Admin
+pub fn authorize_metadata_change(record: &ItemRecord, metadata: &ItemMetadata) -> Result<(), StorageError> {
+    if record.metadata.tags == metadata.tags {
+        return Ok(());
+    }
+    match permission_of(ic_cdk::api::caller(), record) {
+        Some(Permission::Admin) => Ok(()),
+        _ => Err(access_denied(&record.id, Permission::Admin)),
+    }
+}
+
+pub fn remove_item_grants(item_id: &str) {
+    let keys = scope_grants(&AccessScope::Item(item_id.to_string())).into_iter()
+        .map(|grant| GrantKey {
+            scope: grant.scope,
+            grantee: grant.grantee,
+        })
+        .collect();
+    remove_grants(keys);
+}
+
+fn validate_group_name(name: &str) -> Result<(), StorageError> {
+    if name.is_empty() || name.len() > MAX_GROUP_NAME_LENGTH {
+        return Err(invalid("name", format!("must be between 1 and {} bytes", MAX_GROUP_NAME_LENGTH)));
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+        return Err(invalid("name", "may only contain letters, digits, '_', '-' and '.'"));
+    }
+    Ok(())
+}
+
+fn require_scope_admin(scope: &AccessScope) -> Result<(), StorageError> {
+    match scope {
+        AccessScope::Item(id) => authorize_item(id, Permission::Admin).map(|_| ()),
+        // A tag can cover items the caller does not administer
+        AccessScope::Tag(tag) => {
+            require_owner()?;
+            if tag.is_empty() || tag.len() > MAX_TAG_LENGTH {
+                return Err(invalid("scope", format!("tag must be between 1 and {} bytes", MAX_TAG_LENGTH)));
+            }
+            Ok(())
+        }
+    }
+}
+
+fn validate_grantee(grantee: &Grantee) -> Result<(), StorageError> {
+    match grantee {
+        Grantee::User(user) if *user == Principal::anonymous() => {
+            Err(invalid("grantee", "cannot be the anonymous principal"))
+        }
+        Grantee::User(user) if *user == owner() => {
+            Err(invalid("grantee", "the owner already has full access"))
+        }
+        Grantee::User(_) => Ok(()),
+        Grantee::Group(name) => match get_group(name) {
+            Some(_) => Ok(()),
+            None => Err(StorageError::GroupNotFound { name: name.clone() }),
+        },
+    }
+}
+
+#[update]
+fn grant_access(request: GrantRequest) -> Result<AccessGrant, StorageError> {
+    archive::ensure_writable()?;
+    require_scope_admin(&request.scope)?;
+    validate_grantee(&request.grantee)?;
+
+    let now = ic_cdk::api::time();
+    if request.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(invalid("expires_at", "must be in the future"));
+    }
+
+    prune_expired(&request.scope);
+    let key = GrantKey {
+        scope: request.scope.clone(),
+        grantee: request.grantee.clone(),
+    };
+    let replaces = GRANTS.with(|grants| grants.borrow().contains_key(&key));
+    if !replaces && scope_grants(&request.scope).len() >= MAX_GRANTS_PER_SCOPE {
+        return Err(invalid("scope", format!("may have at most {} grants", MAX_GRANTS_PER_SCOPE)));
+    }
+
+    let grant = AccessGrant {
+        scope: request.scope,
+        grantee: request.grantee,
+        permission: request.permission,
+        expires_at: request.expires_at,
+        granted_by: ic_cdk::api::caller(),
+        granted_at: now,
+    };
+    GRANTS.with(|grants| grants.borrow_mut().insert(key, grant.clone()));
+    Ok(grant)
+}
+
+#[update]
+fn revoke_access(scope: AccessScope, grantee: Grantee) -> Result<(), StorageError> {
+    archive::ensure_writable()?;
+    require_scope_admin(&scope)?;
+    GRANTS.with(|grants| grants.borrow_mut().remove(&GrantKey { scope, grantee }));
+    Ok(())
+}
+
+#[query]
+fn get_item_access(id: String) -> Result<ItemAccess, StorageError> {
+    let record = authorize_item(&id, Permission::Admin)?;
+    let grants = item_grants(&record);
+
+    let mut groups: Vec<Group> = Vec::new();
+    for grant in &grants {
+        if let Grantee::Group(name) = &grant.grantee {
+            if groups.iter().all(|group| group.name != *name) {
+                groups.extend(get_group(name));
+            }
+        }
+    }
+
+    Ok(ItemAccess {
+        id: record.id,
+        owner: owner(),
+        grants,
+        groups,
+    })
+}
+
+#[query]
+fn list_grants() -> Result<Vec<AccessGrant>, StorageError> {
+    require_owner()?;
+    Ok(GRANTS.with(|grants| grants.borrow().iter().map(|(_, grant)| grant).collect()))
+}
+
+#[query]
+fn list_shared_with_me() -> Vec<SharedItem> {
+    let caller = ic_cdk::api::caller();
+    if caller == owner() {
+        return Vec::new();
+    }
+
+    ITEMS.with(|items| {
+        items.borrow().iter()
+            .filter_map(|(_, record)| {
+                granted_permission(caller, &record).map(|permission| SharedItem { record, permission })
+            })
+            .collect()
+    })
+}
+
+#[update]
+fn set_group(name: String, members: Vec<Principal>) -> Result<Group, StorageError> {
+    archive::ensure_writable()?;
+    require_owner()?;
+    validate_group_name(&name)?;
+    if members.len() > MAX_GROUP_MEMBERS {
+        return Err(invalid("members", format!("must contain at most {} principals", MAX_GROUP_MEMBERS)));
+    }
+    if members.contains(&Principal::anonymous()) {
+        return Err(invalid("members", "cannot contain the anonymous principal"));
+    }
+
+    let mut members = members;
+    members.sort();
+    members.dedup();
+    let group = Group {
+        name: name.clone(),
+        members,
+        updated_at: ic_cdk::api::time(),
+    };
+    GROUPS.with(|groups| groups.borrow_mut().insert(StorableString::from(name), group.clone()));
+    Ok(group)
+}
+
+#[update]
+fn delete_group(name: String) -> Result<(), StorageError> {
+    archive::ensure_writable()?;
+    require_owner()?;
+    let removed = GROUPS.with(|groups| groups.borrow_mut().remove(&StorableString::from(name.clone())));
+    if removed.is_none() {
+        return Err(StorageError::GroupNotFound { name });
+    }
+
+    // Grants to the group would otherwise apply to a future group of the same name
+    let grantee = Grantee::Group(name);
+    let keys = GRANTS.with(|grants| {
+        grants.borrow().iter()
+            .filter(|(key, _)| key.grantee == grantee)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    remove_grants(keys);
+    Ok(())
+}
+
+#[query]
+fn list_groups() -> Result<Vec<Group>, StorageError> {
+    require_owner()?;
+    Ok(GROUPS.with(|groups| groups.borrow().iter().map(|(_, group)| group).collect()))
+}
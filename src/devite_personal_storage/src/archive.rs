@@ -0,0 +1,736 @@
+// Portable archive of everything a personal canister stores.
+//
+// Layout:
+//   magic      8 bytes, "DVARCHV1"
+//   entries    repeated: u32 little-endian length, then one Candid-encoded
+//              `ArchiveEntry` of that length
+//   trailer    32 bytes, SHA-256 of every byte before it
+//
+// Entries appear in this order: one `Header`, then items, content chunks,
+// revisions, the retention policy, groups, grants, item keys and envelopes,
+// and finally one `End` carrying the number of entries before it. Chunks of
+// every content version are included, so all revisions stay readable.
+// Capability links are not exported; their URLs name the source canister.
+
+use candid::{CandidType, Deserialize, Principal, Encode, Decode};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable, BoundedStorable};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::borrow::Cow;
+
+use crate::acl::{AccessGrant, GrantKey, Group, GRANTS, GROUPS};
+use crate::keys::{EnvelopeKey, ItemKey, ItemKeyId, KeyEnvelope, ENVELOPES, ITEM_KEYS};
+use crate::revisions::{RetentionPolicy, Revision, RevisionKey, RETENTION, REVISIONS};
+use crate::transfer::{self, MAX_TRANSFER_SIZE};
+use crate::usage;
+use crate::{
+    check_quota, invalid, owner, read_chunk, require_owner, search, validate_item_id, write_chunk, ChunkKey,
+    ItemRecord, Memory, StorableString, StorageError, CHUNKS, ITEMS, MANIFESTS, MEMORY_MANAGER,
+};
+
+type ImportCell = StableCell<ImportState, Memory>;
+
+const MAGIC: &[u8; 8] = b"DVARCHV1";
+const FORMAT_VERSION: u32 = 1;
+const TRAILER_LENGTH: u64 = 32;
+// Writes stay blocked at most this long if an export is never finished
+const EXPORT_LIFETIME: u64 = 6 * 60 * 60 * 1_000_000_000;
+// Archive bytes encoded and hashed by one begin_export call
+const MAX_BYTES_HASHED_PER_CALL: u64 = 256 * crate::CHUNK_SIZE as u64;
+
+// ARCHIVE TYPES
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum ArchiveEntry {
+    Header {
+        format_version: u32,
+        source_canister: Principal,
+        owner: Principal,
+        exported_at: u64,
+    },
+    Item(ItemRecord),
+    Chunk { key: ChunkKey, data: Vec<u8> },
+    Revision(Revision),
+    Retention(RetentionPolicy),
+    Group(Group),
+    Grant(AccessGrant),
+    ItemKey(ItemKey),
+    Envelope(KeyEnvelope),
+    End { entries: u64 },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ExportInfo {
+    pub total_size: u64,
+    // Digest stored in the trailer
+    pub sha256: Vec<u8>,
+    pub entries: u64,
+    pub started_at: u64,
+    pub expires_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ImportStatus {
+    pub total_size: u64,
+    pub received_bytes: u64,
+    pub entries_applied: u64,
+    pub started_at: u64,
+    pub last_activity_at: u64,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct ImportState {
+    current: Option<ImportStatus>,
+}
+
+impl Storable for ImportState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Where an entry's data lives, so export pages can be rebuilt on demand
+#[derive(Clone, Debug)]
+enum EntryRef {
+    Header,
+    Item(StorableString),
+    Chunk(ChunkKey),
+    Revision(RevisionKey),
+    Retention,
+    Group(StorableString),
+    Grant(GrantKey),
+    ItemKey(ItemKeyId),
+    Envelope(EnvelopeKey),
+    End(u64),
+}
+
+enum ExportProgress {
+    // Entries are hashed in batches; `offset` is where the next one starts
+    Hashing { hasher: Sha256, offset: u64 },
+    Ready(ExportInfo),
+}
+
+struct ExportSession {
+    started_at: u64,
+    expires_at: u64,
+    entries: Vec<EntryRef>,
+    // Archive offset each hashed entry starts at
+    offsets: Vec<u64>,
+    progress: ExportProgress,
+}
+
+#[derive(Default)]
+struct ImportProgress {
+    hasher: Sha256,
+    // Bytes of an entry that is still incomplete
+    pending: Vec<u8>,
+    header_seen: bool,
+    end_seen: bool,
+    trailer: Vec<u8>,
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Import in progress (Memory ID 19)
+    static IMPORT: RefCell<ImportCell> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+            ImportState::default(),
+        ).expect("Failed to initialize import status")
+    );
+
+    // Lost on upgrade: an open export simply ends, and an interrupted import
+    // has to be aborted and started again
+    static EXPORT: RefCell<Option<ExportSession>> = const { RefCell::new(None) };
+    static IMPORT_PROGRESS: RefCell<Option<ImportProgress>> = const { RefCell::new(None) };
+}
+
+// ARCHIVE FUNCTIONS
+
+fn export_active(now: u64) -> bool {
+    EXPORT.with(|export| {
+        export.borrow().as_ref().is_some_and(|session| session.expires_at > now)
+    })
+}
+
+fn import_status() -> Option<ImportStatus> {
+    IMPORT.with(|cell| cell.borrow().get().current.clone())
+}
+
+fn set_import_status(status: Option<ImportStatus>) {
+    IMPORT.with(|cell| cell.borrow_mut().set(ImportState { current: status }).expect("Failed to store import status"));
+}
+
+// Everything an archive covers is read-only while one is being written or
+// restored
+pub fn ensure_writable() -> Result<(), StorageError> {
+    if export_active(ic_cdk::api::time()) || import_status().is_some() {
+        Err(StorageError::ArchiveInProgress)
+    } else {
+        Ok(())
+    }
+}
+
+fn frame(entry: &ArchiveEntry) -> Vec<u8> {
+    let encoded = Encode!(entry).unwrap();
+    let mut framed = Vec::with_capacity(4 + encoded.len());
+    framed.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+    framed.extend_from_slice(&encoded);
+    framed
+}
+
+fn keys_of<K: Storable + BoundedStorable + Ord + Clone, V: Storable + BoundedStorable>(
+    map: &StableBTreeMap<K, V, Memory>,
+) -> Vec<K> {
+    map.iter().map(|(key, _)| key).collect()
+}
+
+fn entry_refs() -> Vec<EntryRef> {
+    let mut refs = vec![EntryRef::Header];
+    refs.extend(ITEMS.with(|items| keys_of(&items.borrow())).into_iter().map(EntryRef::Item));
+    refs.extend(CHUNKS.with(|chunks| keys_of(&chunks.borrow())).into_iter().map(EntryRef::Chunk));
+    refs.extend(REVISIONS.with(|revisions| keys_of(&revisions.borrow())).into_iter().map(EntryRef::Revision));
+    refs.push(EntryRef::Retention);
+    refs.extend(GROUPS.with(|groups| keys_of(&groups.borrow())).into_iter().map(EntryRef::Group));
+    refs.extend(GRANTS.with(|grants| keys_of(&grants.borrow())).into_iter().map(EntryRef::Grant));
+    refs.extend(ITEM_KEYS.with(|keys| keys_of(&keys.borrow())).into_iter().map(EntryRef::ItemKey));
+    refs.extend(ENVELOPES.with(|envelopes| keys_of(&envelopes.borrow())).into_iter().map(EntryRef::Envelope));
+    refs.push(EntryRef::End(refs.len() as u64));
+    refs
+}
+
+fn load_entry(entry: &EntryRef, exported_at: u64) -> ArchiveEntry {
+    // Nothing can change while the export is open, so every key still resolves
+    const FROZEN: &str = "archived state changed during export";
+    match entry {
+        EntryRef::Header => ArchiveEntry::Header {
+            format_version: FORMAT_VERSION,
+            source_canister: ic_cdk::api::id(),
+            owner: owner(),
+            exported_at,
+        },
+        EntryRef::Item(id) => ArchiveEntry::Item(ITEMS.with(|items| items.borrow().get(id)).expect(FROZEN)),
+        EntryRef::Chunk(key) => ArchiveEntry::Chunk {
+            key: key.clone(),
+            data: read_chunk(&key.item_id, key.version, key.index).expect(FROZEN),
+        },
+        EntryRef::Revision(key) => {
+            ArchiveEntry::Revision(REVISIONS.with(|revisions| revisions.borrow().get(key)).expect(FROZEN))
+        }
+        EntryRef::Retention => ArchiveEntry::Retention(RETENTION.with(|cell| cell.borrow().get().clone())),
+        EntryRef::Group(name) => ArchiveEntry::Group(GROUPS.with(|groups| groups.borrow().get(name)).expect(FROZEN)),
+        EntryRef::Grant(key) => ArchiveEntry::Grant(GRANTS.with(|grants| grants.borrow().get(key)).expect(FROZEN)),
+        EntryRef::ItemKey(id) => ArchiveEntry::ItemKey(ITEM_KEYS.with(|keys| keys.borrow().get(id)).expect(FROZEN)),
+        EntryRef::Envelope(key) => {
+            ArchiveEntry::Envelope(ENVELOPES.with(|envelopes| envelopes.borrow().get(key)).expect(FROZEN))
+        }
+        EntryRef::End(entries) => ArchiveEntry::End { entries: *entries },
+    }
+}
+
+fn is_empty() -> bool {
+    ITEMS.with(|items| items.borrow().is_empty())
+        && CHUNKS.with(|chunks| chunks.borrow().is_empty())
+        && REVISIONS.with(|revisions| revisions.borrow().is_empty())
+        && GROUPS.with(|groups| groups.borrow().is_empty())
+        && GRANTS.with(|grants| grants.borrow().is_empty())
+        && ITEM_KEYS.with(|keys| keys.borrow().is_empty())
+        && ENVELOPES.with(|envelopes| envelopes.borrow().is_empty())
+}
+
+fn clear<K: Storable + BoundedStorable + Ord + Clone, V: Storable + BoundedStorable>(
+    map: &mut StableBTreeMap<K, V, Memory>,
+) {
+    for key in keys_of(map) {
+        map.remove(&key);
+    }
+}
+
+// Imports only ever go into an empty canister, so undoing one means
+// removing everything
+fn wipe() {
+    ITEMS.with(|items| clear(&mut items.borrow_mut()));
+    CHUNKS.with(|chunks| clear(&mut chunks.borrow_mut()));
+    MANIFESTS.with(|manifests| clear(&mut manifests.borrow_mut()));
+    REVISIONS.with(|revisions| clear(&mut revisions.borrow_mut()));
+    GROUPS.with(|groups| clear(&mut groups.borrow_mut()));
+    GRANTS.with(|grants| clear(&mut grants.borrow_mut()));
+    ITEM_KEYS.with(|keys| clear(&mut keys.borrow_mut()));
+    ENVELOPES.with(|envelopes| clear(&mut envelopes.borrow_mut()));
+    RETENTION.with(|cell| cell.borrow_mut().set(RetentionPolicy::default()).expect("Failed to store retention policy"));
+}
+
+fn archive_error(reason: impl Into<String>) -> StorageError {
+    StorageError::InvalidArchive { reason: reason.into() }
+}
+
+fn apply_entry(entry: ArchiveEntry, progress: &mut ImportProgress, applied: u64) -> Result<(), StorageError> {
+    if !progress.header_seen && !matches!(entry, ArchiveEntry::Header { .. }) {
+        return Err(archive_error("the first entry must be the header"));
+    }
+    if progress.end_seen {
+        return Err(archive_error("entries follow the end marker"));
+    }
+
+    match entry {
+        ArchiveEntry::Header { format_version, owner: archive_owner, .. } => {
+            if progress.header_seen {
+                return Err(archive_error("the header appears twice"));
+            }
+            if format_version != FORMAT_VERSION {
+                return Err(archive_error(format!("unsupported format version {}", format_version)));
+            }
+            // Envelopes and grants only make sense for the same owner
+            if archive_owner != owner() {
+                return Err(archive_error(format!("was exported by {}", archive_owner)));
+            }
+            progress.header_seen = true;
+        }
+        ArchiveEntry::Item(record) => {
+            validate_item_id(&record.id)?;
+            ITEMS.with(|items| items.borrow_mut().insert(StorableString::from(record.id.clone()), record));
+        }
+        ArchiveEntry::Chunk { key, data } => {
+            if data.len() > crate::CHUNK_SIZE {
+                return Err(archive_error("a chunk is larger than the chunk size"));
+            }
+            write_chunk(&key.item_id, key.version, key.index, &data);
+        }
+        ArchiveEntry::Revision(revision) => {
+            let key = RevisionKey {
+                item_id: revision.item_id.clone(),
+                number: revision.number,
+            };
+            REVISIONS.with(|revisions| revisions.borrow_mut().insert(key, revision));
+        }
+        ArchiveEntry::Retention(policy) => {
+            RETENTION.with(|cell| cell.borrow_mut().set(policy).expect("Failed to store retention policy"));
+        }
+        ArchiveEntry::Group(group) => {
+            GROUPS.with(|groups| groups.borrow_mut().insert(StorableString::from(group.name.clone()), group));
+        }
+        ArchiveEntry::Grant(grant) => {
+            let key = GrantKey {
+                scope: grant.scope.clone(),
+                grantee: grant.grantee.clone(),
+            };
+            GRANTS.with(|grants| grants.borrow_mut().insert(key, grant));
+        }
+        ArchiveEntry::ItemKey(key) => {
+            let id = ItemKeyId {
+                item_id: key.item_id.clone(),
+                key_version: key.key_version,
+            };
+            ITEM_KEYS.with(|keys| keys.borrow_mut().insert(id, key));
+        }
+        ArchiveEntry::Envelope(envelope) => {
+            let key = EnvelopeKey {
+                item_id: envelope.item_id.clone(),
+                key_version: envelope.key_version,
+                recipient: envelope.recipient,
+            };
+            ENVELOPES.with(|envelopes| envelopes.borrow_mut().insert(key, envelope));
+        }
+        ArchiveEntry::End { entries } => {
+            if entries != applied {
+                return Err(archive_error(format!("expected {} entries, found {}", entries, applied)));
+            }
+            progress.end_seen = true;
+        }
+    }
+    Ok(())
+}
+
+// Feeds archive bytes that start at `offset` through the parser, applying
+// every entry that becomes complete
+fn consume(status: &mut ImportStatus, progress: &mut ImportProgress, offset: u64, bytes: &[u8]) -> Result<(), StorageError> {
+    let body_end = status.total_size - TRAILER_LENGTH;
+    let split = body_end.saturating_sub(offset).min(bytes.len() as u64) as usize;
+    let (body, trailer) = bytes.split_at(split);
+    progress.hasher.update(body);
+    progress.trailer.extend_from_slice(trailer);
+    progress.pending.extend_from_slice(body);
+
+    if offset < MAGIC.len() as u64 {
+        if progress.pending.len() < MAGIC.len() {
+            return Ok(());
+        }
+        if &progress.pending[..MAGIC.len()] != MAGIC {
+            return Err(archive_error("missing archive header"));
+        }
+        progress.pending.drain(..MAGIC.len());
+    }
+
+    loop {
+        if progress.pending.len() < 4 {
+            break;
+        }
+        let length = u32::from_le_bytes(progress.pending[..4].try_into().unwrap()) as usize;
+        if progress.pending.len() < 4 + length {
+            break;
+        }
+        let entry = Decode!(&progress.pending[4..4 + length], ArchiveEntry)
+            .map_err(|error| archive_error(format!("undecodable entry: {}", error)))?;
+        progress.pending.drain(..4 + length);
+
+        let applied = status.entries_applied;
+        apply_entry(entry, progress, applied)?;
+        status.entries_applied += 1;
+    }
+    Ok(())
+}
+
+fn require_import() -> Result<ImportStatus, StorageError> {
+    import_status().ok_or_else(|| archive_error("no import is in progress"))
+}
+
+fn discard_import() {
+    wipe();
+    usage::reconcile();
+    set_import_status(None);
+    IMPORT_PROGRESS.with(|progress| *progress.borrow_mut() = None);
+}
+
+// Encodes and hashes entries until the batch budget is spent, finishing the
+// trailer digest once every entry is done
+fn hash_entries(session: &mut ExportSession) {
+    let ExportProgress::Hashing { hasher, offset } = &mut session.progress else {
+        return;
+    };
+    let budget_end = offset.saturating_add(MAX_BYTES_HASHED_PER_CALL);
+    while session.offsets.len() < session.entries.len() && *offset < budget_end {
+        let framed = frame(&load_entry(&session.entries[session.offsets.len()], session.started_at));
+        hasher.update(&framed);
+        session.offsets.push(*offset);
+        *offset += framed.len() as u64;
+    }
+    if session.offsets.len() < session.entries.len() {
+        return;
+    }
+
+    let info = ExportInfo {
+        total_size: *offset + TRAILER_LENGTH,
+        sha256: std::mem::take(hasher).finalize().to_vec(),
+        entries: session.entries.len() as u64,
+        started_at: session.started_at,
+        expires_at: session.expires_at,
+    };
+    session.progress = ExportProgress::Ready(info);
+}
+
+// Freezes the canister and fixes the archive layout, then hashes the archive
+// a bounded batch per call. While hashing it returns `ExportPreparing` and
+// must be called again; the export opens once the trailer digest is known.
+#[update]
+fn begin_export() -> Result<ExportInfo, StorageError> {
+    require_owner()?;
+    let now = ic_cdk::api::time();
+    if !export_active(now) {
+        ensure_writable()?;
+        if transfer::has_uploads() {
+            return Err(archive_error("finish or abort open uploads before exporting"));
+        }
+        let session = ExportSession {
+            started_at: now,
+            expires_at: now + EXPORT_LIFETIME,
+            entries: entry_refs(),
+            offsets: Vec::new(),
+            progress: ExportProgress::Hashing {
+                hasher: Sha256::new_with_prefix(MAGIC),
+                offset: MAGIC.len() as u64,
+            },
+        };
+        EXPORT.with(|export| *export.borrow_mut() = Some(session));
+    }
+
+    EXPORT.with(|export| {
+        let mut export = export.borrow_mut();
+        let session = export.as_mut().expect("an export is open");
+        hash_entries(session);
+        match &session.progress {
+            ExportProgress::Ready(info) => Ok(info.clone()),
+            ExportProgress::Hashing { .. } => Err(StorageError::ExportPreparing {
+                hashed_entries: session.offsets.len() as u64,
+                entries: session.entries.len() as u64,
+            }),
+        }
+    })
+}
+
+#[query]
+fn get_export_chunk(offset: u64, length: u64) -> Result<Vec<u8>, StorageError> {
+    require_owner()?;
+    if length > MAX_TRANSFER_SIZE as u64 {
+        return Err(invalid("length", format!("must be at most {} bytes", MAX_TRANSFER_SIZE)));
+    }
+
+    EXPORT.with(|export| {
+        let export = export.borrow();
+        let session = export.as_ref()
+            .filter(|session| session.expires_at > ic_cdk::api::time())
+            .ok_or_else(|| archive_error("no export is open"))?;
+        let ExportProgress::Ready(info) = &session.progress else {
+            return Err(archive_error("the export is still being hashed; call begin_export until it completes"));
+        };
+        let end = offset.saturating_add(length).min(info.total_size);
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+
+        let body_end = info.total_size - TRAILER_LENGTH;
+        let mut bytes = Vec::new();
+        let mut position = offset;
+        if position < MAGIC.len() as u64 {
+            bytes.extend_from_slice(&MAGIC[position as usize..MAGIC.len().min(end as usize)]);
+            position = MAGIC.len() as u64;
+        }
+
+        // Rebuild the entries that overlap the requested range
+        let first = session.offsets.partition_point(|start| *start <= position).saturating_sub(1);
+        for (start, entry) in session.offsets[first..].iter().zip(&session.entries[first..]) {
+            if *start >= end || position >= end.min(body_end) {
+                break;
+            }
+            let framed = frame(&load_entry(entry, info.started_at));
+            let entry_end = start + framed.len() as u64;
+            if entry_end <= position {
+                continue;
+            }
+            let from = (position - start) as usize;
+            let to = (end.min(entry_end) - start) as usize;
+            bytes.extend_from_slice(&framed[from..to]);
+            position = start + to as u64;
+        }
+
+        if end > body_end {
+            let from = (position.max(body_end) - body_end) as usize;
+            let to = (end - body_end) as usize;
+            bytes.extend_from_slice(&info.sha256[from..to]);
+        }
+        Ok(bytes)
+    })
+}
+
+#[update]
+fn finish_export() -> Result<(), StorageError> {
+    require_owner()?;
+    EXPORT.with(|export| *export.borrow_mut() = None);
+    Ok(())
+}
+
+#[update]
+fn begin_import(total_size: u64) -> Result<ImportStatus, StorageError> {
+    require_owner()?;
+    ensure_writable()?;
+    if !is_empty() || transfer::has_uploads() {
+        return Err(archive_error("archives can only be imported into an empty canister"));
+    }
+    if total_size < MAGIC.len() as u64 + TRAILER_LENGTH {
+        return Err(invalid("total_size", "is smaller than an empty archive"));
+    }
+    // Chunks make up nearly all of an archive, so its size bounds the content
+    check_quota(total_size, 0)?;
+
+    let now = ic_cdk::api::time();
+    let status = ImportStatus {
+        total_size,
+        received_bytes: 0,
+        entries_applied: 0,
+        started_at: now,
+        last_activity_at: now,
+    };
+    set_import_status(Some(status.clone()));
+    IMPORT_PROGRESS.with(|progress| *progress.borrow_mut() = Some(ImportProgress::default()));
+    Ok(status)
+}
+
+// Archive bytes must arrive in order. A malformed entry discards the import.
+#[update]
+fn put_import_chunk(offset: u64, content: Vec<u8>) -> Result<ImportStatus, StorageError> {
+    require_owner()?;
+    let mut status = require_import()?;
+
+    if offset != status.received_bytes {
+        return Err(invalid("offset", format!("expected {}", status.received_bytes)));
+    }
+    if content.is_empty() || content.len() > MAX_TRANSFER_SIZE {
+        return Err(invalid("content", format!("must be between 1 and {} bytes", MAX_TRANSFER_SIZE)));
+    }
+    if offset + content.len() as u64 > status.total_size {
+        return Err(invalid("content", "extends past the declared total_size"));
+    }
+
+    let mut progress = IMPORT_PROGRESS.with(|progress| progress.borrow_mut().take())
+        .ok_or_else(|| archive_error("the import was interrupted by an upgrade; abort it and start again"))?;
+    if let Err(error) = consume(&mut status, &mut progress, offset, &content) {
+        discard_import();
+        return Err(error);
+    }
+
+    status.received_bytes += content.len() as u64;
+    status.last_activity_at = ic_cdk::api::time();
+    set_import_status(Some(status.clone()));
+    IMPORT_PROGRESS.with(|cell| *cell.borrow_mut() = Some(progress));
+    Ok(status)
+}
+
+#[update]
+fn commit_import() -> Result<ImportStatus, StorageError> {
+    require_owner()?;
+    let status = require_import()?;
+    if status.received_bytes != status.total_size {
+        return Err(invalid(
+            "total_size",
+            format!("received {} of {} bytes", status.received_bytes, status.total_size),
+        ));
+    }
+
+    let progress = IMPORT_PROGRESS.with(|progress| progress.borrow_mut().take())
+        .ok_or_else(|| archive_error("the import was interrupted by an upgrade; abort it and start again"))?;
+    let digest = progress.hasher.finalize().to_vec();
+    let failure = if !progress.end_seen || !progress.pending.is_empty() {
+        Some(archive_error("the archive is truncated"))
+    } else if digest != progress.trailer {
+        Some(StorageError::ChecksumMismatch {
+            expected: progress.trailer,
+            actual: digest,
+        })
+    } else {
+        None
+    };
+    if let Some(error) = failure {
+        discard_import();
+        return Err(error);
+    }
+
+    set_import_status(None);
+    search::ensure_indexed();
+    usage::reconcile();
+    Ok(status)
+}
+
+#[update]
+fn abort_import() -> Result<(), StorageError> {
+    require_owner()?;
+    require_import()?;
+    discard_import();
+    Ok(())
+}
+
+#[query]
+fn get_import_status() -> Option<ImportStatus> {
+    import_status()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> ArchiveEntry {
+        ArchiveEntry::Header {
+            format_version: FORMAT_VERSION,
+            source_canister: Principal::anonymous(),
+            owner: owner(),
+            exported_at: 0,
+        }
+    }
+
+    fn archive(entries: &[ArchiveEntry]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        entries.iter().for_each(|entry| bytes.extend(frame(entry)));
+        let digest = Sha256::digest(&bytes);
+        bytes.extend_from_slice(&digest);
+        bytes
+    }
+
+    fn valid_archive() -> Vec<u8> {
+        archive(&[header(), ArchiveEntry::Retention(RetentionPolicy::default()), ArchiveEntry::End { entries: 2 }])
+    }
+
+    // Feeds `bytes` in pieces of `piece` bytes, the way put_import_chunk does
+    fn feed(bytes: &[u8], piece: usize) -> Result<(ImportStatus, ImportProgress), StorageError> {
+        let mut status = ImportStatus {
+            total_size: bytes.len() as u64,
+            received_bytes: 0,
+            entries_applied: 0,
+            started_at: 0,
+            last_activity_at: 0,
+        };
+        let mut progress = ImportProgress::default();
+        for (index, chunk) in bytes.chunks(piece).enumerate() {
+            consume(&mut status, &mut progress, (index * piece) as u64, chunk)?;
+        }
+        Ok((status, progress))
+    }
+
+    fn reason(result: Result<(ImportStatus, ImportProgress), StorageError>) -> String {
+        match result {
+            Err(StorageError::InvalidArchive { reason }) => reason,
+            Err(error) => panic!("unexpected error {:?}", error),
+            Ok(_) => panic!("archive was accepted"),
+        }
+    }
+
+    #[test]
+    fn consumes_an_archive_in_any_piece_size() {
+        let bytes = valid_archive();
+        for piece in [1, 3, 7, 64, bytes.len()] {
+            let (status, progress) = feed(&bytes, piece).unwrap();
+            assert_eq!(status.entries_applied, 3, "pieces of {}", piece);
+            assert!(progress.end_seen && progress.pending.is_empty());
+            assert_eq!(progress.hasher.finalize().to_vec(), progress.trailer);
+        }
+    }
+
+    #[test]
+    fn trailer_is_not_parsed_as_entries() {
+        let mut bytes = valid_archive();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let (_, progress) = feed(&bytes, 5).unwrap();
+        assert_ne!(progress.hasher.finalize().to_vec(), progress.trailer);
+    }
+
+    #[test]
+    fn rejects_malformed_archives() {
+        let mut bytes = valid_archive();
+        bytes[0] = b'X';
+        assert_eq!(reason(feed(&bytes, 4)), "missing archive header");
+
+        let bytes = archive(&[ArchiveEntry::End { entries: 0 }]);
+        assert_eq!(reason(feed(&bytes, 4)), "the first entry must be the header");
+
+        let bytes = archive(&[header(), header()]);
+        assert_eq!(reason(feed(&bytes, 4)), "the header appears twice");
+
+        let bytes = archive(&[header(), ArchiveEntry::End { entries: 5 }]);
+        assert_eq!(reason(feed(&bytes, 4)), "expected 5 entries, found 1");
+
+        let bytes = archive(&[header(), ArchiveEntry::End { entries: 1 }, ArchiveEntry::End { entries: 2 }]);
+        assert_eq!(reason(feed(&bytes, 4)), "entries follow the end marker");
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&3u32.to_le_bytes());
+        bytes.extend_from_slice(b"bad");
+        bytes.extend_from_slice(&[0; TRAILER_LENGTH as usize]);
+        assert!(reason(feed(&bytes, 4)).starts_with("undecodable entry"));
+    }
+
+    #[test]
+    fn rejects_archives_of_another_owner() {
+        let bytes = archive(&[ArchiveEntry::Header {
+            format_version: FORMAT_VERSION,
+            source_canister: Principal::anonymous(),
+            owner: Principal::management_canister(),
+            exported_at: 0,
+        }]);
+        assert!(reason(feed(&bytes, 16)).starts_with("was exported by"));
+    }
+}
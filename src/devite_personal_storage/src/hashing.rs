@@ -0,0 +1,142 @@
+// SHA-256 whose running state fits in a stable map. Uploads are hashed over
+// many messages, possibly across upgrades, which a heap hasher would not
+// survive.
+
+use candid::{CandidType, Deserialize, Encode, Decode};
+use ic_stable_structures::{Storable, BoundedStorable};
+use sha2::compress256;
+use sha2::digest::generic_array::GenericArray;
+use std::borrow::Cow;
+
+const BLOCK_SIZE: usize = 64;
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09_e667, 0xbb67_ae85, 0x3c6e_f372, 0xa54f_f53a, 0x510e_527f, 0x9b05_688c, 0x1f83_d9ab, 0x5be0_cd19,
+];
+
+// HASHING TYPES
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct ResumableSha256 {
+    state: Vec<u32>,
+    // Bytes hashed so far, including `pending`
+    length: u64,
+    // Input that does not fill a block yet
+    pending: Vec<u8>,
+}
+
+impl Default for ResumableSha256 {
+    fn default() -> Self {
+        ResumableSha256 {
+            state: INITIAL_STATE.to_vec(),
+            length: 0,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl Storable for ResumableSha256 {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ResumableSha256 {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// HASHING FUNCTIONS
+
+impl ResumableSha256 {
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        if !self.pending.is_empty() {
+            let take = (BLOCK_SIZE - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() < BLOCK_SIZE {
+                return;
+            }
+            let block = std::mem::take(&mut self.pending);
+            self.compress(&block);
+        }
+        let whole = data.len() - data.len() % BLOCK_SIZE;
+        self.compress(&data[..whole]);
+        self.pending = data[whole..].to_vec();
+    }
+
+    // `data` is a whole number of blocks
+    fn compress(&mut self, data: &[u8]) {
+        let mut state: [u32; 8] = self.state.as_slice().try_into().expect("SHA-256 state has 8 words");
+        for block in data.chunks_exact(BLOCK_SIZE) {
+            compress256(&mut state, std::slice::from_ref(GenericArray::from_slice(block)));
+        }
+        self.state = state.to_vec();
+    }
+
+    pub fn finalize(mut self) -> Vec<u8> {
+        let mut tail = std::mem::take(&mut self.pending);
+        tail.push(0x80);
+        while tail.len() % BLOCK_SIZE != BLOCK_SIZE - 8 {
+            tail.push(0);
+        }
+        tail.extend_from_slice(&(self.length * 8).to_be_bytes());
+        self.compress(&tail);
+        self.state.iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    fn sample(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 31 + 7) as u8).collect()
+    }
+
+    #[test]
+    fn matches_sha256_around_block_boundaries() {
+        for length in [0, 1, 55, 56, 63, 64, 65, 119, 120, 128, 1000] {
+            let data = sample(length);
+            let mut hasher = ResumableSha256::default();
+            hasher.update(&data);
+            assert_eq!(hasher.finalize(), Sha256::digest(&data).to_vec(), "length {}", length);
+        }
+    }
+
+    #[test]
+    fn matches_sha256_when_fed_in_uneven_pieces() {
+        let data = sample(5000);
+        let mut hasher = ResumableSha256::default();
+        let mut rest = data.as_slice();
+        for size in [1, 3, 64, 70, 0, 200, 17].iter().cycle() {
+            if rest.is_empty() {
+                break;
+            }
+            let (piece, tail) = rest.split_at((*size).min(rest.len()));
+            hasher.update(piece);
+            rest = tail;
+        }
+        assert_eq!(hasher.length(), 5000);
+        assert_eq!(hasher.finalize(), Sha256::digest(&data).to_vec());
+    }
+
+    #[test]
+    fn resumes_from_stored_state() {
+        let data = sample(3000);
+        let mut hasher = ResumableSha256::default();
+        hasher.update(&data[..1234]);
+        let mut restored = ResumableSha256::from_bytes(hasher.to_bytes());
+        restored.update(&data[1234..]);
+        assert_eq!(restored.finalize(), Sha256::digest(&data).to_vec());
+    }
+}
@@ -0,0 +1,429 @@
+use candid::{CandidType, Deserialize, Principal, Encode, Decode};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, Storable, BoundedStorable};
+use std::cell::RefCell;
+use std::borrow::Cow;
+
+use crate::acl::{self, Permission};
+use crate::archive;
+use crate::revisions;
+use crate::{invalid, owner, ItemRecord, Memory, StorableString, StorageError, ITEMS, MEMORY_MANAGER};
+
+type ItemKeyStorage = StableBTreeMap<ItemKeyId, ItemKey, Memory>;
+type EnvelopeStorage = StableBTreeMap<EnvelopeKey, KeyEnvelope, Memory>;
+
+const MAX_CIPHER_LENGTH: usize = 32;
+const MAX_WRAPPED_KEY_LENGTH: usize = 1024;
+const MAX_ENVELOPES_PER_CALL: usize = 100;
+// Fingerprints are the SHA-256 of the recipient's registered public key
+const FINGERPRINT_LENGTH: usize = 32;
+
+// KEY ENVELOPE TYPES
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ItemKeyId {
+    pub item_id: String,
+    pub key_version: u32,
+}
+
+impl Storable for ItemKeyId {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ItemKeyId {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// One generation of an item's data key. The key itself never reaches the
+// canister; only copies wrapped to each recipient's public key are stored.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ItemKey {
+    pub item_id: String,
+    pub key_version: u32,
+    // Content cipher chosen by the client, e.g. "AES-256-GCM"
+    pub cipher: String,
+    pub created_by: Principal,
+    pub created_at: u64,
+}
+
+impl Storable for ItemKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ItemKey {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EnvelopeKey {
+    pub item_id: String,
+    pub key_version: u32,
+    pub recipient: Principal,
+}
+
+impl Storable for EnvelopeKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EnvelopeKey {
+    const MAX_SIZE: u32 = 160;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct KeyEnvelope {
+    pub item_id: String,
+    pub key_version: u32,
+    pub recipient: Principal,
+    pub wrapped_key: Vec<u8>,
+    pub recipient_key_fingerprint: Vec<u8>,
+    pub wrapped_by: Principal,
+    pub wrapped_at: u64,
+}
+
+impl Storable for KeyEnvelope {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for KeyEnvelope {
+    const MAX_SIZE: u32 = 1536;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct EnvelopeInput {
+    pub recipient: Principal,
+    pub wrapped_key: Vec<u8>,
+    pub recipient_key_fingerprint: Vec<u8>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RotateKeyRequest {
+    pub item_id: String,
+    pub cipher: String,
+    pub envelopes: Vec<EnvelopeInput>,
+    // Set when the item's current content is already encrypted with the new
+    // key, typically right after storing it. Otherwise the content keeps its
+    // old key until it is next written.
+    pub encrypts_current_content: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct EnvelopeSummary {
+    pub recipient: Principal,
+    pub recipient_key_fingerprint: Vec<u8>,
+    pub wrapped_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct KeyStatus {
+    pub item_id: String,
+    pub key: Option<ItemKey>,
+    // Key the current content is encrypted with
+    pub content_key_version: Option<u32>,
+    pub envelopes: Vec<EnvelopeSummary>,
+    // Readers without an envelope for the current key
+    pub missing_recipients: Vec<Principal>,
+    // Envelope holders who can no longer read the item
+    pub revoked_recipients: Vec<Principal>,
+    pub needs_rewrap: bool,
+    pub needs_rotation: bool,
+    pub needs_reencryption: bool,
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Item keys and their wrapped envelopes (Memory ID 15, 16)
+    pub(crate) static ITEM_KEYS: RefCell<ItemKeyStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+        )
+    );
+
+    pub(crate) static ENVELOPES: RefCell<EnvelopeStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))),
+        )
+    );
+}
+
+// KEY ENVELOPE FUNCTIONS
+
+fn item_keys(item_id: &str) -> Vec<ItemKey> {
+    let start = ItemKeyId {
+        item_id: item_id.to_string(),
+        key_version: 0,
+    };
+    ITEM_KEYS.with(|keys| {
+        keys.borrow().range(start..)
+            .take_while(|(id, _)| id.item_id == item_id)
+            .map(|(_, key)| key)
+            .collect()
+    })
+}
+
+fn current_key(item_id: &str) -> Option<ItemKey> {
+    item_keys(item_id).pop()
+}
+
+// Key version new content of the item is expected to be encrypted with
+pub fn current_key_version(item_id: &str) -> Option<u32> {
+    current_key(item_id).map(|key| key.key_version)
+}
+
+fn get_key(item_id: &str, key_version: Option<u32>) -> Result<ItemKey, StorageError> {
+    let key = match key_version {
+        Some(key_version) => ITEM_KEYS.with(|keys| {
+            keys.borrow().get(&ItemKeyId {
+                item_id: item_id.to_string(),
+                key_version,
+            })
+        }),
+        None => current_key(item_id),
+    };
+    key.ok_or_else(|| StorageError::KeyNotFound {
+        id: item_id.to_string(),
+        key_version,
+    })
+}
+
+fn key_envelopes(item_id: &str, key_version: u32) -> Vec<KeyEnvelope> {
+    let start = EnvelopeKey {
+        item_id: item_id.to_string(),
+        key_version,
+        recipient: Principal::management_canister(),
+    };
+    ENVELOPES.with(|envelopes| {
+        envelopes.borrow().range(start..)
+            .take_while(|(key, _)| key.item_id == item_id && key.key_version == key_version)
+            .map(|(_, envelope)| envelope)
+            .collect()
+    })
+}
+
+// Every recipient must be able to read the item, so wrapping a key never
+// widens access beyond the ACL
+fn store_envelopes(record: &ItemRecord, key_version: u32, envelopes: Vec<EnvelopeInput>) -> Result<(), StorageError> {
+    if envelopes.is_empty() || envelopes.len() > MAX_ENVELOPES_PER_CALL {
+        return Err(invalid("envelopes", format!("must contain between 1 and {} envelopes", MAX_ENVELOPES_PER_CALL)));
+    }
+
+    let readers = acl::readers(record);
+    for envelope in &envelopes {
+        if !readers.contains(&envelope.recipient) {
+            return Err(invalid("envelopes", format!("{} cannot read this item", envelope.recipient)));
+        }
+        if envelope.wrapped_key.is_empty() || envelope.wrapped_key.len() > MAX_WRAPPED_KEY_LENGTH {
+            return Err(invalid("envelopes", format!("wrapped keys must be between 1 and {} bytes", MAX_WRAPPED_KEY_LENGTH)));
+        }
+        if envelope.recipient_key_fingerprint.len() != FINGERPRINT_LENGTH {
+            return Err(invalid("envelopes", "fingerprints must be 32-byte SHA-256 digests"));
+        }
+    }
+
+    let now = ic_cdk::api::time();
+    let wrapped_by = ic_cdk::api::caller();
+    ENVELOPES.with(|stored| {
+        let mut stored = stored.borrow_mut();
+        for envelope in envelopes {
+            let key = EnvelopeKey {
+                item_id: record.id.clone(),
+                key_version,
+                recipient: envelope.recipient,
+            };
+            stored.insert(key, KeyEnvelope {
+                item_id: record.id.clone(),
+                key_version,
+                recipient: envelope.recipient,
+                wrapped_key: envelope.wrapped_key,
+                recipient_key_fingerprint: envelope.recipient_key_fingerprint,
+                wrapped_by,
+                wrapped_at: now,
+            });
+        }
+    });
+    Ok(())
+}
+
+fn key_status(record: &ItemRecord) -> KeyStatus {
+    let key = current_key(&record.id);
+    let envelopes = key.as_ref()
+        .map(|key| key_envelopes(&record.id, key.key_version))
+        .unwrap_or_default();
+
+    let (missing_recipients, revoked_recipients) = match &key {
+        Some(_) => {
+            let readers = acl::readers(record);
+            let holders: Vec<Principal> = envelopes.iter().map(|envelope| envelope.recipient).collect();
+            (
+                readers.iter().filter(|reader| !holders.contains(reader)).copied().collect(),
+                holders.into_iter().filter(|holder| !readers.contains(holder)).collect(),
+            )
+        }
+        None => (Vec::new(), Vec::new()),
+    };
+
+    KeyStatus {
+        item_id: record.id.clone(),
+        content_key_version: record.key_version,
+        needs_rewrap: !missing_recipients.is_empty(),
+        needs_rotation: !revoked_recipients.is_empty(),
+        needs_reencryption: key.is_some() && record.key_version != key.as_ref().map(|key| key.key_version),
+        envelopes: envelopes.into_iter()
+            .map(|envelope| EnvelopeSummary {
+                recipient: envelope.recipient,
+                recipient_key_fingerprint: envelope.recipient_key_fingerprint,
+                wrapped_at: envelope.wrapped_at,
+            })
+            .collect(),
+        missing_recipients,
+        revoked_recipients,
+        key,
+    }
+}
+
+pub fn delete_keys(item_id: &str) {
+    for key in item_keys(item_id) {
+        for envelope in key_envelopes(item_id, key.key_version) {
+            ENVELOPES.with(|envelopes| {
+                envelopes.borrow_mut().remove(&EnvelopeKey {
+                    item_id: item_id.to_string(),
+                    key_version: key.key_version,
+                    recipient: envelope.recipient,
+                })
+            });
+        }
+        ITEM_KEYS.with(|keys| {
+            keys.borrow_mut().remove(&ItemKeyId {
+                item_id: item_id.to_string(),
+                key_version: key.key_version,
+            })
+        });
+    }
+}
+
+// Starts a new key generation, e.g. after a reader lost access. The owner
+// must always be able to unwrap the current key.
+#[update]
+fn rotate_item_key(request: RotateKeyRequest) -> Result<KeyStatus, StorageError> {
+    archive::ensure_writable()?;
+    let mut record = acl::authorize_item(&request.item_id, Permission::Admin)?;
+    if request.cipher.is_empty() || request.cipher.len() > MAX_CIPHER_LENGTH {
+        return Err(invalid("cipher", format!("must be between 1 and {} bytes", MAX_CIPHER_LENGTH)));
+    }
+    if !request.envelopes.iter().any(|envelope| envelope.recipient == owner()) {
+        return Err(invalid("envelopes", "must include an envelope for the owner"));
+    }
+    if request.encrypts_current_content {
+        if let Some(key_version) = record.key_version {
+            return Err(invalid(
+                "encrypts_current_content",
+                format!("the current content is encrypted with key version {}", key_version),
+            ));
+        }
+    }
+
+    let key_version = current_key_version(&record.id).unwrap_or(0) + 1;
+    store_envelopes(&record, key_version, request.envelopes)?;
+    let key = ItemKey {
+        item_id: record.id.clone(),
+        key_version,
+        cipher: request.cipher,
+        created_by: ic_cdk::api::caller(),
+        created_at: ic_cdk::api::time(),
+    };
+    ITEM_KEYS.with(|keys| {
+        keys.borrow_mut().insert(
+            ItemKeyId {
+                item_id: record.id.clone(),
+                key_version,
+            },
+            key,
+        )
+    });
+
+    if request.encrypts_current_content {
+        record.key_version = Some(key_version);
+        ITEMS.with(|items| items.borrow_mut().insert(StorableString::from(record.id.clone()), record.clone()));
+        revisions::set_current_key_version(&record);
+    }
+    Ok(key_status(&record))
+}
+
+// Re-wraps an existing key for readers who were added after it was created
+// or whose public key changed
+#[update]
+fn add_key_envelopes(item_id: String, key_version: u32, envelopes: Vec<EnvelopeInput>) -> Result<KeyStatus, StorageError> {
+    archive::ensure_writable()?;
+    let record = acl::authorize_item(&item_id, Permission::Admin)?;
+    get_key(&item_id, Some(key_version))?;
+
+    store_envelopes(&record, key_version, envelopes)?;
+    Ok(key_status(&record))
+}
+
+#[query]
+fn get_key_envelope(item_id: String, key_version: Option<u32>) -> Result<KeyEnvelope, StorageError> {
+    acl::authorize_item(&item_id, Permission::Read)?;
+    let key = get_key(&item_id, key_version)?;
+
+    let envelope_key = EnvelopeKey {
+        item_id: item_id.clone(),
+        key_version: key.key_version,
+        recipient: ic_cdk::api::caller(),
+    };
+    ENVELOPES.with(|envelopes| envelopes.borrow().get(&envelope_key))
+        .ok_or(StorageError::EnvelopeNotFound {
+            id: item_id,
+            key_version: key.key_version,
+        })
+}
+
+#[query]
+fn get_key_status(item_id: String) -> Result<KeyStatus, StorageError> {
+    let record = acl::authorize_item(&item_id, Permission::Admin)?;
+    Ok(key_status(&record))
+}
+
+// Encrypted items the caller administers whose envelopes no longer match
+// the ACL, or whose content still uses an older key
+#[query]
+fn list_items_needing_rewrap() -> Vec<KeyStatus> {
+    let caller = ic_cdk::api::caller();
+    ITEMS.with(|items| {
+        items.borrow().iter()
+            .map(|(_, record)| record)
+            .filter(|record| acl::permission_of(caller, record) == Some(Permission::Admin))
+            .map(|record| key_status(&record))
+            .filter(|status| status.needs_rewrap || status.needs_rotation || status.needs_reencryption)
+            .collect()
+    })
+}
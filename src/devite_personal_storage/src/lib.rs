@@ -0,0 +1,651 @@
+use candid::{CandidType, Deserialize, Principal, Encode, Decode};
+use ic_cdk::{init, post_upgrade, query, update};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable, BoundedStorable};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::borrow::Cow;
+
+mod acl;
+mod archive;
+mod hashing;
+mod keys;
+mod links;
+mod revisions;
+mod search;
+mod transfer;
+mod usage;
+
+use acl::Permission;
+use transfer::MAX_TRANSFER_SIZE;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Content is split into chunks so that large items never have to fit in a
+// single stable map value
+const CHUNK_SIZE: usize = 64 * 1024;
+const MAX_ITEM_ID_LENGTH: usize = 64;
+const MAX_FILENAME_LENGTH: usize = 255;
+const MAX_CONTENT_TYPE_LENGTH: usize = 128;
+const MAX_DESCRIPTION_LENGTH: usize = 1000;
+const MAX_TAGS: usize = 20;
+const MAX_TAG_LENGTH: usize = 32;
+// Used until the backend sets a quota for this canister
+const DEFAULT_QUOTA: StorageQuota = StorageQuota {
+    max_bytes: 100 * 1024 * 1024,
+    max_items: 1000,
+};
+
+// Wrapper types to work around orphan rules
+#[derive(Clone, Debug, Default, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StorableString(pub String);
+
+impl From<String> for StorableString {
+    fn from(s: String) -> Self {
+        StorableString(s)
+    }
+}
+
+impl Storable for StorableString {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&self.0).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let string: String = Decode!(bytes.as_ref(), String).unwrap();
+        StorableString(string)
+    }
+}
+
+impl BoundedStorable for StorableString {
+    const MAX_SIZE: u32 = 96;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ChunkKey {
+    pub item_id: String,
+    pub version: u64,
+    pub index: u32,
+}
+
+impl Storable for ChunkKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ChunkKey {
+    const MAX_SIZE: u32 = 160;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Raw content bytes, stored without Candid framing
+#[derive(Clone, Debug)]
+pub struct Chunk(pub Vec<u8>);
+
+impl Storable for Chunk {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Chunk(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for Chunk {
+    const MAX_SIZE: u32 = CHUNK_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+type ItemStorage = StableBTreeMap<StorableString, ItemRecord, Memory>;
+type ChunkStorage = StableBTreeMap<ChunkKey, Chunk, Memory>;
+type ManifestStorage = StableBTreeMap<ChunkKey, ChunkInfo, Memory>;
+type ConfigCell = StableCell<StorageConfig, Memory>;
+type QuotaCell = StableCell<StorageQuota, Memory>;
+
+// PERSONAL STORAGE TYPES
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StorageInitArgs {
+    pub owner: Principal,
+    pub quota: Option<StorageQuota>,
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq)]
+pub struct StorageQuota {
+    pub max_bytes: u64,
+    pub max_items: u64,
+}
+
+impl Storable for StorageQuota {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct StorageUsage {
+    pub used_bytes: u64,
+    pub item_count: u64,
+    // Declared sizes of uploads that have not been committed yet
+    pub reserved_bytes: u64,
+    pub reserved_items: u64,
+    pub quota: StorageQuota,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StorageConfig {
+    pub owner: Principal,
+    // The platform canister that installed this one
+    pub backend: Principal,
+}
+
+impl Storable for StorageConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StoredItem {
+    pub id: String,
+    pub content: Vec<u8>,
+    pub metadata: ItemMetadata,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ItemMetadata {
+    pub content_type: String,
+    pub filename: String,
+    pub description: String,
+    pub tags: Vec<String>,
+}
+
+// Everything about an item except its content, which lives in chunk storage
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ItemRecord {
+    pub id: String,
+    pub metadata: ItemMetadata,
+    pub size: u64,
+    pub sha256: Vec<u8>,
+    pub version: u64,
+    pub chunk_count: u32,
+    pub created_at: u64,
+    pub updated_at: u64,
+    // Current revision; None for items stored before revisions were kept
+    pub revision: Option<u64>,
+    // Item key the content is encrypted with; None for plaintext content
+    pub key_version: Option<u32>,
+}
+
+impl Storable for ItemRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ItemRecord {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Manifest entry of one stored chunk
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ChunkInfo {
+    pub index: u32,
+    pub size: u32,
+    pub sha256: Vec<u8>,
+}
+
+impl Storable for ChunkInfo {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ChunkInfo {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CreateItemRequest {
+    pub id: String,
+    pub content: Vec<u8>,
+    pub metadata: ItemMetadata,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct UpdateItemRequest {
+    pub id: String,
+    pub content: Option<Vec<u8>>,
+    pub metadata: Option<ItemMetadata>,
+    pub message: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum StorageError {
+    NotOwner,
+    NotBackend,
+    NotFound { id: String },
+    AlreadyExists { id: String },
+    InvalidInput { field: String, reason: String },
+    QuotaExceeded { usage: StorageUsage },
+    ContentTooLarge { size: u64, limit: u64 },
+    UploadNotFound { upload_id: u64 },
+    // Another upload is writing to this item
+    UploadInProgress { id: String },
+    ChecksumMismatch { expected: Vec<u8>, actual: Vec<u8> },
+    // The commit hashed another batch of stored chunks and must be called again
+    UploadVerifying { upload_id: u64, hashed_bytes: u64, total_size: u64 },
+    RevisionNotFound { id: String, number: u64 },
+    AccessDenied { id: String, required: Permission },
+    GroupNotFound { name: String },
+    LinkNotFound { link_id: u64 },
+    KeyNotFound { id: String, key_version: Option<u32> },
+    EnvelopeNotFound { id: String, key_version: u32 },
+    RandomnessUnavailable { reason: String },
+    // An export or import is open; stored data is read-only until it ends
+    ArchiveInProgress,
+    InvalidArchive { reason: String },
+    // The export hashed another batch of entries and must be begun again
+    ExportPreparing { hashed_entries: u64, entries: u64 },
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    // Ownership (Memory ID 0)
+    static CONFIG: RefCell<ConfigCell> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
+            StorageConfig {
+                owner: Principal::anonymous(),
+                backend: Principal::anonymous(),
+            },
+        ).expect("Failed to initialize storage config")
+    );
+
+    // Items and their content (Memory ID 1, 2)
+    static ITEMS: RefCell<ItemStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
+        )
+    );
+
+    static CHUNKS: RefCell<ChunkStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
+        )
+    );
+
+    // Quota set by the backend (Memory ID 3)
+    static QUOTA: RefCell<QuotaCell> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+            DEFAULT_QUOTA,
+        ).expect("Failed to initialize storage quota")
+    );
+
+    // Chunk manifests (Memory ID 4)
+    static MANIFESTS: RefCell<ManifestStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+        )
+    );
+}
+
+// LIFECYCLE
+
+#[init]
+fn init(args: StorageInitArgs) {
+    let config = StorageConfig {
+        owner: args.owner,
+        backend: ic_cdk::api::caller(),
+    };
+    CONFIG.with(|cell| cell.borrow_mut().set(config).expect("Failed to store storage config"));
+
+    if let Some(quota) = args.quota {
+        QUOTA.with(|cell| cell.borrow_mut().set(quota).expect("Failed to store storage quota"));
+    }
+
+    transfer::start_upload_gc_timer();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    // Timers do not survive upgrades
+    transfer::start_upload_gc_timer();
+    search::ensure_indexed();
+    usage::reconcile();
+}
+
+// HELPERS
+
+fn require_backend() -> Result<(), StorageError> {
+    let backend = CONFIG.with(|cell| cell.borrow().get().backend);
+    if ic_cdk::api::caller() == backend {
+        Ok(())
+    } else {
+        Err(StorageError::NotBackend)
+    }
+}
+
+fn usage() -> StorageUsage {
+    let (used_bytes, item_count) = usage::used();
+    let (reserved_bytes, reserved_items) = transfer::reservations();
+    StorageUsage {
+        used_bytes,
+        item_count,
+        reserved_bytes,
+        reserved_items,
+        quota: QUOTA.with(|cell| *cell.borrow().get()),
+    }
+}
+
+// Checks that adding `added_bytes` and `added_items` stays within the quota
+fn check_quota(added_bytes: u64, added_items: u64) -> Result<(), StorageError> {
+    let usage = usage();
+    let bytes = usage.used_bytes.saturating_add(usage.reserved_bytes).saturating_add(added_bytes);
+    let items = (usage.item_count + usage.reserved_items).saturating_add(added_items);
+
+    if bytes > usage.quota.max_bytes || items > usage.quota.max_items {
+        Err(StorageError::QuotaExceeded { usage })
+    } else {
+        Ok(())
+    }
+}
+
+fn owner() -> Principal {
+    CONFIG.with(|cell| cell.borrow().get().owner)
+}
+
+fn require_owner() -> Result<(), StorageError> {
+    if ic_cdk::api::caller() == owner() {
+        Ok(())
+    } else {
+        Err(StorageError::NotOwner)
+    }
+}
+
+fn invalid(field: &str, reason: impl Into<String>) -> StorageError {
+    StorageError::InvalidInput {
+        field: field.to_string(),
+        reason: reason.into(),
+    }
+}
+
+fn validate_item_id(id: &str) -> Result<(), StorageError> {
+    if id.is_empty() || id.len() > MAX_ITEM_ID_LENGTH {
+        return Err(invalid("id", format!("must be between 1 and {} bytes", MAX_ITEM_ID_LENGTH)));
+    }
+    if !id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+        return Err(invalid("id", "may only contain letters, digits, '_', '-' and '.'"));
+    }
+    Ok(())
+}
+
+fn validate_metadata(metadata: &ItemMetadata) -> Result<(), StorageError> {
+    if metadata.filename.is_empty() || metadata.filename.len() > MAX_FILENAME_LENGTH {
+        return Err(invalid("metadata.filename", format!("must be between 1 and {} bytes", MAX_FILENAME_LENGTH)));
+    }
+    if metadata.content_type.len() > MAX_CONTENT_TYPE_LENGTH {
+        return Err(invalid("metadata.content_type", format!("must be at most {} bytes", MAX_CONTENT_TYPE_LENGTH)));
+    }
+    if metadata.description.len() > MAX_DESCRIPTION_LENGTH {
+        return Err(invalid("metadata.description", format!("must be at most {} bytes", MAX_DESCRIPTION_LENGTH)));
+    }
+    if metadata.tags.len() > MAX_TAGS || metadata.tags.iter().any(|tag| tag.is_empty() || tag.len() > MAX_TAG_LENGTH) {
+        return Err(invalid(
+            "metadata.tags",
+            format!("must contain at most {} tags of 1 to {} bytes", MAX_TAGS, MAX_TAG_LENGTH),
+        ));
+    }
+    Ok(())
+}
+
+fn write_chunk(item_id: &str, version: u64, index: u32, bytes: &[u8]) {
+    let key = ChunkKey {
+        item_id: item_id.to_string(),
+        version,
+        index,
+    };
+    let info = ChunkInfo {
+        index,
+        size: bytes.len() as u32,
+        sha256: Sha256::digest(bytes).to_vec(),
+    };
+    MANIFESTS.with(|manifests| manifests.borrow_mut().insert(key.clone(), info));
+    CHUNKS.with(|chunks| chunks.borrow_mut().insert(key, Chunk(bytes.to_vec())));
+}
+
+fn read_chunk(item_id: &str, version: u64, index: u32) -> Option<Vec<u8>> {
+    let key = ChunkKey {
+        item_id: item_id.to_string(),
+        version,
+        index,
+    };
+    CHUNKS.with(|chunks| chunks.borrow().get(&key)).map(|chunk| chunk.0)
+}
+
+fn write_content(item_id: &str, version: u64, content: &[u8]) -> u32 {
+    let mut count = 0;
+    for (index, bytes) in content.chunks(CHUNK_SIZE).enumerate() {
+        write_chunk(item_id, version, index as u32, bytes);
+        count += 1;
+    }
+    count
+}
+
+fn read_content(record: &ItemRecord) -> Vec<u8> {
+    transfer::read_range(&record.id, record.version, record.size, 0, record.size)
+}
+
+fn delete_version(item_id: &str, version: u64, chunk_count: u32) {
+    for index in 0..chunk_count {
+        let key = ChunkKey {
+            item_id: item_id.to_string(),
+            version,
+            index,
+        };
+        CHUNKS.with(|chunks| chunks.borrow_mut().remove(&key));
+        MANIFESTS.with(|manifests| manifests.borrow_mut().remove(&key));
+    }
+}
+
+fn delete_content(record: &ItemRecord) {
+    delete_version(&record.id, record.version, record.chunk_count);
+}
+
+fn get_record(id: &str) -> Result<ItemRecord, StorageError> {
+    ITEMS.with(|items| items.borrow().get(&StorableString::from(id.to_string())))
+        .ok_or_else(|| StorageError::NotFound { id: id.to_string() })
+}
+
+// ITEM FUNCTIONS
+
+#[update]
+fn create_item(request: CreateItemRequest) -> Result<ItemRecord, StorageError> {
+    archive::ensure_writable()?;
+    require_owner()?;
+    validate_item_id(&request.id)?;
+    validate_metadata(&request.metadata)?;
+
+    let key = StorableString::from(request.id.clone());
+    if ITEMS.with(|items| items.borrow().contains_key(&key)) {
+        return Err(StorageError::AlreadyExists { id: request.id });
+    }
+    transfer::ensure_no_open_upload(&request.id)?;
+    check_quota(request.content.len() as u64, 1)?;
+
+    let now = ic_cdk::api::time();
+    let version = 1;
+    let chunk_count = write_content(&request.id, version, &request.content);
+    let record = ItemRecord {
+        id: request.id,
+        metadata: request.metadata,
+        size: request.content.len() as u64,
+        sha256: Sha256::digest(&request.content).to_vec(),
+        version,
+        chunk_count,
+        created_at: now,
+        updated_at: now,
+        revision: Some(version),
+        // Content encrypted before its first key exists is claimed with
+        // `rotate_item_key`
+        key_version: None,
+    };
+
+    ITEMS.with(|items| items.borrow_mut().insert(key, record.clone()));
+    search::reindex(None, Some(&record));
+    usage::item_changed(None, Some(&record));
+    revisions::record_revision(&record, Some("Created".to_string()));
+    Ok(record)
+}
+
+#[update]
+fn update_item(request: UpdateItemRequest) -> Result<ItemRecord, StorageError> {
+    archive::ensure_writable()?;
+    let mut record = acl::authorize_item(&request.id, Permission::Write)?;
+    transfer::ensure_no_open_upload(&record.id)?;
+
+    if let Some(metadata) = &request.metadata {
+        validate_metadata(metadata)?;
+        acl::authorize_metadata_change(&record, metadata)?;
+    }
+    revisions::validate_message(&request.message)?;
+    if let Some(content) = &request.content {
+        // The previous content stays with its revision until retention prunes it
+        check_quota(content.len() as u64, 0)?;
+    }
+
+    let previous = record.clone();
+    let revision = revisions::next_revision(&record);
+    if let Some(metadata) = request.metadata {
+        record.metadata = metadata;
+    }
+
+    if let Some(content) = request.content {
+        record.version = revision;
+        record.chunk_count = write_content(&record.id, record.version, &content);
+        record.size = content.len() as u64;
+        record.sha256 = Sha256::digest(&content).to_vec();
+        record.key_version = keys::current_key_version(&record.id);
+    }
+
+    record.revision = Some(revision);
+    record.updated_at = ic_cdk::api::time();
+    ITEMS.with(|items| items.borrow_mut().insert(StorableString::from(record.id.clone()), record.clone()));
+    search::reindex(Some(&previous), Some(&record));
+    usage::item_changed(Some(&previous), Some(&record));
+    revisions::record_revision(&record, request.message);
+    Ok(record)
+}
+
+#[update]
+fn delete_item(id: String) -> Result<(), StorageError> {
+    archive::ensure_writable()?;
+    let record = acl::authorize_item(&id, Permission::Admin)?;
+    transfer::ensure_no_open_upload(&id)?;
+
+    delete_content(&record);
+    revisions::delete_history(&id);
+    keys::delete_keys(&id);
+    acl::remove_item_grants(&id);
+    links::revoke_links_for(&id);
+    search::reindex(Some(&record), None);
+    usage::item_changed(Some(&record), None);
+    ITEMS.with(|items| items.borrow_mut().remove(&StorableString::from(id)));
+    Ok(())
+}
+
+#[query]
+fn get_item(id: String) -> Result<StoredItem, StorageError> {
+    let record = acl::authorize_item(&id, Permission::Read)?;
+
+    // Larger items do not fit in a reply and are read with `get_range`
+    if record.size > MAX_TRANSFER_SIZE as u64 {
+        return Err(StorageError::ContentTooLarge {
+            size: record.size,
+            limit: MAX_TRANSFER_SIZE as u64,
+        });
+    }
+
+    Ok(StoredItem {
+        content: read_content(&record),
+        id: record.id,
+        metadata: record.metadata,
+        created_at: record.created_at,
+        updated_at: record.updated_at,
+    })
+}
+
+// The backend reads records to check the content research tokens point at
+#[query]
+fn get_item_record(id: String) -> Result<ItemRecord, StorageError> {
+    if require_backend().is_ok() {
+        return get_record(&id);
+    }
+    acl::authorize_item(&id, Permission::Read)
+}
+
+// Pooled canisters are installed with the backend as owner and handed over
+// to a user when they register
+#[update]
+fn assign_owner(owner: Principal) -> Result<(), StorageError> {
+    require_backend()?;
+
+    CONFIG.with(|cell| {
+        let mut config = cell.borrow().get().clone();
+        config.owner = owner;
+        cell.borrow_mut().set(config).expect("Failed to store storage config");
+    });
+    Ok(())
+}
+
+#[update]
+fn set_quota(quota: StorageQuota) -> Result<(), StorageError> {
+    require_backend()?;
+
+    // Lowering the quota below current usage only blocks further writes
+    QUOTA.with(|cell| cell.borrow_mut().set(quota).expect("Failed to store storage quota"));
+    Ok(())
+}
+
+#[query]
+fn get_usage() -> Result<StorageUsage, StorageError> {
+    require_owner().or_else(|_| require_backend())?;
+    Ok(usage())
+}
+
+#[query]
+fn get_owner() -> Principal {
+    owner()
+}
@@ -0,0 +1,604 @@
+use candid::parser::types::FuncMode;
+use candid::types::{Function, Serializer, Type};
+use candid::{CandidType, Deserialize, Func, Principal, Encode, Decode};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable, BoundedStorable};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::borrow::Cow;
+
+use crate::acl::{self, Permission};
+use crate::revisions;
+use crate::transfer::{self, MAX_TRANSFER_SIZE};
+use crate::{invalid, owner, ItemRecord, Memory, StorableString, StorageError, ITEMS, MEMORY_MANAGER};
+
+type LinkStorage = StableBTreeMap<u64, CapabilityLink, Memory>;
+type LinkIndex = StableBTreeMap<StorableString, u64, Memory>;
+type LinkCounterCell = StableCell<LinkCounters, Memory>;
+type RedemptionStorage = StableBTreeMap<u64, LinkRedemption, Memory>;
+
+const LINK_PATH_PREFIX: &str = "/links/";
+const MAX_LINK_ITEMS: usize = 20;
+const MAX_LABEL_LENGTH: usize = 100;
+const MAX_LINK_LIFETIME: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+// Expired and revoked links are kept this long so their audit trail stays readable
+const LINK_RETENTION: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_REDEMPTIONS: u64 = 10_000;
+// How long a served download may keep streaming the rest of the item
+const STREAM_WINDOW: u64 = 60 * 60 * 1_000_000_000;
+
+// CAPABILITY LINK TYPES
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CapabilityLink {
+    pub id: u64,
+    // Only the digest of the bearer token is stored
+    pub token_sha256: Vec<u8>,
+    pub item_ids: Vec<String>,
+    pub label: String,
+    pub created_by: Principal,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub max_downloads: Option<u32>,
+    pub downloads: u32,
+    pub revoked_at: Option<u64>,
+}
+
+impl Storable for CapabilityLink {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CapabilityLink {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct IssueLinkRequest {
+    pub item_ids: Vec<String>,
+    pub expires_at: u64,
+    pub max_downloads: Option<u32>,
+    pub label: Option<String>,
+}
+
+// The token is only ever returned here
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct IssuedLink {
+    pub link: CapabilityLink,
+    pub token: String,
+    pub path: String,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct LinkCounters {
+    pub next_link_id: u64,
+    pub next_redemption_id: u64,
+}
+
+impl Storable for LinkCounters {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq)]
+pub enum RedemptionOutcome {
+    Served,
+    Expired,
+    Revoked,
+    LimitReached,
+    ItemNotInScope,
+    ItemNotFound,
+    // The principal who issued the link no longer administers the item
+    IssuerAccessRevoked,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct LinkRedemption {
+    pub id: u64,
+    pub link_id: u64,
+    pub item_id: String,
+    // Content version and size that were served, if any
+    pub version: Option<u64>,
+    pub size: Option<u64>,
+    pub outcome: RedemptionOutcome,
+    pub at: u64,
+}
+
+impl Storable for LinkRedemption {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LinkRedemption {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// HTTP gateway interface
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Clone, Debug, CandidType)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub streaming_strategy: Option<StreamingStrategy>,
+    pub upgrade: Option<bool>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StreamingCallbackToken {
+    pub redemption_id: u64,
+    // Hex digest of the link token, so redemption ids alone cannot be replayed
+    pub token_sha256: String,
+    pub offset: u64,
+}
+
+#[derive(Clone, Debug, CandidType)]
+pub struct StreamingCallbackHttpResponse {
+    pub body: Vec<u8>,
+    pub token: Option<StreamingCallbackToken>,
+}
+
+// A `Func` reference typed as the streaming callback the gateway expects
+#[derive(Clone, Debug)]
+pub struct StreamingCallback(pub Func);
+
+impl CandidType for StreamingCallback {
+    fn _ty() -> Type {
+        Type::Func(Function {
+            modes: vec![FuncMode::Query],
+            args: vec![StreamingCallbackToken::ty()],
+            rets: vec![StreamingCallbackHttpResponse::ty()],
+        })
+    }
+
+    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
+    where
+        S: Serializer,
+    {
+        self.0.idl_serialize(serializer)
+    }
+}
+
+#[derive(Clone, Debug, CandidType)]
+pub enum StreamingStrategy {
+    Callback {
+        callback: StreamingCallback,
+        token: StreamingCallbackToken,
+    },
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Capability links, their token index and counters (Memory ID 11, 12, 13)
+    static LINKS: RefCell<LinkStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+        )
+    );
+
+    static LINK_INDEX: RefCell<LinkIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+        )
+    );
+
+    static LINK_COUNTERS: RefCell<LinkCounterCell> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+            LinkCounters::default(),
+        ).expect("Failed to initialize link counters")
+    );
+
+    // Audit log of link redemptions (Memory ID 14)
+    static REDEMPTIONS: RefCell<RedemptionStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+        )
+    );
+}
+
+// CAPABILITY LINK FUNCTIONS
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn token_digest(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn next_id(select: impl FnOnce(&mut LinkCounters) -> &mut u64) -> u64 {
+    LINK_COUNTERS.with(|cell| {
+        let mut counters = cell.borrow().get().clone();
+        let counter = select(&mut counters);
+        *counter += 1;
+        let id = *counter;
+        cell.borrow_mut().set(counters).expect("Failed to store link counters");
+        id
+    })
+}
+
+fn get_link(link_id: u64) -> Result<CapabilityLink, StorageError> {
+    LINKS.with(|links| links.borrow().get(&link_id))
+        .ok_or(StorageError::LinkNotFound { link_id })
+}
+
+fn find_link(token: &str) -> Option<CapabilityLink> {
+    let link_id = LINK_INDEX.with(|index| index.borrow().get(&StorableString::from(token_digest(token))))?;
+    LINKS.with(|links| links.borrow().get(&link_id))
+}
+
+fn save_link(link: &CapabilityLink) {
+    LINKS.with(|links| links.borrow_mut().insert(link.id, link.clone()));
+}
+
+fn require_link_manager(link: &CapabilityLink) -> Result<(), StorageError> {
+    let caller = ic_cdk::api::caller();
+    if caller == owner() || caller == link.created_by {
+        Ok(())
+    } else {
+        Err(StorageError::NotOwner)
+    }
+}
+
+// Forgets links that stopped working more than `LINK_RETENTION` ago
+fn prune_links(now: u64) {
+    let stale: Vec<CapabilityLink> = LINKS.with(|links| {
+        links.borrow().iter()
+            .map(|(_, link)| link)
+            .filter(|link| {
+                let ended_at = link.revoked_at.unwrap_or(link.expires_at).min(link.expires_at);
+                ended_at.saturating_add(LINK_RETENTION) < now
+            })
+            .collect()
+    });
+    for link in stale {
+        LINKS.with(|links| links.borrow_mut().remove(&link.id));
+        LINK_INDEX.with(|index| index.borrow_mut().remove(&StorableString::from(to_hex(&link.token_sha256))));
+    }
+}
+
+// Sharing an item outside the platform is an access decision, so it takes
+// Admin on every item in the link
+fn check_issue_request(request: &IssueLinkRequest) -> Result<(), StorageError> {
+    if request.item_ids.is_empty() || request.item_ids.len() > MAX_LINK_ITEMS {
+        return Err(invalid("item_ids", format!("must contain between 1 and {} items", MAX_LINK_ITEMS)));
+    }
+    for id in &request.item_ids {
+        acl::authorize_item(id, Permission::Admin)?;
+    }
+
+    let now = ic_cdk::api::time();
+    if request.expires_at <= now || request.expires_at - now > MAX_LINK_LIFETIME {
+        return Err(invalid("expires_at", "must be in the future and at most 30 days away"));
+    }
+    if request.max_downloads == Some(0) {
+        return Err(invalid("max_downloads", "must be greater than zero"));
+    }
+    if request.label.as_ref().is_some_and(|label| label.len() > MAX_LABEL_LENGTH) {
+        return Err(invalid("label", format!("must be at most {} bytes", MAX_LABEL_LENGTH)));
+    }
+    Ok(())
+}
+
+// Links act for whoever issued them, so they only work while the issuer could
+// still issue them
+fn issuer_may_share(link: &CapabilityLink, record: &ItemRecord) -> bool {
+    acl::permission_of(link.created_by, record) == Some(Permission::Admin)
+}
+
+// Revokes the links covering an item that is being deleted, so they do not
+// serve another item later created under the same id
+pub fn revoke_links_for(item_id: &str) {
+    let now = ic_cdk::api::time();
+    let covering: Vec<CapabilityLink> = LINKS.with(|links| {
+        links.borrow().iter()
+            .map(|(_, link)| link)
+            .filter(|link| link.revoked_at.is_none() && link.item_ids.iter().any(|id| id == item_id))
+            .collect()
+    });
+    for mut link in covering {
+        link.revoked_at = Some(now);
+        save_link(&link);
+    }
+}
+
+fn evaluate(link: &CapabilityLink, item_id: &str, now: u64) -> (RedemptionOutcome, Option<ItemRecord>) {
+    if link.revoked_at.is_some() {
+        return (RedemptionOutcome::Revoked, None);
+    }
+    if link.expires_at <= now {
+        return (RedemptionOutcome::Expired, None);
+    }
+    if !link.item_ids.iter().any(|id| id == item_id) {
+        return (RedemptionOutcome::ItemNotInScope, None);
+    }
+    if link.max_downloads.is_some_and(|max| link.downloads >= max) {
+        return (RedemptionOutcome::LimitReached, None);
+    }
+    match ITEMS.with(|items| items.borrow().get(&StorableString::from(item_id.to_string()))) {
+        Some(record) if issuer_may_share(link, &record) => (RedemptionOutcome::Served, Some(record)),
+        Some(_) => (RedemptionOutcome::IssuerAccessRevoked, None),
+        None => (RedemptionOutcome::ItemNotFound, None),
+    }
+}
+
+fn record_redemption(link: &CapabilityLink, item_id: &str, served: Option<&ItemRecord>, outcome: RedemptionOutcome) -> u64 {
+    let id = next_id(|counters| &mut counters.next_redemption_id);
+    let redemption = LinkRedemption {
+        id,
+        link_id: link.id,
+        item_id: item_id.to_string(),
+        version: served.map(|record| record.version),
+        size: served.map(|record| record.size),
+        outcome,
+        at: ic_cdk::api::time(),
+    };
+    REDEMPTIONS.with(|redemptions| {
+        let mut redemptions = redemptions.borrow_mut();
+        redemptions.insert(id, redemption);
+        if id > MAX_REDEMPTIONS {
+            redemptions.remove(&(id - MAX_REDEMPTIONS));
+        }
+    });
+    id
+}
+
+// Accepts `/links/<token>/<item id>`, and `/links/<token>` for single-item links
+fn parse_link_path(url: &str) -> Option<(CapabilityLink, String)> {
+    let path = url.split('?').next().unwrap_or_default();
+    let rest = path.strip_prefix(LINK_PATH_PREFIX)?;
+    let (token, item_id) = match rest.split_once('/') {
+        Some((token, item_id)) => (token, Some(item_id)),
+        None => (rest, None),
+    };
+
+    let link = find_link(token)?;
+    let item_id = match item_id {
+        Some(item_id) => item_id.to_string(),
+        None => link.item_ids.first()?.clone(),
+    };
+    Some((link, item_id))
+}
+
+fn text_response(status_code: u16, message: &str) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), "text/plain; charset=utf-8".to_string())],
+        body: message.as_bytes().to_vec(),
+        streaming_strategy: None,
+        upgrade: None,
+    }
+}
+
+fn outcome_response(outcome: RedemptionOutcome) -> HttpResponse {
+    match outcome {
+        RedemptionOutcome::Revoked => text_response(410, "This link has been revoked"),
+        RedemptionOutcome::Expired => text_response(410, "This link has expired"),
+        RedemptionOutcome::LimitReached => text_response(410, "This link has reached its download limit"),
+        RedemptionOutcome::IssuerAccessRevoked => text_response(410, "This link is no longer valid"),
+        RedemptionOutcome::ItemNotInScope | RedemptionOutcome::ItemNotFound => text_response(404, "Not found"),
+        RedemptionOutcome::Served => text_response(500, "Unexpected redemption outcome"),
+    }
+}
+
+fn download_response(record: &ItemRecord, redemption_id: u64, token_sha256: &[u8]) -> HttpResponse {
+    let length = record.size.min(MAX_TRANSFER_SIZE as u64);
+    let body = transfer::read_range(&record.id, record.version, record.size, 0, length);
+
+    // Items that do not fit in one response are streamed in transfer-sized parts
+    let streaming_strategy = (length < record.size).then(|| StreamingStrategy::Callback {
+        callback: StreamingCallback(Func {
+            principal: ic_cdk::api::id(),
+            method: "http_streaming_callback".to_string(),
+        }),
+        token: StreamingCallbackToken {
+            redemption_id,
+            token_sha256: to_hex(token_sha256),
+            offset: length,
+        },
+    });
+
+    let content_type = if record.metadata.content_type.is_empty() {
+        "application/octet-stream".to_string()
+    } else {
+        record.metadata.content_type.clone()
+    };
+    let filename = record.metadata.filename.replace(['"', '\\'], "_");
+    HttpResponse {
+        status_code: 200,
+        headers: vec![
+            ("Content-Type".to_string(), content_type),
+            ("Content-Length".to_string(), record.size.to_string()),
+            ("Content-Disposition".to_string(), format!("attachment; filename=\"{}\"", filename)),
+            ("Cache-Control".to_string(), "no-store".to_string()),
+        ],
+        body,
+        streaming_strategy,
+        upgrade: None,
+    }
+}
+
+#[update]
+async fn issue_link(request: IssueLinkRequest) -> Result<IssuedLink, StorageError> {
+    check_issue_request(&request)?;
+
+    let (random,) = raw_rand().await.map_err(|(code, message)| StorageError::RandomnessUnavailable {
+        reason: format!("{:?}: {}", code, message),
+    })?;
+    // Items may have been deleted or unshared while waiting for randomness
+    check_issue_request(&request)?;
+
+    let now = ic_cdk::api::time();
+    prune_links(now);
+
+    let mut item_ids = request.item_ids;
+    item_ids.sort();
+    item_ids.dedup();
+    let token = to_hex(&random);
+    let token_sha256 = Sha256::digest(token.as_bytes()).to_vec();
+    let link = CapabilityLink {
+        id: next_id(|counters| &mut counters.next_link_id),
+        token_sha256: token_sha256.clone(),
+        item_ids,
+        label: request.label.unwrap_or_default(),
+        created_by: ic_cdk::api::caller(),
+        created_at: now,
+        expires_at: request.expires_at,
+        max_downloads: request.max_downloads,
+        downloads: 0,
+        revoked_at: None,
+    };
+
+    save_link(&link);
+    LINK_INDEX.with(|index| index.borrow_mut().insert(StorableString::from(to_hex(&token_sha256)), link.id));
+    Ok(IssuedLink {
+        path: format!("{}{}", LINK_PATH_PREFIX, token),
+        link,
+        token,
+    })
+}
+
+#[update]
+fn revoke_link(link_id: u64) -> Result<CapabilityLink, StorageError> {
+    let mut link = get_link(link_id)?;
+    require_link_manager(&link)?;
+
+    if link.revoked_at.is_none() {
+        link.revoked_at = Some(ic_cdk::api::time());
+        save_link(&link);
+    }
+    Ok(link)
+}
+
+// The owner sees every link, anyone else the links they issued
+#[query]
+fn list_links() -> Vec<CapabilityLink> {
+    let caller = ic_cdk::api::caller();
+    let is_owner = caller == owner();
+    LINKS.with(|links| {
+        links.borrow().iter()
+            .map(|(_, link)| link)
+            .filter(|link| is_owner || link.created_by == caller)
+            .collect()
+    })
+}
+
+#[query]
+fn get_link_redemptions(link_id: u64) -> Result<Vec<LinkRedemption>, StorageError> {
+    let link = get_link(link_id)?;
+    require_link_manager(&link)?;
+
+    Ok(REDEMPTIONS.with(|redemptions| {
+        redemptions.borrow().iter()
+            .map(|(_, redemption)| redemption)
+            .filter(|redemption| redemption.link_id == link_id)
+            .collect()
+    }))
+}
+
+// Queries cannot count downloads or write the audit log, so every request
+// for a known link is upgraded to an update call
+#[query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        return text_response(405, "Method not allowed");
+    }
+    match parse_link_path(&request.url) {
+        Some(_) => HttpResponse {
+            upgrade: Some(true),
+            ..text_response(200, "")
+        },
+        None => text_response(404, "Not found"),
+    }
+}
+
+#[update]
+fn http_request_update(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        return text_response(405, "Method not allowed");
+    }
+    let (mut link, item_id) = match parse_link_path(&request.url) {
+        Some(found) => found,
+        None => return text_response(404, "Not found"),
+    };
+
+    match evaluate(&link, &item_id, ic_cdk::api::time()) {
+        (RedemptionOutcome::Served, Some(record)) => {
+            link.downloads += 1;
+            save_link(&link);
+            let redemption_id = record_redemption(&link, &item_id, Some(&record), RedemptionOutcome::Served);
+            download_response(&record, redemption_id, &link.token_sha256)
+        }
+        (outcome, _) => {
+            record_redemption(&link, &item_id, None, outcome);
+            outcome_response(outcome)
+        }
+    }
+}
+
+// Continues a download that `http_request_update` already counted. The link
+// must still be live, and the download must have started recently.
+#[query]
+fn http_streaming_callback(token: StreamingCallbackToken) -> StreamingCallbackHttpResponse {
+    let redemption = REDEMPTIONS.with(|redemptions| redemptions.borrow().get(&token.redemption_id))
+        .filter(|redemption| redemption.outcome == RedemptionOutcome::Served)
+        .unwrap_or_else(|| ic_cdk::trap("Unknown download"));
+    let link = LINKS.with(|links| links.borrow().get(&redemption.link_id))
+        .filter(|link| to_hex(&link.token_sha256) == token.token_sha256)
+        .unwrap_or_else(|| ic_cdk::trap("Unknown download"));
+
+    let now = ic_cdk::api::time();
+    if link.revoked_at.is_some() || link.expires_at <= now || redemption.at.saturating_add(STREAM_WINDOW) < now {
+        ic_cdk::trap("This download is no longer available");
+    }
+    let shared = ITEMS.with(|items| items.borrow().get(&StorableString::from(redemption.item_id.clone())))
+        .is_some_and(|record| issuer_may_share(&link, &record));
+    if !shared {
+        ic_cdk::trap("This download is no longer available");
+    }
+
+    // Serve the content version the download started with, for as long as
+    // the item or one of its revisions keeps it
+    let (version, size) = redemption.version.zip(redemption.size)
+        .filter(|(version, _)| revisions::has_content(&redemption.item_id, *version))
+        .unwrap_or_else(|| ic_cdk::trap("Content no longer available"));
+
+    let body = transfer::read_range(&redemption.item_id, version, size, token.offset, MAX_TRANSFER_SIZE as u64);
+    let next_offset = token.offset + body.len() as u64;
+    StreamingCallbackHttpResponse {
+        token: (next_offset < size && !body.is_empty()).then_some(StreamingCallbackToken {
+            offset: next_offset,
+            ..token
+        }),
+        body,
+    }
+}
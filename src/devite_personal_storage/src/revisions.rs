@@ -0,0 +1,579 @@
+use candid::{CandidType, Deserialize, Principal, Encode, Decode};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable, BoundedStorable};
+use std::cell::RefCell;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use crate::acl::{self, Permission};
+use crate::archive;
+use crate::search;
+use crate::transfer::{self, MAX_TRANSFER_SIZE};
+use crate::usage;
+use crate::{
+    delete_version, invalid, require_owner, ItemMetadata, ItemRecord, Memory,
+    StorableString, StorageError, ITEMS, MEMORY_MANAGER,
+};
+
+type RevisionStorage = StableBTreeMap<RevisionKey, Revision, Memory>;
+type RetentionCell = StableCell<RetentionPolicy, Memory>;
+type PastRevisionIndex = StableBTreeMap<PastRevisionKey, (), Memory>;
+
+const MAX_MESSAGE_LENGTH: usize = 500;
+const MAX_REVISIONS_PER_ITEM: u32 = 1000;
+
+// REVISION TYPES
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RevisionKey {
+    pub item_id: String,
+    pub number: u64,
+}
+
+impl Storable for RevisionKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RevisionKey {
+    const MAX_SIZE: u32 = 160;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Orders past revisions oldest first, across items
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct PastRevisionKey {
+    created_at: u64,
+    item_id: String,
+    number: u64,
+}
+
+impl PastRevisionKey {
+    fn of(revision: &Revision) -> Self {
+        PastRevisionKey {
+            created_at: revision.created_at,
+            item_id: revision.item_id.clone(),
+            number: revision.number,
+        }
+    }
+}
+
+impl Storable for PastRevisionKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PastRevisionKey {
+    const MAX_SIZE: u32 = 180;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// An immutable snapshot of an item. Revisions that did not change the content
+// point at the chunks of an earlier one through `content_version`.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Revision {
+    pub item_id: String,
+    pub number: u64,
+    pub author: Principal,
+    pub message: String,
+    pub created_at: u64,
+    pub metadata: ItemMetadata,
+    pub size: u64,
+    pub sha256: Vec<u8>,
+    pub content_version: u64,
+    pub chunk_count: u32,
+    pub key_version: Option<u32>,
+}
+
+impl Storable for Revision {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Revision {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RetentionPolicy {
+    // Older revisions beyond this count are pruned, per item
+    pub max_revisions_per_item: u32,
+    // Content kept only by past revisions, across all items
+    pub max_revision_bytes: u64,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            max_revisions_per_item: 20,
+            max_revision_bytes: 50 * 1024 * 1024,
+        }
+    }
+}
+
+impl Storable for RetentionPolicy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RevisionDiff {
+    pub item_id: String,
+    pub from: u64,
+    pub to: u64,
+    pub content_changed: bool,
+    pub changes: Vec<FieldChange>,
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Revisions and the retention policy (Memory ID 7, 8)
+    pub(crate) static REVISIONS: RefCell<RevisionStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+        )
+    );
+
+    pub(crate) static RETENTION: RefCell<RetentionCell> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+            RetentionPolicy::default(),
+        ).expect("Failed to initialize retention policy")
+    );
+
+    // Revisions other than the current one of each item (Memory ID 23)
+    static PAST: RefCell<PastRevisionIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))),
+        )
+    );
+}
+
+// REVISION FUNCTIONS
+
+pub fn validate_message(message: &Option<String>) -> Result<(), StorageError> {
+    match message {
+        Some(message) if message.len() > MAX_MESSAGE_LENGTH => Err(invalid(
+            "message",
+            format!("must be at most {} bytes", MAX_MESSAGE_LENGTH),
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Revision numbers double as chunk versions, so they are never reused even
+// after old revisions are pruned
+pub fn next_revision(record: &ItemRecord) -> u64 {
+    record.revision.unwrap_or(0).max(record.version) + 1
+}
+
+fn item_revisions(item_id: &str) -> Vec<Revision> {
+    let start = RevisionKey {
+        item_id: item_id.to_string(),
+        number: 0,
+    };
+    REVISIONS.with(|revisions| {
+        revisions.borrow().range(start..)
+            .take_while(|(key, _)| key.item_id == item_id)
+            .map(|(_, revision)| revision)
+            .collect()
+    })
+}
+
+fn get_revision_record(item_id: &str, number: u64) -> Result<Revision, StorageError> {
+    let key = RevisionKey {
+        item_id: item_id.to_string(),
+        number,
+    };
+    REVISIONS.with(|revisions| revisions.borrow().get(&key))
+        .ok_or_else(|| StorageError::RevisionNotFound {
+            id: item_id.to_string(),
+            number,
+        })
+}
+
+// Stores `record` as its own revision `record.revision` and prunes history
+// that falls outside the retention policy
+pub fn record_revision(record: &ItemRecord, message: Option<String>) {
+    let number = record.revision.expect("records are saved with a revision number");
+    let revision = Revision {
+        item_id: record.id.clone(),
+        number,
+        author: ic_cdk::api::caller(),
+        message: message.unwrap_or_default(),
+        created_at: record.updated_at,
+        metadata: record.metadata.clone(),
+        size: record.size,
+        sha256: record.sha256.clone(),
+        content_version: record.version,
+        chunk_count: record.chunk_count,
+        key_version: record.key_version,
+    };
+    let key = RevisionKey {
+        item_id: record.id.clone(),
+        number,
+    };
+    // The revision that was current until now becomes a past one
+    if let Some(previous) = item_revisions(&record.id).last() {
+        PAST.with(|past| past.borrow_mut().insert(PastRevisionKey::of(previous), ()));
+    }
+    REVISIONS.with(|revisions| revisions.borrow_mut().insert(key, revision));
+    usage::refresh_retained(&record.id);
+
+    let policy = RETENTION.with(|cell| cell.borrow().get().clone());
+    prune_item(&record.id, &policy);
+    prune_retained(&policy);
+}
+
+// Notes the key of content that turned out to be encrypted after it was stored
+pub fn set_current_key_version(record: &ItemRecord) {
+    let Some(number) = record.revision else {
+        return;
+    };
+    let key = RevisionKey {
+        item_id: record.id.clone(),
+        number,
+    };
+    REVISIONS.with(|revisions| {
+        let mut revisions = revisions.borrow_mut();
+        if let Some(mut revision) = revisions.get(&key) {
+            revision.key_version = record.key_version;
+            revisions.insert(key, revision);
+        }
+    });
+}
+
+// Chunks of a content version survive while the item or any revision uses them
+pub fn has_content(item_id: &str, content_version: u64) -> bool {
+    let current = ITEMS.with(|items| items.borrow().get(&StorableString::from(item_id.to_string())));
+    current.map(|record| record.version) == Some(content_version)
+        || item_revisions(item_id).iter().any(|revision| revision.content_version == content_version)
+}
+
+fn release_content(item_id: &str, content_version: u64, chunk_count: u32) {
+    if !has_content(item_id, content_version) {
+        delete_version(item_id, content_version, chunk_count);
+    }
+}
+
+fn remove_revision(revision: &Revision) {
+    REVISIONS.with(|revisions| {
+        revisions.borrow_mut().remove(&RevisionKey {
+            item_id: revision.item_id.clone(),
+            number: revision.number,
+        })
+    });
+    PAST.with(|past| past.borrow_mut().remove(&PastRevisionKey::of(revision)));
+    release_content(&revision.item_id, revision.content_version, revision.chunk_count);
+    usage::refresh_retained(&revision.item_id);
+}
+
+// Revisions other than the current one of each item, and the content version
+// each of their items currently uses
+fn past_revisions() -> (Vec<Revision>, BTreeMap<String, u64>) {
+    let mut current_versions = BTreeMap::new();
+    let mut past = Vec::new();
+    // Revisions are keyed by item, so each item is looked up once
+    let mut current: Option<(String, Option<ItemRecord>)> = None;
+    REVISIONS.with(|revisions| {
+        for (_, revision) in revisions.borrow().iter() {
+            if current.as_ref().is_none_or(|(item_id, _)| *item_id != revision.item_id) {
+                let record = ITEMS.with(|items| items.borrow().get(&StorableString::from(revision.item_id.clone())));
+                if let Some(record) = &record {
+                    current_versions.insert(record.id.clone(), record.version);
+                }
+                current = Some((revision.item_id.clone(), record));
+            }
+            let record = current.as_ref().and_then(|(_, record)| record.as_ref());
+            if record.and_then(|record| record.revision) != Some(revision.number) {
+                past.push(revision);
+            }
+        }
+    });
+    (past, current_versions)
+}
+
+// Bytes held only by past revisions of `item_id`: content versions the item
+// no longer uses
+pub fn retained_bytes_of(item_id: &str) -> u64 {
+    let current = ITEMS.with(|items| items.borrow().get(&StorableString::from(item_id.to_string())));
+    let mut retained = BTreeMap::new();
+    for revision in item_revisions(item_id) {
+        let is_current = current.as_ref().is_some_and(|record| {
+            record.revision == Some(revision.number) || record.version == revision.content_version
+        });
+        if !is_current {
+            retained.insert(revision.content_version, revision.size);
+        }
+    }
+    retained.values().sum()
+}
+
+// Rebuilds the index of past revisions from stored state and returns
+// `retained_bytes_of` for every item, in one pass
+pub fn reconcile() -> BTreeMap<String, u64> {
+    let stale: Vec<PastRevisionKey> = PAST.with(|past| past.borrow().iter().map(|(key, _)| key).collect());
+    PAST.with(|past| {
+        let mut past = past.borrow_mut();
+        for key in stale {
+            past.remove(&key);
+        }
+    });
+
+    let (revisions, current_versions) = past_revisions();
+    let mut retained = BTreeMap::new();
+    for revision in &revisions {
+        PAST.with(|past| past.borrow_mut().insert(PastRevisionKey::of(revision), ()));
+        if current_versions.get(&revision.item_id) != Some(&revision.content_version) {
+            retained.insert((revision.item_id.clone(), revision.content_version), revision.size);
+        }
+    }
+
+    let mut by_item = BTreeMap::new();
+    for ((item_id, _), size) in retained {
+        *by_item.entry(item_id).or_insert(0) += size;
+    }
+    by_item
+}
+
+// Drops the oldest revisions of `item_id` beyond the per-item limit. The
+// current revision counts towards it and, being the newest, is always kept.
+fn prune_item(item_id: &str, policy: &RetentionPolicy) {
+    let revisions = item_revisions(item_id);
+    let excess = revisions.len().saturating_sub(policy.max_revisions_per_item as usize);
+    for revision in &revisions[..excess] {
+        remove_revision(revision);
+    }
+}
+
+// Drops the oldest past revisions, across items, until the content only they
+// hold fits
+fn prune_retained(policy: &RetentionPolicy) {
+    while usage::retained_bytes() > policy.max_revision_bytes {
+        let Some((key, _)) = PAST.with(|past| past.borrow().iter().next()) else {
+            break;
+        };
+        match get_revision_record(&key.item_id, key.number) {
+            Ok(revision) => remove_revision(&revision),
+            Err(_) => {
+                PAST.with(|past| past.borrow_mut().remove(&key));
+            }
+        }
+    }
+}
+
+pub fn delete_history(item_id: &str) {
+    for revision in item_revisions(item_id) {
+        REVISIONS.with(|revisions| {
+            revisions.borrow_mut().remove(&RevisionKey {
+                item_id: revision.item_id.clone(),
+                number: revision.number,
+            })
+        });
+        PAST.with(|past| past.borrow_mut().remove(&PastRevisionKey::of(&revision)));
+        delete_version(&revision.item_id, revision.content_version, revision.chunk_count);
+    }
+    usage::refresh_retained(item_id);
+}
+
+fn metadata_changes(from: &Revision, to: &Revision) -> Vec<FieldChange> {
+    let fields = [
+        ("content_type", &from.metadata.content_type, &to.metadata.content_type),
+        ("filename", &from.metadata.filename, &to.metadata.filename),
+        ("description", &from.metadata.description, &to.metadata.description),
+    ];
+    let mut changes: Vec<FieldChange> = fields.iter()
+        .filter(|(_, before, after)| before != after)
+        .map(|(field, before, after)| FieldChange {
+            field: field.to_string(),
+            from: before.to_string(),
+            to: after.to_string(),
+        })
+        .collect();
+
+    if from.metadata.tags != to.metadata.tags {
+        changes.push(FieldChange {
+            field: "tags".to_string(),
+            from: from.metadata.tags.join(", "),
+            to: to.metadata.tags.join(", "),
+        });
+    }
+    if from.size != to.size {
+        changes.push(FieldChange {
+            field: "size".to_string(),
+            from: from.size.to_string(),
+            to: to.size.to_string(),
+        });
+    }
+    changes
+}
+
+#[query]
+fn list_revisions(id: String) -> Result<Vec<Revision>, StorageError> {
+    acl::authorize_item(&id, Permission::Read)?;
+    Ok(item_revisions(&id))
+}
+
+#[query]
+fn get_revision(id: String, number: u64) -> Result<Revision, StorageError> {
+    acl::authorize_item(&id, Permission::Read)?;
+    get_revision_record(&id, number)
+}
+
+#[query]
+fn get_revision_range(id: String, number: u64, offset: u64, length: u64) -> Result<Vec<u8>, StorageError> {
+    acl::authorize_item(&id, Permission::Read)?;
+    let revision = get_revision_record(&id, number)?;
+
+    if length > MAX_TRANSFER_SIZE as u64 {
+        return Err(invalid("length", format!("must be at most {} bytes", MAX_TRANSFER_SIZE)));
+    }
+    Ok(transfer::read_range(&id, revision.content_version, revision.size, offset, length))
+}
+
+#[query]
+fn diff_revisions(id: String, from: u64, to: u64) -> Result<RevisionDiff, StorageError> {
+    acl::authorize_item(&id, Permission::Read)?;
+    let before = get_revision_record(&id, from)?;
+    let after = get_revision_record(&id, to)?;
+
+    Ok(RevisionDiff {
+        item_id: id,
+        from,
+        to,
+        content_changed: before.sha256 != after.sha256,
+        changes: metadata_changes(&before, &after),
+    })
+}
+
+// Makes an old revision current again by recording it as a new revision, so
+// history is never rewritten
+#[update]
+fn restore_revision(id: String, number: u64, message: Option<String>) -> Result<ItemRecord, StorageError> {
+    archive::ensure_writable()?;
+    validate_message(&message)?;
+    let mut record = acl::authorize_item(&id, Permission::Write)?;
+    transfer::ensure_no_open_upload(&id)?;
+    let revision = get_revision_record(&id, number)?;
+    acl::authorize_metadata_change(&record, &revision.metadata)?;
+    let previous = record.clone();
+
+    record.revision = Some(next_revision(&record));
+    record.metadata = revision.metadata;
+    record.size = revision.size;
+    record.sha256 = revision.sha256;
+    record.version = revision.content_version;
+    record.chunk_count = revision.chunk_count;
+    record.key_version = revision.key_version;
+    record.updated_at = ic_cdk::api::time();
+
+    ITEMS.with(|items| items.borrow_mut().insert(StorableString::from(record.id.clone()), record.clone()));
+    search::reindex(Some(&previous), Some(&record));
+    usage::item_changed(Some(&previous), Some(&record));
+    record_revision(&record, Some(message.unwrap_or_else(|| format!("Restore revision {}", number))));
+    Ok(record)
+}
+
+#[update]
+fn set_retention_policy(policy: RetentionPolicy) -> Result<(), StorageError> {
+    archive::ensure_writable()?;
+    require_owner()?;
+    if policy.max_revisions_per_item == 0 || policy.max_revisions_per_item > MAX_REVISIONS_PER_ITEM {
+        return Err(invalid(
+            "max_revisions_per_item",
+            format!("must be between 1 and {}", MAX_REVISIONS_PER_ITEM),
+        ));
+    }
+
+    RETENTION.with(|cell| cell.borrow_mut().set(policy.clone()).expect("Failed to store retention policy"));
+    let item_ids: Vec<String> = ITEMS.with(|items| items.borrow().iter().map(|(_, record)| record.id).collect());
+    for item_id in item_ids {
+        prune_item(&item_id, &policy);
+    }
+    prune_retained(&policy);
+    Ok(())
+}
+
+#[query]
+fn get_retention_policy() -> RetentionPolicy {
+    RETENTION.with(|cell| cell.borrow().get().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usage::tests::{record, write};
+
+    fn numbers(item_id: &str) -> Vec<u64> {
+        item_revisions(item_id).iter().map(|revision| revision.number).collect()
+    }
+
+    #[test]
+    fn retention_prunes_per_item_and_oldest_past_content_first() {
+        write(record("notes", 1, 1, 100));
+        write(record("data", 2, 2, 40));
+        write(record("notes", 3, 3, 100));
+        write(record("data", 4, 4, 40));
+        write(record("notes", 5, 5, 100));
+        write(record("data", 6, 6, 40));
+        usage::reconcile();
+        assert_eq!(usage::retained_bytes(), 280);
+
+        let policy = RetentionPolicy {
+            max_revisions_per_item: 2,
+            max_revision_bytes: 1000,
+        };
+        prune_item("notes", &policy);
+        assert_eq!(numbers("notes"), vec![3, 5]);
+        assert_eq!(usage::retained_bytes(), 180);
+
+        // The oldest past revision goes first, whichever item it belongs to
+        let policy = RetentionPolicy {
+            max_revisions_per_item: 20,
+            max_revision_bytes: 80,
+        };
+        prune_retained(&policy);
+        assert_eq!(numbers("notes"), vec![5]);
+        assert_eq!(numbers("data"), vec![4, 6]);
+        assert_eq!(usage::retained_bytes(), 40);
+
+        // Current revisions are never pruned
+        prune_retained(&RetentionPolicy {
+            max_revisions_per_item: 20,
+            max_revision_bytes: 0,
+        });
+        assert_eq!(numbers("notes"), vec![5]);
+        assert_eq!(numbers("data"), vec![6]);
+        assert_eq!(PAST.with(|past| past.borrow().len()), 0);
+    }
+}
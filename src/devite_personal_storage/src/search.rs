@@ -0,0 +1,273 @@
+use candid::{CandidType, Deserialize, Encode, Decode};
+use ic_cdk::query;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, Storable, BoundedStorable};
+use std::cell::RefCell;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+
+use crate::acl;
+use crate::{ItemRecord, Memory, StorableString, ITEMS, MEMORY_MANAGER};
+
+type TermIndex = StableBTreeMap<IndexKey, (), Memory>;
+
+const MIN_TOKEN_LENGTH: usize = 2;
+// Longer words are indexed by their first characters, which prefix search still finds
+const MAX_TOKEN_LENGTH: usize = 32;
+const MAX_QUERY_TOKENS: usize = 8;
+const MAX_QUERY_TAGS: usize = 10;
+const MAX_PAGE_SIZE: u32 = 100;
+
+// SEARCH TYPES
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IndexKey {
+    pub term: String,
+    pub item_id: String,
+}
+
+impl Storable for IndexKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for IndexKey {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq)]
+pub enum ItemSort {
+    UpdatedNewestFirst,
+    UpdatedOldestFirst,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ItemQuery {
+    // Words matched against filename and description; every word must
+    // prefix-match a word of the item
+    pub text: Option<String>,
+    // Items must carry all of these tags
+    pub tags: Vec<String>,
+    // Exact type, or a family such as "image/*"
+    pub content_type: Option<String>,
+    pub sort: Option<ItemSort>,
+    pub start: u32,
+    pub limit: u32,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ItemPage {
+    pub items: Vec<ItemRecord>,
+    // Matches across all pages
+    pub total: u64,
+    pub next_start: Option<u32>,
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Word and tag indexes over item metadata (Memory ID 17, 18)
+    static TOKEN_INDEX: RefCell<TermIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+        )
+    );
+
+    static TAG_INDEX: RefCell<TermIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+        )
+    );
+}
+
+// SEARCH FUNCTIONS
+
+fn tokenize(text: &str) -> BTreeSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .map(|word| word.to_lowercase().chars().take(MAX_TOKEN_LENGTH).collect::<String>())
+        .filter(|word| word.chars().count() >= MIN_TOKEN_LENGTH)
+        .collect()
+}
+
+fn item_tokens(record: &ItemRecord) -> BTreeSet<String> {
+    let mut tokens = tokenize(&record.metadata.filename);
+    tokens.extend(tokenize(&record.metadata.description));
+    tokens
+}
+
+fn index_key(term: &str, item_id: &str) -> IndexKey {
+    IndexKey {
+        term: term.to_string(),
+        item_id: item_id.to_string(),
+    }
+}
+
+// Brings both indexes from the `old` state of an item to its `new` one.
+// Either side is None when the item is created or deleted.
+pub fn reindex(old: Option<&ItemRecord>, new: Option<&ItemRecord>) {
+    let old_tokens = old.map(item_tokens).unwrap_or_default();
+    let new_tokens = new.map(item_tokens).unwrap_or_default();
+    let old_tags: BTreeSet<String> = old.map(|record| record.metadata.tags.iter().cloned().collect()).unwrap_or_default();
+    let new_tags: BTreeSet<String> = new.map(|record| record.metadata.tags.iter().cloned().collect()).unwrap_or_default();
+    let old_id = old.map(|record| record.id.as_str()).unwrap_or_default();
+    let new_id = new.map(|record| record.id.as_str()).unwrap_or_default();
+
+    TOKEN_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for token in old_tokens.difference(&new_tokens) {
+            index.remove(&index_key(token, old_id));
+        }
+        for token in new_tokens.difference(&old_tokens) {
+            index.insert(index_key(token, new_id), ());
+        }
+    });
+    TAG_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for tag in old_tags.difference(&new_tags) {
+            index.remove(&index_key(tag, old_id));
+        }
+        for tag in new_tags.difference(&old_tags) {
+            index.insert(index_key(tag, new_id), ());
+        }
+    });
+}
+
+// Items stored before the indexes existed are indexed once after the upgrade
+pub fn ensure_indexed() {
+    let indexed = TOKEN_INDEX.with(|index| !index.borrow().is_empty())
+        || TAG_INDEX.with(|index| !index.borrow().is_empty());
+    if indexed {
+        return;
+    }
+    let records: Vec<ItemRecord> = ITEMS.with(|items| items.borrow().iter().map(|(_, record)| record).collect());
+    for record in &records {
+        reindex(None, Some(record));
+    }
+}
+
+fn prefix_matches(index: &TermIndex, prefix: &str) -> BTreeSet<String> {
+    index.range(index_key(prefix, "")..)
+        .take_while(|(key, _)| key.term.starts_with(prefix))
+        .map(|(key, _)| key.item_id)
+        .collect()
+}
+
+fn exact_matches(index: &TermIndex, term: &str) -> BTreeSet<String> {
+    index.range(index_key(term, "")..)
+        .take_while(|(key, _)| key.term == term)
+        .map(|(key, _)| key.item_id)
+        .collect()
+}
+
+fn intersect(candidates: Option<BTreeSet<String>>, matches: BTreeSet<String>) -> Option<BTreeSet<String>> {
+    Some(match candidates {
+        Some(candidates) => candidates.intersection(&matches).cloned().collect(),
+        None => matches,
+    })
+}
+
+// Ids the text and tag filters allow, or None when neither is set
+fn candidate_ids(words: &[String], tags: &[String]) -> Option<BTreeSet<String>> {
+    let mut candidates = None;
+    TOKEN_INDEX.with(|index| {
+        let index = index.borrow();
+        for word in words {
+            candidates = intersect(candidates.take(), prefix_matches(&index, word));
+        }
+    });
+    TAG_INDEX.with(|index| {
+        let index = index.borrow();
+        for tag in tags {
+            candidates = intersect(candidates.take(), exact_matches(&index, tag));
+        }
+    });
+    candidates
+}
+
+fn matches_content_type(record: &ItemRecord, content_type: &str) -> bool {
+    match content_type.strip_suffix("/*") {
+        Some(family) => record.metadata.content_type.split('/').next() == Some(family),
+        None => record.metadata.content_type == content_type,
+    }
+}
+
+// Lists the items the caller can read, newest first unless asked otherwise
+#[query]
+fn list_items(query: ItemQuery) -> ItemPage {
+    let caller = ic_cdk::api::caller();
+    let words: Vec<String> = query.text.as_deref().map(tokenize).unwrap_or_default()
+        .into_iter()
+        .take(MAX_QUERY_TOKENS)
+        .collect();
+    let tags: Vec<String> = query.tags.into_iter().take(MAX_QUERY_TAGS).collect();
+
+    let mut records: Vec<ItemRecord> = match candidate_ids(&words, &tags) {
+        Some(ids) => ids.into_iter()
+            .filter_map(|id| ITEMS.with(|items| items.borrow().get(&StorableString::from(id))))
+            .collect(),
+        None => ITEMS.with(|items| items.borrow().iter().map(|(_, record)| record).collect()),
+    };
+    records.retain(|record| {
+        query.content_type.as_deref().is_none_or(|content_type| matches_content_type(record, content_type))
+            && acl::permission_of(caller, record).is_some()
+    });
+
+    match query.sort.unwrap_or(ItemSort::UpdatedNewestFirst) {
+        ItemSort::UpdatedNewestFirst => records.sort_by(|a, b| b.updated_at.cmp(&a.updated_at).then_with(|| a.id.cmp(&b.id))),
+        ItemSort::UpdatedOldestFirst => records.sort_by(|a, b| a.updated_at.cmp(&b.updated_at).then_with(|| a.id.cmp(&b.id))),
+    }
+
+    let total = records.len() as u64;
+    let start = query.start as usize;
+    let limit = query.limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let items: Vec<ItemRecord> = records.into_iter().skip(start).take(limit).collect();
+    let end = start + items.len();
+    ItemPage {
+        next_start: ((end as u64) < total).then_some(end as u32),
+        total,
+        items,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(words: &[&str]) -> BTreeSet<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn tokenize_splits_and_lowercases_words() {
+        assert_eq!(tokenize("Field-Notes_2024.PDF"), set(&["field", "notes", "2024", "pdf"]));
+        assert_eq!(tokenize("Über Größen, über alles"), set(&["über", "größen", "alles"]));
+    }
+
+    #[test]
+    fn tokenize_drops_short_words_and_truncates_long_ones() {
+        assert_eq!(tokenize("a b c ok"), set(&["ok"]));
+        assert!(tokenize("").is_empty());
+
+        // Characters, not bytes, are counted
+        let long = "é".repeat(40);
+        assert_eq!(tokenize(&long), set(&[&"é".repeat(MAX_TOKEN_LENGTH)]));
+    }
+
+    #[test]
+    fn intersect_narrows_candidates() {
+        assert_eq!(intersect(None, set(&["a", "b"])), Some(set(&["a", "b"])));
+        assert_eq!(intersect(Some(set(&["a", "b"])), set(&["b", "c"])), Some(set(&["b"])));
+    }
+
+    #[test]
+    fn index_keys_round_trip() {
+        let key = index_key("notes", "field-notes");
+        assert_eq!(IndexKey::from_bytes(key.to_bytes()), key);
+    }
+}
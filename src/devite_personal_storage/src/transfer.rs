@@ -0,0 +1,491 @@
+use candid::{CandidType, Deserialize, Principal, Encode, Decode};
+use ic_cdk::{query, update};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable, BoundedStorable};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::borrow::Cow;
+use std::time::Duration;
+
+use crate::acl::{self, Permission};
+use crate::archive;
+use crate::hashing::ResumableSha256;
+use crate::keys;
+use crate::revisions;
+use crate::search;
+use crate::usage;
+use crate::{
+    check_quota, delete_version, get_record, invalid, owner, read_chunk, require_owner,
+    validate_item_id, validate_metadata, write_chunk, ChunkInfo, ChunkKey, ItemMetadata, ItemRecord,
+    Memory, StorableString, StorageError, CHUNK_SIZE, ITEMS, MANIFESTS, MEMORY_MANAGER,
+};
+
+type UploadStorage = StableBTreeMap<u64, UploadSession, Memory>;
+type UploadIdCell = StableCell<u64, Memory>;
+type UploadDigestStorage = StableBTreeMap<u64, ResumableSha256, Memory>;
+
+// Largest payload moved by a single call, well below the ingress and reply limits
+pub const MAX_TRANSFER_SIZE: usize = 16 * CHUNK_SIZE;
+// Uploads without a chunk for this long are garbage-collected
+const UPLOAD_TIMEOUT: u64 = 24 * 60 * 60 * 1_000_000_000;
+const UPLOAD_GC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_MANIFEST_PAGE: u32 = 1000;
+// Stored chunks a single commit hashes for uploads without a running digest
+const MAX_CHUNKS_HASHED_PER_CALL: u32 = 256;
+
+// TRANSFER TYPES
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct BeginUploadRequest {
+    pub id: String,
+    pub total_size: u64,
+    pub sha256: Vec<u8>,
+    // Required when the upload creates a new item, optional when it replaces
+    // the content of an existing one
+    pub metadata: Option<ItemMetadata>,
+    pub message: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct UploadSession {
+    pub upload_id: u64,
+    pub item_id: String,
+    pub metadata: Option<ItemMetadata>,
+    pub message: Option<String>,
+    pub creates_item: bool,
+    // None for uploads the owner opened before items could be shared
+    pub uploader: Option<Principal>,
+    // Revision the upload will create, which is also the chunk version
+    pub version: u64,
+    pub total_size: u64,
+    pub expected_sha256: Vec<u8>,
+    pub received_bytes: u64,
+    pub chunk_size: u64,
+    pub started_at: u64,
+    pub last_activity_at: u64,
+}
+
+impl Storable for UploadSession {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for UploadSession {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ItemManifest {
+    pub id: String,
+    pub version: u64,
+    pub size: u64,
+    pub sha256: Vec<u8>,
+    pub chunk_size: u64,
+    pub chunk_count: u32,
+    // The requested page of chunk descriptors
+    pub chunks: Vec<ChunkInfo>,
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Open uploads and the next upload id (Memory ID 5, 6)
+    static UPLOADS: RefCell<UploadStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
+        )
+    );
+
+    static NEXT_UPLOAD_ID: RefCell<UploadIdCell> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
+            1,
+        ).expect("Failed to initialize upload id counter")
+    );
+
+    // Running digests of open uploads (Memory ID 20)
+    static UPLOAD_DIGESTS: RefCell<UploadDigestStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+        )
+    );
+}
+
+// TRANSFER FUNCTIONS
+
+pub fn start_upload_gc_timer() {
+    ic_cdk_timers::set_timer_interval(UPLOAD_GC_INTERVAL, collect_abandoned_uploads);
+}
+
+fn collect_abandoned_uploads() {
+    let now = ic_cdk::api::time();
+    let abandoned: Vec<UploadSession> = UPLOADS.with(|uploads| {
+        uploads.borrow().iter()
+            .map(|(_, session)| session)
+            .filter(|session| now.saturating_sub(session.last_activity_at) > UPLOAD_TIMEOUT)
+            .collect()
+    });
+
+    for session in abandoned {
+        discard_upload(&session);
+    }
+}
+
+fn discard_upload(session: &UploadSession) {
+    delete_version(&session.item_id, session.version, chunk_count(session.total_size));
+    UPLOADS.with(|uploads| uploads.borrow_mut().remove(&session.upload_id));
+    UPLOAD_DIGESTS.with(|digests| digests.borrow_mut().remove(&session.upload_id));
+}
+
+fn chunk_count(size: u64) -> u32 {
+    size.div_ceil(CHUNK_SIZE as u64) as u32
+}
+
+// Bytes and items reserved by open uploads, counted against the quota so that
+// parallel uploads cannot overshoot it
+pub fn reservations() -> (u64, u64) {
+    UPLOADS.with(|uploads| {
+        uploads.borrow().iter().fold((0u64, 0u64), |(bytes, items), (_, session)| {
+            (bytes + session.total_size, items + session.creates_item as u64)
+        })
+    })
+}
+
+pub fn has_uploads() -> bool {
+    UPLOADS.with(|uploads| !uploads.borrow().is_empty())
+}
+
+pub fn has_open_upload(item_id: &str) -> bool {
+    UPLOADS.with(|uploads| uploads.borrow().iter().any(|(_, session)| session.item_id == item_id))
+}
+
+pub fn ensure_no_open_upload(item_id: &str) -> Result<(), StorageError> {
+    if has_open_upload(item_id) {
+        Err(StorageError::UploadInProgress { id: item_id.to_string() })
+    } else {
+        Ok(())
+    }
+}
+
+fn get_session(upload_id: u64) -> Result<UploadSession, StorageError> {
+    UPLOADS.with(|uploads| uploads.borrow().get(&upload_id))
+        .ok_or(StorageError::UploadNotFound { upload_id })
+}
+
+// An upload is continued by whoever opened it, and only while they can
+// still write to the item. The owner may act on any upload.
+fn authorize_session(session: &UploadSession) -> Result<(), StorageError> {
+    let caller = ic_cdk::api::caller();
+    if caller == owner() {
+        return Ok(());
+    }
+    if session.uploader != Some(caller) {
+        return Err(StorageError::AccessDenied {
+            id: session.item_id.clone(),
+            required: Permission::Write,
+        });
+    }
+    acl::authorize_item(&session.item_id, Permission::Write).map(|_| ())
+}
+
+// Uploads opened before digests were stored have none, or one that stopped
+// short of the received bytes; they are hashed from their stored chunks, a
+// bounded batch per call
+fn hash_stored_chunks(session: &UploadSession, digest: &mut ResumableSha256) {
+    let first = (digest.length() / CHUNK_SIZE as u64) as u32;
+    let last = first.saturating_add(MAX_CHUNKS_HASHED_PER_CALL).min(chunk_count(session.total_size));
+    for index in first..last {
+        if let Some(bytes) = read_chunk(&session.item_id, session.version, index) {
+            digest.update(&bytes);
+        }
+    }
+}
+
+pub fn read_range(item_id: &str, version: u64, size: u64, offset: u64, length: u64) -> Vec<u8> {
+    let end = offset.saturating_add(length).min(size);
+    if offset >= end {
+        return Vec::new();
+    }
+
+    let chunk_size = CHUNK_SIZE as u64;
+    let mut content = Vec::with_capacity((end - offset) as usize);
+    for index in (offset / chunk_size)..end.div_ceil(chunk_size) {
+        let chunk_start = index * chunk_size;
+        if let Some(bytes) = read_chunk(item_id, version, index as u32) {
+            let from = offset.saturating_sub(chunk_start) as usize;
+            let to = ((end - chunk_start) as usize).min(bytes.len());
+            content.extend_from_slice(&bytes[from..to]);
+        }
+    }
+    content
+}
+
+#[update]
+fn begin_upload(request: BeginUploadRequest) -> Result<UploadSession, StorageError> {
+    archive::ensure_writable()?;
+    validate_item_id(&request.id)?;
+    // Writers may replace the content of an item; only the owner creates items
+    let existing = ITEMS.with(|items| items.borrow().get(&StorableString::from(request.id.clone())));
+    match &existing {
+        Some(_) => {
+            acl::authorize_item(&request.id, Permission::Write)?;
+        }
+        None => require_owner()?,
+    }
+    if let Some(metadata) = &request.metadata {
+        validate_metadata(metadata)?;
+        if let Some(record) = &existing {
+            acl::authorize_metadata_change(record, metadata)?;
+        }
+    }
+    if request.total_size == 0 {
+        return Err(invalid("total_size", "must be greater than zero"));
+    }
+    if request.sha256.len() != 32 {
+        return Err(invalid("sha256", "must be a 32-byte SHA-256 digest"));
+    }
+    revisions::validate_message(&request.message)?;
+    ensure_no_open_upload(&request.id)?;
+
+    let version = match &existing {
+        Some(record) => revisions::next_revision(record),
+        None if request.metadata.is_none() => {
+            return Err(invalid("metadata", "is required when creating an item"));
+        }
+        None => 1,
+    };
+    // The previous version stays in place until the commit, so nothing is freed yet
+    check_quota(request.total_size, existing.is_none() as u64)?;
+
+    let upload_id = NEXT_UPLOAD_ID.with(|cell| {
+        let id = *cell.borrow().get();
+        cell.borrow_mut().set(id + 1).expect("Failed to store upload id counter");
+        id
+    });
+    let now = ic_cdk::api::time();
+    let session = UploadSession {
+        upload_id,
+        item_id: request.id,
+        metadata: request.metadata,
+        message: request.message,
+        creates_item: existing.is_none(),
+        uploader: Some(ic_cdk::api::caller()),
+        version,
+        total_size: request.total_size,
+        expected_sha256: request.sha256,
+        received_bytes: 0,
+        chunk_size: CHUNK_SIZE as u64,
+        started_at: now,
+        last_activity_at: now,
+    };
+
+    UPLOADS.with(|uploads| uploads.borrow_mut().insert(upload_id, session.clone()));
+    UPLOAD_DIGESTS.with(|digests| digests.borrow_mut().insert(upload_id, ResumableSha256::default()));
+    Ok(session)
+}
+
+// Chunks must arrive in order. Every call but the last carries a multiple of
+// `chunk_size` bytes.
+#[update]
+fn put_chunk(upload_id: u64, offset: u64, content: Vec<u8>) -> Result<UploadSession, StorageError> {
+    let mut session = get_session(upload_id)?;
+    authorize_session(&session)?;
+
+    if offset != session.received_bytes {
+        return Err(invalid("offset", format!("expected {}", session.received_bytes)));
+    }
+    if content.is_empty() || content.len() > MAX_TRANSFER_SIZE {
+        return Err(invalid("content", format!("must be between 1 and {} bytes", MAX_TRANSFER_SIZE)));
+    }
+    let end = offset + content.len() as u64;
+    if end > session.total_size {
+        return Err(invalid("content", "extends past the declared total_size"));
+    }
+    if end < session.total_size && !content.len().is_multiple_of(CHUNK_SIZE) {
+        return Err(invalid("content", format!("must be a multiple of {} bytes", CHUNK_SIZE)));
+    }
+
+    let first_index = (offset / CHUNK_SIZE as u64) as u32;
+    for (index, bytes) in content.chunks(CHUNK_SIZE).enumerate() {
+        write_chunk(&session.item_id, session.version, first_index + index as u32, bytes);
+    }
+    UPLOAD_DIGESTS.with(|digests| {
+        let mut digests = digests.borrow_mut();
+        if let Some(mut digest) = digests.get(&upload_id).filter(|digest| digest.length() == offset) {
+            digest.update(&content);
+            digests.insert(upload_id, digest);
+        }
+    });
+
+    session.received_bytes = end;
+    session.last_activity_at = ic_cdk::api::time();
+    UPLOADS.with(|uploads| uploads.borrow_mut().insert(upload_id, session.clone()));
+    Ok(session)
+}
+
+#[update]
+fn commit_upload(upload_id: u64) -> Result<ItemRecord, StorageError> {
+    let session = get_session(upload_id)?;
+    authorize_session(&session)?;
+
+    if session.received_bytes != session.total_size {
+        return Err(invalid(
+            "upload_id",
+            format!("received {} of {} bytes", session.received_bytes, session.total_size),
+        ));
+    }
+
+    let mut digest = UPLOAD_DIGESTS.with(|digests| digests.borrow().get(&upload_id))
+        .unwrap_or_default();
+    if digest.length() < session.total_size {
+        hash_stored_chunks(&session, &mut digest);
+        UPLOAD_DIGESTS.with(|digests| digests.borrow_mut().insert(upload_id, digest.clone()));
+        if digest.length() < session.total_size {
+            return Err(StorageError::UploadVerifying {
+                upload_id,
+                hashed_bytes: digest.length(),
+                total_size: session.total_size,
+            });
+        }
+    }
+    UPLOAD_DIGESTS.with(|digests| digests.borrow_mut().remove(&upload_id));
+    let digest = digest.finalize();
+    if digest != session.expected_sha256 {
+        // The stored bytes are not what the client meant to send
+        discard_upload(&session);
+        return Err(StorageError::ChecksumMismatch {
+            expected: session.expected_sha256,
+            actual: digest,
+        });
+    }
+
+    let now = ic_cdk::api::time();
+    let chunk_count = chunk_count(session.total_size);
+    let previous = if session.creates_item {
+        None
+    } else {
+        Some(get_record(&session.item_id)?)
+    };
+    let record = match previous.clone() {
+        None => ItemRecord {
+            id: session.item_id.clone(),
+            metadata: session.metadata.clone().expect("metadata is checked when the upload begins"),
+            size: session.total_size,
+            sha256: digest,
+            version: session.version,
+            chunk_count,
+            created_at: now,
+            updated_at: now,
+            revision: Some(session.version),
+            key_version: None,
+        },
+        Some(mut record) => {
+            if let Some(metadata) = session.metadata.clone() {
+                record.metadata = metadata;
+            }
+            record.size = session.total_size;
+            record.sha256 = digest;
+            record.version = session.version;
+            record.chunk_count = chunk_count;
+            record.updated_at = now;
+            record.revision = Some(session.version);
+            record.key_version = keys::current_key_version(&record.id);
+            record
+        }
+    };
+
+    ITEMS.with(|items| items.borrow_mut().insert(StorableString::from(record.id.clone()), record.clone()));
+    UPLOADS.with(|uploads| uploads.borrow_mut().remove(&upload_id));
+    search::reindex(previous.as_ref(), Some(&record));
+    usage::item_changed(previous.as_ref(), Some(&record));
+    revisions::record_revision(&record, session.message);
+    Ok(record)
+}
+
+#[update]
+fn abort_upload(upload_id: u64) -> Result<(), StorageError> {
+    let session = get_session(upload_id)?;
+    authorize_session(&session)?;
+    discard_upload(&session);
+    Ok(())
+}
+
+#[query]
+fn list_uploads() -> Result<Vec<UploadSession>, StorageError> {
+    // The owner sees every upload, anyone else only their own
+    let caller = ic_cdk::api::caller();
+    let is_owner = caller == owner();
+    Ok(UPLOADS.with(|uploads| {
+        uploads.borrow().iter()
+            .map(|(_, session)| session)
+            .filter(|session| is_owner || session.uploader == Some(caller))
+            .collect()
+    }))
+}
+
+#[query]
+fn get_chunk(id: String, index: u32) -> Result<Vec<u8>, StorageError> {
+    let record = acl::authorize_item(&id, Permission::Read)?;
+
+    if index >= record.chunk_count {
+        return Err(invalid("index", format!("must be below {}", record.chunk_count)));
+    }
+    Ok(read_chunk(&record.id, record.version, index).unwrap_or_default())
+}
+
+#[query]
+fn get_range(id: String, offset: u64, length: u64) -> Result<Vec<u8>, StorageError> {
+    let record = acl::authorize_item(&id, Permission::Read)?;
+
+    if length > MAX_TRANSFER_SIZE as u64 {
+        return Err(invalid("length", format!("must be at most {} bytes", MAX_TRANSFER_SIZE)));
+    }
+    if offset > record.size {
+        return Err(invalid("offset", format!("must be at most {}", record.size)));
+    }
+    Ok(read_range(&record.id, record.version, record.size, offset, length))
+}
+
+#[query]
+fn get_manifest(id: String, start: u32, limit: u32) -> Result<ItemManifest, StorageError> {
+    let record = acl::authorize_item(&id, Permission::Read)?;
+
+    let end = start.saturating_add(limit.min(MAX_MANIFEST_PAGE)).min(record.chunk_count);
+    let chunks = MANIFESTS.with(|manifests| {
+        let manifests = manifests.borrow();
+        (start..end)
+            .map(|index| {
+                let key = ChunkKey {
+                    item_id: record.id.clone(),
+                    version: record.version,
+                    index,
+                };
+                // Items stored before manifests existed get theirs computed on read
+                manifests.get(&key).unwrap_or_else(|| {
+                    let bytes = read_chunk(&record.id, record.version, index).unwrap_or_default();
+                    ChunkInfo {
+                        index,
+                        size: bytes.len() as u32,
+                        sha256: Sha256::digest(&bytes).to_vec(),
+                    }
+                })
+            })
+            .collect()
+    });
+
+    Ok(ItemManifest {
+        id: record.id,
+        version: record.version,
+        size: record.size,
+        sha256: record.sha256,
+        chunk_size: CHUNK_SIZE as u64,
+        chunk_count: record.chunk_count,
+        chunks,
+    })
+}
@@ -0,0 +1,215 @@
+// Running storage totals, so that quota checks do not walk every item and
+// revision. Writes keep them current; upgrades and imports recompute them.
+
+use candid::{CandidType, Deserialize, Encode, Decode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable};
+use std::cell::RefCell;
+use std::borrow::Cow;
+
+use crate::revisions;
+use crate::{ItemRecord, Memory, StorableString, ITEMS, MEMORY_MANAGER};
+
+type UsageCell = StableCell<UsageTotals, Memory>;
+type RetainedStorage = StableBTreeMap<StorableString, u64, Memory>;
+
+// USAGE TYPES
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, PartialEq)]
+struct UsageTotals {
+    // Current content of every item
+    item_bytes: u64,
+    item_count: u64,
+    // Content only past revisions hold
+    retained_bytes: u64,
+}
+
+impl Storable for UsageTotals {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// GLOBAL STATE
+
+thread_local! {
+    // Usage totals and the bytes each item's past revisions retain (Memory ID 21, 22)
+    static TOTALS: RefCell<UsageCell> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))),
+            UsageTotals::default(),
+        ).expect("Failed to initialize usage totals")
+    );
+
+    static RETAINED: RefCell<RetainedStorage> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))),
+        )
+    );
+}
+
+// USAGE FUNCTIONS
+
+fn totals() -> UsageTotals {
+    TOTALS.with(|cell| cell.borrow().get().clone())
+}
+
+fn set_totals(totals: UsageTotals) {
+    TOTALS.with(|cell| cell.borrow_mut().set(totals).expect("Failed to store usage totals"));
+}
+
+// Stored bytes, counting retained revision content, and the number of items
+pub fn used() -> (u64, u64) {
+    let totals = totals();
+    (totals.item_bytes.saturating_add(totals.retained_bytes), totals.item_count)
+}
+
+// Brings the item totals from the `old` state of an item to its `new` one.
+// Either side is None when the item is created or deleted.
+pub fn item_changed(old: Option<&ItemRecord>, new: Option<&ItemRecord>) {
+    let mut totals = totals();
+    if let Some(old) = old {
+        totals.item_bytes = totals.item_bytes.saturating_sub(old.size);
+        totals.item_count = totals.item_count.saturating_sub(1);
+    }
+    if let Some(new) = new {
+        totals.item_bytes = totals.item_bytes.saturating_add(new.size);
+        totals.item_count = totals.item_count.saturating_add(1);
+    }
+    set_totals(totals);
+}
+
+fn set_retained(item_id: &str, bytes: u64) -> u64 {
+    let key = StorableString::from(item_id.to_string());
+    RETAINED.with(|retained| {
+        let mut retained = retained.borrow_mut();
+        if bytes == 0 {
+            retained.remove(&key)
+        } else {
+            retained.insert(key, bytes)
+        }
+    })
+    .unwrap_or(0)
+}
+
+pub fn retained_bytes() -> u64 {
+    totals().retained_bytes
+}
+
+// Recounts what the past revisions of `item_id` retain, after they or the
+// item's current content changed
+pub fn refresh_retained(item_id: &str) {
+    let bytes = revisions::retained_bytes_of(item_id);
+    let previous = set_retained(item_id, bytes);
+    let mut totals = totals();
+    totals.retained_bytes = totals.retained_bytes.saturating_sub(previous).saturating_add(bytes);
+    set_totals(totals);
+}
+
+// Recomputes every total, and the revision index they rely on, from stored state
+pub fn reconcile() {
+    let (item_bytes, item_count) = ITEMS.with(|items| {
+        items.borrow().iter().fold((0u64, 0u64), |(bytes, count), (_, record)| {
+            (bytes + record.size, count + 1)
+        })
+    });
+
+    let stale: Vec<StorableString> = RETAINED.with(|retained| retained.borrow().iter().map(|(key, _)| key).collect());
+    RETAINED.with(|retained| {
+        let mut retained = retained.borrow_mut();
+        for key in stale {
+            retained.remove(&key);
+        }
+    });
+    let mut retained_bytes = 0u64;
+    for (item_id, bytes) in revisions::reconcile() {
+        set_retained(&item_id, bytes);
+        retained_bytes += bytes;
+    }
+
+    set_totals(UsageTotals {
+        item_bytes,
+        item_count,
+        retained_bytes,
+    });
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::revisions::{Revision, RevisionKey, REVISIONS};
+    use crate::ItemMetadata;
+    use candid::Principal;
+
+    pub(crate) fn record(id: &str, revision: u64, version: u64, size: u64) -> ItemRecord {
+        ItemRecord {
+            id: id.to_string(),
+            metadata: ItemMetadata {
+                content_type: "text/plain".to_string(),
+                filename: format!("{}.txt", id),
+                description: String::new(),
+                tags: Vec::new(),
+            },
+            size,
+            sha256: vec![0; 32],
+            version,
+            chunk_count: 1,
+            created_at: 0,
+            updated_at: revision,
+            revision: Some(revision),
+            key_version: None,
+        }
+    }
+
+    // Saves `record` as current and as its own revision, the way writes do
+    pub(crate) fn write(record: ItemRecord) {
+        let key = StorableString::from(record.id.clone());
+        let previous = ITEMS.with(|items| items.borrow_mut().insert(key, record.clone()));
+        item_changed(previous.as_ref(), Some(&record));
+        let revision = Revision {
+            item_id: record.id.clone(),
+            number: record.revision.unwrap(),
+            author: Principal::anonymous(),
+            message: String::new(),
+            created_at: record.updated_at,
+            metadata: record.metadata.clone(),
+            size: record.size,
+            sha256: record.sha256.clone(),
+            content_version: record.version,
+            chunk_count: record.chunk_count,
+            key_version: None,
+        };
+        let key = RevisionKey {
+            item_id: record.id.clone(),
+            number: revision.number,
+        };
+        REVISIONS.with(|revisions| revisions.borrow_mut().insert(key, revision));
+        refresh_retained(&record.id);
+    }
+
+    #[test]
+    fn running_totals_match_a_recount() {
+        write(record("notes", 1, 1, 100));
+        write(record("notes", 2, 2, 250));
+        // Metadata-only revision sharing the current content
+        write(record("notes", 3, 2, 250));
+        write(record("data", 1, 1, 40));
+        // Back to content a past revision still holds
+        write(record("data", 2, 2, 60));
+        write(record("data", 3, 1, 40));
+
+        // notes retains version 1, data retains version 2
+        assert_eq!(used(), (250 + 40 + 100 + 60, 2));
+        let running = totals();
+        reconcile();
+        assert_eq!(totals(), running);
+
+        ITEMS.with(|items| items.borrow_mut().remove(&StorableString::from("data".to_string())));
+        item_changed(Some(&record("data", 3, 1, 40)), None);
+        assert_eq!(used(), (250 + 100 + 60, 1));
+    }
+}